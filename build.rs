@@ -1,6 +1,8 @@
 use static_files::NpmBuild;
 
 fn main() -> std::io::Result<()> {
+    // Embedded by sqlx::migrate!
+    println!("cargo:rerun-if-changed=migrations");
     NpmBuild::new("ui")
         .executable("bun")
         .install()?
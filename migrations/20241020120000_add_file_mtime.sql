@@ -0,0 +1 @@
+alter table filesystem_artifacts add column file_mtime integer;
@@ -0,0 +1,15 @@
+create table track_artists (
+    filesystem_artifact_id integer not null,
+    position integer not null,
+    artist varchar(200) not null,
+    primary key (filesystem_artifact_id, position),
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
+
+create table track_genres (
+    filesystem_artifact_id integer not null,
+    position integer not null,
+    genre varchar(40) not null,
+    primary key (filesystem_artifact_id, position),
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
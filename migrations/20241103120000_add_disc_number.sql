@@ -0,0 +1 @@
+alter table track_metadata add column disc_number integer;
@@ -0,0 +1,2 @@
+alter table filesystem_artifacts add column content_hash char(64);
+create index filesystem_artifacts_content_hash on filesystem_artifacts (content_hash);
@@ -0,0 +1,2 @@
+alter table track_metadata add column track_gain real;
+alter table track_metadata add column album_gain real;
@@ -0,0 +1,3 @@
+alter table track_metadata add column bitrate integer;
+alter table track_metadata add column sample_rate integer;
+alter table track_metadata add column channels integer;
@@ -0,0 +1,3 @@
+alter table filesystem_artifacts add column path_inferred bit not null default FALSE;
+-- Files less than two directories deep were given placeholder artist and album names
+update filesystem_artifacts set path_inferred = TRUE where relative_path not like '%/%/%';
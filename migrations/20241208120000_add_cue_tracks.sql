@@ -0,0 +1,3 @@
+alter table filesystem_artifacts add column cue_track integer;
+alter table filesystem_artifacts add column cue_start_ms integer;
+alter table filesystem_artifacts add column cue_end_ms integer;
@@ -0,0 +1,9 @@
+create table play_events (
+    id integer primary key autoincrement,
+    filesystem_artifact_id integer not null,
+    played_at integer not null,
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
+
+create index play_events_played_at on play_events (played_at);
+create index play_events_filesystem_artifact_id on play_events (filesystem_artifact_id);
@@ -0,0 +1,2 @@
+-- Replaced along with the rest of the row whenever a file's metadata is rescanned
+alter table track_metadata add column peaks blob;
@@ -0,0 +1,3 @@
+-- A cover image found next to the song, relative to the library root. Replaced along with
+-- the rest of the row whenever a file's metadata is rescanned
+alter table track_metadata add column cover_path varchar(256);
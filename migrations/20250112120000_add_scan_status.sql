@@ -0,0 +1,4 @@
+-- How reading the file's tags went: ok, no_tags or read_error, with the error message for
+-- the latter two. Rows from earlier scans count as ok until their file is rescanned
+alter table track_metadata add column scan_status varchar(12) not null default 'ok';
+alter table track_metadata add column scan_error text;
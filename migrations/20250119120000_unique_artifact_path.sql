@@ -0,0 +1,32 @@
+-- One row per file, or per cue track of a file. Scans that raced each other may have
+-- inserted the same path twice; the oldest row is kept and takes over the plays and
+-- playlist entries of the others
+create temporary table duplicate_artifacts as
+select
+    f.id,
+    (
+        select min(k.id) from filesystem_artifacts k
+        where k.relative_path = f.relative_path and k.cue_track is f.cue_track
+    ) as keep_id
+from filesystem_artifacts f;
+delete from duplicate_artifacts where id = keep_id;
+
+update play_events
+set filesystem_artifact_id = (
+    select keep_id from duplicate_artifacts where id = play_events.filesystem_artifact_id
+)
+where filesystem_artifact_id in (select id from duplicate_artifacts);
+update playlist_items
+set artifact_id = (
+    select keep_id from duplicate_artifacts where id = playlist_items.artifact_id
+)
+where artifact_id in (select id from duplicate_artifacts);
+delete from track_artists where filesystem_artifact_id in (select id from duplicate_artifacts);
+delete from track_genres where filesystem_artifact_id in (select id from duplicate_artifacts);
+delete from track_metadata where filesystem_artifact_id in (select id from duplicate_artifacts);
+delete from filesystem_artifacts where id in (select id from duplicate_artifacts);
+drop table duplicate_artifacts;
+
+-- A plain unique index would let any number of rows with a null cue_track through
+create unique index filesystem_artifacts_relative_path
+    on filesystem_artifacts (relative_path, ifnull(cue_track, -1));
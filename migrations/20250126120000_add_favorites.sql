@@ -0,0 +1,6 @@
+-- Songs marked as favorites, at most once each
+create table favorites (
+    filesystem_artifact_id integer primary key,
+    created_at integer not null,
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
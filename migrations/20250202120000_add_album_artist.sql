@@ -0,0 +1,3 @@
+-- The album artist tag, which groups compilations under one artist. Filled in as songs are
+-- rescanned, a forced rescan reads it for every song
+alter table track_metadata add column album_artist varchar(200);
@@ -0,0 +1,3 @@
+-- The path's bytes as the filesystem names it, for files whose names aren't UTF-8 and so are
+-- listed under a lossy relative_path
+alter table filesystem_artifacts add column raw_path blob;
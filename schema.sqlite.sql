@@ -7,9 +7,21 @@ create table filesystem_artifacts (
     first_path_segment varchar(150) null,
     second_path_segment varchar(150) null,
     created_at integer not null,
-    updated_at integer
+    updated_at integer,
+    file_mtime integer,
+    content_hash char(64),
+    path_inferred bit not null default FALSE,
+    cue_track integer,
+    cue_start_ms integer,
+    cue_end_ms integer,
+    file_size integer,
+    raw_path blob
 );
 
+create index filesystem_artifacts_content_hash on filesystem_artifacts (content_hash);
+create unique index filesystem_artifacts_relative_path
+    on filesystem_artifacts (relative_path, ifnull(cue_track, -1));
+
 create table track_metadata (
     filesystem_artifact_id integer not null primary key,
     artist varchar(200),
@@ -20,6 +32,33 @@ create table track_metadata (
     release_year integer,
     track_number integer,
     duration integer,
+    disc_number integer,
+    track_gain real,
+    album_gain real,
+    bitrate integer,
+    sample_rate integer,
+    channels integer,
+    peaks blob,
+    cover_path varchar(256),
+    scan_status varchar(12) not null default 'ok',
+    scan_error text,
+    album_artist varchar(200),
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
+
+create table track_artists (
+    filesystem_artifact_id integer not null,
+    position integer not null,
+    artist varchar(200) not null,
+    primary key (filesystem_artifact_id, position),
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
+
+create table track_genres (
+    filesystem_artifact_id integer not null,
+    position integer not null,
+    genre varchar(40) not null,
+    primary key (filesystem_artifact_id, position),
     foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
 );
 
@@ -37,4 +76,20 @@ create table playlist_items (
     rank integer not null,
     foreign key (artifact_id)
         references filesystem_artifacts(id)
-);
\ No newline at end of file
+);
+
+create table play_events (
+    id integer primary key autoincrement,
+    filesystem_artifact_id integer not null,
+    played_at integer not null,
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
+
+create index play_events_played_at on play_events (played_at);
+create index play_events_filesystem_artifact_id on play_events (filesystem_artifact_id);
+
+create table favorites (
+    filesystem_artifact_id integer primary key,
+    created_at integer not null,
+    foreign key (filesystem_artifact_id) references filesystem_artifacts(id)
+);
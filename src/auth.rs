@@ -0,0 +1,247 @@
+use std::fmt::Write;
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, HttpResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::state::AppState;
+
+/// Probes have to work for load balancers that cannot authenticate
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+#[derive(Deserialize)]
+struct Credentials {
+    token: Option<String>,
+    /// Subsonic password, plain or `enc:` hex encoded
+    p: Option<String>,
+    /// Subsonic token, an md5 of the password and salt `s`
+    t: Option<String>,
+    s: Option<String>,
+}
+
+/// Requires `AUTH_TOKEN`, when set, as the password of HTTP Basic credentials or as a
+/// `?token=` query param, which works where headers cannot be set, e.g. an `<audio>` src.
+/// Subsonic clients authenticate with the token as their password
+pub async fn require_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let expected = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.auth_token.clone());
+    let Some(expected) = expected else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    if EXEMPT_PATHS.contains(&req.path()) || is_authorized(&req, &expected) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    log::debug!("Rejected unauthenticated request for {}", req.path());
+    let response = HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"musrs\""))
+        .json(json!({
+            "error": "authentication required",
+            "code": 401,
+        }));
+    Ok(req.into_response(response))
+}
+
+fn is_authorized(req: &ServiceRequest, expected: &str) -> bool {
+    let basic_password = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|pair| pair.split_once(':').map(|(_, password)| password.to_string()));
+    if basic_password.is_some_and(|p| constant_time_eq(&p, expected)) {
+        return true;
+    }
+
+    let Ok(creds) = web::Query::<Credentials>::from_query(req.query_string()) else {
+        return false;
+    };
+    if creds.token.as_deref().is_some_and(|t| constant_time_eq(t, expected)) {
+        return true;
+    }
+    if !req.path().starts_with("/rest/") {
+        return false;
+    }
+    if let Some(password) = creds.p.as_deref() {
+        let password = match password.strip_prefix("enc:") {
+            Some(hex) => decode_hex(hex).unwrap_or_default(),
+            None => password.to_string(),
+        };
+        return constant_time_eq(&password, expected);
+    }
+    if let (Some(token), Some(salt)) = (creds.t.as_deref(), creds.s.as_deref()) {
+        let digest = Md5::digest(format!("{}{}", expected, salt));
+        let hex = digest.iter().fold(String::new(), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        });
+        return constant_time_eq(&token.to_lowercase(), &hex);
+    }
+    false
+}
+
+/// Query params that carry credentials, never written to the access log
+const CREDENTIAL_PARAMS: &[&str] = &["token", "p", "t", "s"];
+
+/// The request line as `%r` logs it, with the values of credential params blanked out
+pub fn logged_request_line(req: &ServiceRequest) -> String {
+    let query = req
+        .query_string()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if CREDENTIAL_PARAMS.contains(&name) => format!("{}=-", name),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    if query.is_empty() {
+        format!("{} {} {:?}", req.method(), req.path(), req.version())
+    } else {
+        format!("{} {}?{} {:?}", req.method(), req.path(), query, req.version())
+    }
+}
+
+/// Subsonic's `enc:` password: its UTF-8 bytes as pairs of hex digits
+fn decode_hex(hex: &str) -> Option<String> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            // from_str_radix would also take a sign, e.g. `+1`
+            let pair = hex.get(i..i + 2).filter(|p| p.bytes().all(|b| b.is_ascii_hexdigit()))?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Compares without returning early, so response times don't reveal how much of a guess matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a
+            .bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_whole_strings() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(constant_time_eq("", ""));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("secret", ""));
+    }
+
+    #[test]
+    fn decode_hex_reads_utf8_text() {
+        assert_eq!(decode_hex("736563726574").as_deref(), Some("secret"));
+        assert_eq!(decode_hex("C3A9").as_deref(), Some("é"));
+        assert_eq!(decode_hex("").as_deref(), Some(""));
+        for bad in ["7", "736", "zz", "+1", "ff", "é0"] {
+            assert_eq!(decode_hex(bad), None, "{}", bad);
+        }
+    }
+
+    /// `uri` on an app behind `require_token` with `AUTH_TOKEN=secret`
+    async fn call(uri: &str, authorization: Option<&str>) -> ServiceResponse {
+        use actix_web::test;
+        let state = crate::state::AppStateStruct::new(
+            "/music".into(),
+            crate::file_utils::test_settings(),
+            None,
+            Some("secret".into()),
+            None,
+            None,
+        );
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(std::sync::Arc::new(state)))
+                .wrap(actix_web::middleware::from_fn(require_token))
+                .route("/api/songs", web::get().to(HttpResponse::Ok))
+                .route("/rest/ping", web::get().to(HttpResponse::Ok))
+                .route("/healthz", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut req = test::TestRequest::get().uri(uri);
+        if let Some(authorization) = authorization {
+            req = req.insert_header((header::AUTHORIZATION, authorization));
+        }
+        test::call_service(&app, req.to_request()).await
+    }
+
+    async fn status_of(uri: &str, authorization: Option<&str>) -> u16 {
+        call(uri, authorization).await.status().as_u16()
+    }
+
+    #[actix_web::test]
+    async fn require_token_lets_authorized_requests_through() {
+        let basic = format!("Basic {}", STANDARD.encode("anyone:secret"));
+        assert_eq!(status_of("/api/songs", Some(&basic)).await, 200);
+        assert_eq!(status_of("/api/songs?token=secret", None).await, 200);
+        assert_eq!(status_of("/rest/ping?p=secret", None).await, 200);
+        assert_eq!(status_of("/rest/ping?p=enc:736563726574", None).await, 200);
+        let digest = Md5::digest("secretsalt");
+        let hex = digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(status_of(&format!("/rest/ping?t={}&s=salt", hex), None).await, 200);
+    }
+
+    #[actix_web::test]
+    async fn require_token_rejects_wrong_credentials() {
+        let basic = format!("Basic {}", STANDARD.encode("anyone:guess"));
+        assert_eq!(status_of("/api/songs", Some(&basic)).await, 401);
+        assert_eq!(status_of("/api/songs?token=guess", None).await, 401);
+        assert_eq!(status_of("/api/songs?token=", None).await, 401);
+        let token = format!("/rest/ping?t={}&s=salt", "0".repeat(32));
+        assert_eq!(status_of(&token, None).await, 401);
+        // Subsonic params only count on Subsonic routes
+        assert_eq!(status_of("/api/songs?p=secret", None).await, 401);
+    }
+
+    #[actix_web::test]
+    async fn require_token_asks_for_missing_credentials() {
+        let resp = call("/api/songs", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"musrs\""
+        );
+        assert_eq!(status_of("/healthz", None).await, 200);
+    }
+
+    #[test]
+    fn logged_request_line_blanks_credentials() {
+        let req = actix_web::test::TestRequest::get()
+            .uri("/rest/stream?u=me&t=abc&s=salt&id=4&p=enc:73")
+            .to_srv_request();
+        assert_eq!(
+            logged_request_line(&req),
+            "GET /rest/stream?u=me&t=-&s=-&id=4&p=- HTTP/1.1"
+        );
+        let req = actix_web::test::TestRequest::get()
+            .uri("/song/1?token=secret")
+            .to_srv_request();
+        assert_eq!(logged_request_line(&req), "GET /song/1?token=- HTTP/1.1");
+        let req = actix_web::test::TestRequest::get().uri("/api/songs").to_srv_request();
+        assert_eq!(logged_request_line(&req), "GET /api/songs HTTP/1.1");
+    }
+}
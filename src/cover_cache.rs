@@ -0,0 +1,98 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::types::CoverArt;
+
+/// Image types a cover is stored as, by file extension
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/bmp", "bmp"),
+    ("image/tiff", "tiff"),
+];
+/// Extension of the empty file recording that a song has no cover
+const NO_COVER: &str = "none";
+
+/// Covers extracted from songs, written once under `dir` so tags are only read again after
+/// the song's file changes. Each song has a directory of its id holding one file named after
+/// the song file's modification time
+#[derive(Clone)]
+pub struct CoverCache {
+    dir: PathBuf,
+}
+
+impl CoverCache {
+    /// Creates `dir` if needed, `Err` when it can't be written to
+    pub fn open(dir: PathBuf) -> Result<Self, String> {
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("could not create '{}': {}", dir.display(), e))?;
+        let probe = dir.join(".probe");
+        fs::write(&probe, b"")
+            .and_then(|_| fs::remove_file(&probe))
+            .map_err(|e| format!("'{}' is not writable: {}", dir.display(), e))?;
+        Ok(Self { dir })
+    }
+
+    /// Identifies the version of the song file at `abs_path` its cover was cached for
+    pub fn version(abs_path: &Path) -> Option<u128> {
+        fs::metadata(abs_path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+    }
+
+    fn song_dir(&self, song_id: i64) -> PathBuf {
+        self.dir.join(song_id.to_string())
+    }
+
+    /// `None` when nothing is cached for this `version` of the song, `Some(None)` when it is
+    /// cached as having no cover
+    pub fn read(&self, song_id: i64, version: u128) -> Option<Option<CoverArt>> {
+        let song_dir = self.song_dir(song_id);
+        if song_dir.join(format!("{}.{}", version, NO_COVER)).exists() {
+            return Some(None);
+        }
+        EXTENSIONS.iter().find_map(|(mime_type, extension)| {
+            let data = fs::read(song_dir.join(format!("{}.{}", version, extension))).ok()?;
+            Some(Some(CoverArt {
+                mime_type,
+                data: data.into(),
+            }))
+        })
+    }
+
+    /// Stores the cover found for this `version` of the song, replacing older versions.
+    /// Failures are only logged, the cover is then extracted again next time
+    pub fn write(&self, song_id: i64, version: u128, cover: Option<&CoverArt>) {
+        let extension = match cover {
+            Some(cover) => match EXTENSIONS.iter().find(|(m, _)| *m == cover.mime_type) {
+                Some((_, extension)) => *extension,
+                None => return,
+            },
+            None => NO_COVER,
+        };
+        let song_dir = self.song_dir(song_id);
+        let path = song_dir.join(format!("{}.{}", version, extension));
+        // Written aside and renamed into place, so a reader never sees half a file
+        let partial = song_dir.join(format!("{}.partial", version));
+        let res = fs::create_dir_all(&song_dir)
+            .and_then(|_| fs::write(&partial, cover.map_or(&[][..], |c| &c.data[..])))
+            .and_then(|_| fs::rename(&partial, &path));
+        if let Err(e) = res {
+            log::warn!("Could not cache the cover of song {}: {}", song_id, e);
+            return;
+        }
+        let Ok(entries) = fs::read_dir(&song_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry.path() != path {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! Cue sheet tracks as byte ranges of their image, for formats whose audio can be cut at a
+//! byte offset without re-encoding. WAV holds plain samples, and constant bitrate MP3 frames
+//! stand on their own. FLAC frames carry their position in the image, so players would show
+//! a cut track's time from the image's start, and cut tracks of it are transcoded instead
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::transcode::Segment;
+
+/// How far past an estimated offset the next MP3 frame is looked for
+const MP3_SYNC_WINDOW: usize = 16 * 1024;
+
+/// A track as the bytes to send: `header`, then the image's bytes from `start` to `end`
+#[derive(Debug, PartialEq)]
+pub struct TrackBytes {
+    pub header: Vec<u8>,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl TrackBytes {
+    /// A whole file, as it is
+    pub fn whole(file_size: u64) -> Self {
+        Self {
+            header: Vec::new(),
+            start: 0,
+            end: file_size,
+        }
+    }
+
+    /// Bytes sent for the whole track
+    pub fn size(&self) -> u64 {
+        self.header.len() as u64 + self.end - self.start
+    }
+}
+
+/// The bytes of `segment` of the image at `path`, `None` when its format can't be cut like
+/// this, e.g. a variable bitrate MP3, or the segment is outside its audio
+pub fn track_bytes(
+    path: &Path,
+    extension: &str,
+    segment: Segment,
+) -> io::Result<Option<TrackBytes>> {
+    let mut file = File::open(path)?;
+    if extension.eq_ignore_ascii_case("wav") {
+        wav_bytes(&mut file, segment)
+    } else if extension.eq_ignore_ascii_case("mp3") {
+        mp3_bytes(&mut file, segment)
+    } else {
+        Ok(None)
+    }
+}
+
+fn ms_to_units(ms: i64, per_second: u64) -> u64 {
+    ms.max(0) as u64 * per_second / 1000
+}
+
+/// A WAV of the segment's samples, under a header of its own with the image's format chunk
+fn wav_bytes(file: &mut File, segment: Segment) -> io::Result<Option<TrackBytes>> {
+    let file_size = file.metadata()?.len();
+    let mut riff = [0u8; 12];
+    file.read_exact(&mut riff)?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Ok(None);
+    }
+    let mut fmt: Option<Vec<u8>> = None;
+    let (data_start, data_len) = loop {
+        let mut chunk = [0u8; 8];
+        if file.read_exact(&mut chunk).is_err() {
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+        let pos = file.stream_position()?;
+        match &chunk[..4] {
+            b"fmt " if (16..=1024).contains(&size) => {
+                let mut body = vec![0u8; size as usize];
+                file.read_exact(&mut body)?;
+                fmt = Some(body);
+                file.seek(SeekFrom::Current((size & 1) as i64))?;
+            }
+            b"data" => {
+                let rest = file_size.saturating_sub(pos);
+                // Streamed WAVs may leave the size at 0, or at more than was written
+                break (pos, if size == 0 { rest } else { size.min(rest) });
+            }
+            _ => {
+                file.seek(SeekFrom::Start(pos + size + (size & 1)))?;
+            }
+        }
+    };
+    let Some(fmt) = fmt else {
+        return Ok(None);
+    };
+    let format_tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+    // PCM, float and extensible, which all store whole sample frames
+    if ![1, 3, 0xFFFE].contains(&format_tag) {
+        return Ok(None);
+    }
+    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]) as u64;
+    let block_align = u16::from_le_bytes([fmt[12], fmt[13]]) as u64;
+    if sample_rate == 0 || block_align == 0 {
+        return Ok(None);
+    }
+    let frames = data_len / block_align;
+    let start_frame = ms_to_units(segment.start_ms, sample_rate).min(frames);
+    let end_frame = segment
+        .end_ms
+        .map_or(frames, |end| ms_to_units(end, sample_rate).min(frames));
+    if start_frame >= end_frame {
+        return Ok(None);
+    }
+    let len = (end_frame - start_frame) * block_align;
+    let Ok(len32) = u32::try_from(len) else {
+        return Ok(None);
+    };
+    let fmt_len = fmt.len() as u32;
+    let fmt_pad = fmt.len() % 2;
+    let mut header = Vec::with_capacity(28 + fmt.len());
+    header.extend_from_slice(b"RIFF");
+    let riff_len = 4 + 8 + fmt_len + fmt_pad as u32 + 8 + len32;
+    header.extend_from_slice(&riff_len.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&fmt_len.to_le_bytes());
+    header.extend_from_slice(&fmt);
+    header.resize(header.len() + fmt_pad, 0);
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&len32.to_le_bytes());
+    let start = data_start + start_frame * block_align;
+    Ok(Some(TrackBytes {
+        header,
+        start,
+        end: start + len,
+    }))
+}
+
+/// What an MP3 frame header says, as far as cutting at frames needs
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Mp3Frame {
+    mpeg1: bool,
+    mono: bool,
+    kbps: u32,
+    sample_rate: u32,
+    len: usize,
+}
+
+/// Layer III frame headers only, the other layers are next to never used for music
+fn parse_mp3_frame(bytes: &[u8]) -> Option<Mp3Frame> {
+    const MPEG1_KBPS: [u32; 15] =
+        [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const MPEG2_KBPS: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    let [b0, b1, b2, b3, ..] = *bytes else {
+        return None;
+    };
+    if b0 != 0xFF || b1 & 0xE0 != 0xE0 || (b1 >> 1) & 3 != 1 {
+        return None;
+    }
+    let (mpeg1, rates) = match (b1 >> 3) & 3 {
+        3 => (true, [44_100, 48_000, 32_000]),
+        2 => (false, [22_050, 24_000, 16_000]),
+        0 => (false, [11_025, 12_000, 8_000]),
+        _ => return None,
+    };
+    let kbps = match (b2 >> 4) as usize {
+        // Free format frames have no size to go by
+        0 | 15 => return None,
+        i if mpeg1 => MPEG1_KBPS[i],
+        i => MPEG2_KBPS[i],
+    };
+    let sample_rate = *rates.get(((b2 >> 2) & 3) as usize)?;
+    let padding = ((b2 >> 1) & 1) as usize;
+    let per_frame = if mpeg1 { 144 } else { 72 };
+    Some(Mp3Frame {
+        mpeg1,
+        mono: b3 >> 6 == 3,
+        kbps,
+        sample_rate,
+        len: (per_frame * kbps * 1000 / sample_rate) as usize + padding,
+    })
+}
+
+/// Where a Xing or Info header starts in the frame, right after its side information
+fn xing_offset(frame: &Mp3Frame) -> usize {
+    match (frame.mpeg1, frame.mono) {
+        (true, false) => 36,
+        (true, true) | (false, false) => 21,
+        (false, true) => 13,
+    }
+}
+
+/// Whether the frame holds a Xing or VBRI header, which variable bitrate encoders write
+fn is_vbr_frame(frame: &Mp3Frame, bytes: &[u8]) -> bool {
+    let tag_at = |at: usize| bytes.get(at..at + 4);
+    tag_at(xing_offset(frame)) == Some(b"Xing") || tag_at(36) == Some(b"VBRI")
+}
+
+/// The offset of the first frame like `first` at or after `from`, with another like it or
+/// the end of the audio at `audio_end` right behind, so a stray sync pattern in audio data
+/// isn't taken for one
+fn find_mp3_frame(
+    file: &mut File,
+    from: u64,
+    audio_end: u64,
+    first: &Mp3Frame,
+) -> io::Result<Option<u64>> {
+    let window_len = MP3_SYNC_WINDOW.min(audio_end.saturating_sub(from) as usize);
+    let mut window = vec![0u8; window_len];
+    file.seek(SeekFrom::Start(from))?;
+    let mut filled = 0;
+    while filled < window.len() {
+        match file.read(&mut window[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    let window = &window[..filled];
+    let to_end = from + filled as u64 == audio_end;
+    let same = |frame: &Mp3Frame| {
+        (frame.mpeg1, frame.kbps, frame.sample_rate) == (first.mpeg1, first.kbps, first.sample_rate)
+    };
+    for at in 0..window.len() {
+        let Some(frame) = parse_mp3_frame(&window[at..]).filter(same) else {
+            continue;
+        };
+        match window.get(at + frame.len..) {
+            // The last frame of the file
+            Some([]) if to_end => return Ok(Some(from + at as u64)),
+            Some(next) if parse_mp3_frame(next).is_some_and(|f| same(&f)) => {
+                return Ok(Some(from + at as u64))
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// The frames of a constant bitrate MP3 that play during the segment. Tags are left out and
+/// variable bitrate files give `None`, as their offsets can't be worked out from the time
+fn mp3_bytes(file: &mut File, segment: Segment) -> io::Result<Option<TrackBytes>> {
+    let file_size = file.metadata()?.len();
+    let mut head = [0u8; 10];
+    file.read_exact(&mut head)?;
+    let mut audio_start = 0;
+    if &head[..3] == b"ID3" {
+        let size = head[6..10]
+            .iter()
+            .fold(0u64, |size, b| (size << 7) | u64::from(b & 0x7F));
+        let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+        audio_start = 10 + size + footer;
+    }
+    let mut audio_end = file_size;
+    if file_size >= 128 {
+        let mut tag = [0u8; 3];
+        file.seek(SeekFrom::Start(file_size - 128))?;
+        file.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            audio_end = file_size - 128;
+        }
+    }
+
+    let mut first_bytes = vec![0u8; 64];
+    file.seek(SeekFrom::Start(audio_start))?;
+    let read = file.read(&mut first_bytes)?;
+    let Some(first) = parse_mp3_frame(&first_bytes[..read]) else {
+        return Ok(None);
+    };
+    if is_vbr_frame(&first, &first_bytes[..read]) {
+        return Ok(None);
+    }
+    // LAME's `Info` frame of a constant bitrate file is silent and only describes the file
+    let info_at = xing_offset(&first);
+    if first_bytes.get(info_at..info_at + 4) == Some(b"Info") {
+        audio_start += first.len as u64;
+    }
+
+    let bytes_per_second = u64::from(first.kbps) * 1000 / 8;
+    let offset = |ms: i64| audio_start + ms_to_units(ms, bytes_per_second);
+    let start = match offset(segment.start_ms) {
+        at if at >= audio_end => return Ok(None),
+        at => find_mp3_frame(file, at, audio_end, &first)?,
+    };
+    let end = match segment.end_ms.map(offset) {
+        Some(at) if at < audio_end => find_mp3_frame(file, at, audio_end, &first)?,
+        _ => Some(audio_end),
+    };
+    Ok(match (start, end) {
+        (Some(start), Some(end)) if start < end => Some(TrackBytes {
+            header: Vec::new(),
+            start,
+            end,
+        }),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(start_ms: i64, end_ms: Option<i64>) -> Segment {
+        Segment { start_ms, end_ms }
+    }
+
+    /// A 16-bit stereo PCM WAV of `secs` seconds at 1kHz, each sample frame holding its index
+    fn write_wav(path: &Path, secs: u32) {
+        let frames = secs * 1000;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + frames * 4).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&1000u32.to_le_bytes());
+        wav.extend_from_slice(&4000u32.to_le_bytes());
+        wav.extend_from_slice(&4u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(frames * 4).to_le_bytes());
+        for i in 0..frames {
+            wav.extend_from_slice(&i.to_le_bytes());
+        }
+        std::fs::write(path, wav).unwrap();
+    }
+
+    /// A 128kbps 44.1kHz MPEG-1 Layer III frame, padded or not, of silence
+    fn mp3_frame(padded: bool) -> Vec<u8> {
+        let header = [0xFF, 0xFB, 0x90 | if padded { 2 } else { 0 }, 0x00];
+        let len = 144 * 128_000 / 44_100 + usize::from(padded);
+        let mut frame = header.to_vec();
+        frame.resize(len, 0);
+        frame
+    }
+
+    #[test]
+    fn wav_tracks_get_the_segment_samples_under_a_header_of_their_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.wav");
+        write_wav(&path, 10);
+
+        let track = track_bytes(&path, "wav", segment(2000, Some(5000))).unwrap().unwrap();
+        assert_eq!((track.start, track.end), (44 + 2000 * 4, 44 + 5000 * 4));
+        assert_eq!(track.header.len(), 44);
+        assert_eq!(&track.header[..4], b"RIFF");
+        assert_eq!(&track.header[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(track.header[40..44].try_into().unwrap()), 3000 * 4);
+        assert_eq!(track.size(), 44 + 3000 * 4);
+
+        let last = track_bytes(&path, "WAV", segment(9000, None)).unwrap().unwrap();
+        assert_eq!((last.start, last.end), (44 + 9000 * 4, 44 + 10_000 * 4));
+        assert_eq!(track_bytes(&path, "wav", segment(12_000, None)).unwrap(), None);
+    }
+
+    #[test]
+    fn constant_bitrate_mp3_tracks_start_and_end_on_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.mp3");
+        let mut mp3 = b"ID3\x04\x00\x00\x00\x00\x00\x05tags!".to_vec();
+        for i in 0..400 {
+            mp3.extend(mp3_frame(i % 3 == 1));
+        }
+        let audio_len = mp3.len() as u64 - 15;
+        mp3.extend_from_slice(b"TAG");
+        mp3.resize(mp3.len() + 125, b' ');
+        std::fs::write(&path, &mp3).unwrap();
+
+        let track = track_bytes(&path, "mp3", segment(2000, Some(4000))).unwrap().unwrap();
+        let at_frame = |at: u64| parse_mp3_frame(&mp3[at as usize..]).is_some();
+        assert!(at_frame(track.start) && at_frame(track.end));
+        // 16000 bytes a second, give or take a frame
+        assert!(track.start.abs_diff(15 + 32_000) < 420, "{}", track.start);
+        assert!(track.end.abs_diff(15 + 64_000) < 420, "{}", track.end);
+        assert!(track.header.is_empty());
+
+        let last = track_bytes(&path, "mp3", segment(4000, None)).unwrap().unwrap();
+        assert_eq!(last.start, track.end);
+        assert_eq!(last.end, 15 + audio_len);
+    }
+
+    #[test]
+    fn variable_bitrate_mp3s_and_other_formats_are_not_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.mp3");
+        let mut mp3 = mp3_frame(false);
+        mp3[36..40].copy_from_slice(b"Xing");
+        for _ in 0..100 {
+            mp3.extend(mp3_frame(false));
+        }
+        std::fs::write(&path, &mp3).unwrap();
+        assert_eq!(track_bytes(&path, "mp3", segment(0, Some(1000))).unwrap(), None);
+        assert_eq!(track_bytes(&path, "flac", segment(0, Some(1000))).unwrap(), None);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::db::get_library;
+use crate::file_utils::LooseFilePolicy;
+use crate::types::{AlbumRow, ArtistRow, LibraryRow, SongSort};
+use sqlx::{Pool, Sqlite};
+use std::collections::{BTreeSet, HashMap};
+
+/// Albums without an album artist tag are compilations when their tracks have more distinct
+/// artists than this, rather than albums of the same name by a few artists
+const COMPILATION_ARTISTS: usize = 2;
+const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// What tracks of an album are grouped by besides its name
+#[derive(PartialEq, Eq, Hash)]
+enum AlbumKey {
+    /// The album artist tag, lowercased
+    Tagged(String),
+    /// The directories the layout takes the artist and album from
+    Dir(Option<String>, Option<String>),
+    /// The track's artist, lowercased, for loose files without directories to go by
+    Artist(String),
+}
+
+/// The album's row, with its tracks in play order
+fn album_row(
+    artist: String,
+    mut tracks: Vec<LibraryRow>,
+    compilation: bool,
+) -> (AlbumRow, Vec<LibraryRow>) {
+    // Library order already sorts one artist's tracks, a compilation's are by several
+    tracks.sort_by_key(|t| {
+        (
+            t.disc_number.is_none(),
+            t.disc_number,
+            t.track_number.is_none(),
+            t.track_number,
+        )
+    });
+    // Untagged tracks are on disc 1
+    let discs = tracks
+        .iter()
+        .map(|t| t.disc_number.unwrap_or(1))
+        .collect::<BTreeSet<_>>();
+    let row = AlbumRow {
+        artist,
+        album: tracks[0].album.clone(),
+        track_count: tracks.len() as u32,
+        disc_count: discs.len() as u32,
+        song_ids: tracks.iter().map(|t| t.id).collect(),
+        cover_song_id: tracks[0].id,
+        compilation,
+    };
+    (row, tracks)
+}
+
+/// Tracks with an album artist tag are listed under it, other tracks are grouped by album
+/// and directory, and listed under Various Artists when by more than a couple of artists.
+/// Loose files have no directories, so `loose` decides whether they're told apart by
+/// artist or all grouped together
+fn group_albums(
+    songs: Vec<LibraryRow>,
+    loose: LooseFilePolicy,
+) -> Vec<(AlbumRow, Vec<LibraryRow>)> {
+    // Groups in the order their first track is listed
+    let mut groups: Vec<Vec<LibraryRow>> = Vec::new();
+    let mut group_of: HashMap<(String, AlbumKey), usize> = HashMap::new();
+    for song in songs {
+        let key = match (&song.album_artist, &song.album_dir) {
+            (Some(album_artist), _) => AlbumKey::Tagged(album_artist.to_lowercase()),
+            (None, (None, None)) if loose == LooseFilePolicy::UseTags => {
+                AlbumKey::Artist(song.artist.to_lowercase())
+            }
+            (None, (artist_dir, album_dir)) => AlbumKey::Dir(artist_dir.clone(), album_dir.clone()),
+        };
+        let index = *group_of
+            .entry((song.album.to_lowercase(), key))
+            .or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+        groups[index].push(song);
+    }
+
+    let mut albums = Vec::new();
+    for mut tracks in groups {
+        let mut artists: Vec<String> = Vec::new();
+        for track in &tracks {
+            let track_artist = track.artist.to_lowercase();
+            if !artists.contains(&track_artist) {
+                artists.push(track_artist);
+            }
+        }
+        if let Some(album_artist) = tracks[0].album_artist.clone() {
+            albums.push(album_row(album_artist, tracks, artists.len() > 1));
+        } else if artists.len() > COMPILATION_ARTISTS {
+            albums.push(album_row(VARIOUS_ARTISTS.to_string(), tracks, true));
+        } else {
+            // An album of the same name by each artist
+            for track_artist in artists {
+                let (by_artist, rest): (Vec<_>, Vec<_>) = tracks
+                    .into_iter()
+                    .partition(|t| t.artist.to_lowercase() == track_artist);
+                albums.push(album_row(by_artist[0].artist.clone(), by_artist, false));
+                tracks = rest;
+            }
+        }
+    }
+    albums
+}
+
+/// Albums in artist then album order, see [`group_albums`]. `artist` matches the album's
+/// artist
+pub async fn get_albums(
+    pool: &Pool<Sqlite>,
+    artist: Option<&str>,
+    loose: LooseFilePolicy,
+) -> Result<Vec<AlbumRow>, sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    let songs = get_library(&mut conn, -1, 0, SongSort::default()).await?;
+    let mut albums = group_albums(songs, loose)
+        .into_iter()
+        .map(|(row, _)| row)
+        .filter(|a| artist.is_none_or(|artist| a.artist.eq_ignore_ascii_case(artist)))
+        .collect::<Vec<_>>();
+    albums.sort_by_cached_key(|a| (a.artist.to_lowercase(), a.album.to_lowercase()));
+
+    Ok(albums)
+}
+
+/// One album and its tracks in play order. The album is found by its artist and name as
+/// [`get_albums`] lists it, or else by the directories the layout takes them from
+pub async fn get_album_tracks(
+    pool: &Pool<Sqlite>,
+    artist: &str,
+    album: &str,
+    loose: LooseFilePolicy,
+) -> Result<Option<(AlbumRow, Vec<LibraryRow>)>, sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    let songs = get_library(&mut conn, -1, 0, SongSort::default()).await?;
+    let in_dir = songs
+        .iter()
+        .filter(|s| match &s.album_dir {
+            (Some(artist_dir), Some(album_dir)) => {
+                artist_dir.eq_ignore_ascii_case(artist) && album_dir.eq_ignore_ascii_case(album)
+            }
+            _ => false,
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let listed = group_albums(songs, loose).into_iter().find(|(row, _)| {
+        row.artist.eq_ignore_ascii_case(artist) && row.album.eq_ignore_ascii_case(album)
+    });
+    if listed.is_some() || in_dir.is_empty() {
+        return Ok(listed);
+    }
+    // The directory may hold tracks tagged with other artists or albums
+    let dir_artist = in_dir[0].album_dir.0.clone().unwrap_or_default();
+    let first_artist = in_dir[0].artist.clone();
+    let compilation = in_dir.iter().any(|t| !t.artist.eq_ignore_ascii_case(&first_artist));
+    Ok(Some(album_row(dir_artist, in_dir, compilation)))
+}
+
+pub async fn get_artists(
+    pool: &Pool<Sqlite>,
+    loose: LooseFilePolicy,
+) -> Result<Vec<ArtistRow>, sqlx::Error> {
+    let mut artists: Vec<ArtistRow> = Vec::new();
+    for album in get_albums(pool, None, loose).await? {
+        match artists.last_mut() {
+            Some(last) if last.artist.to_lowercase() == album.artist.to_lowercase() => {
+                last.song_count += album.track_count;
+                last.album_count += 1;
+            }
+            _ => artists.push(ArtistRow {
+                artist: album.artist,
+                song_count: album.track_count,
+                album_count: 1,
+            }),
+        }
+    }
+
+    Ok(artists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_utils::test_db;
+
+    /// Tags of a track, `None` where the tag isn't set
+    #[derive(Default)]
+    struct Tags<'a> {
+        artist: Option<&'a str>,
+        album_artist: Option<&'a str>,
+        disc: Option<i64>,
+        track: Option<i64>,
+    }
+
+    /// Adds a present file at `path`, under an artist and album directory, with `tags`
+    async fn add_track(db: &Pool<Sqlite>, path: &str, tags: Tags<'_>) -> i64 {
+        let mut segments = path.split('/');
+        let (artist_dir, album_dir) = (segments.next(), segments.next());
+        let file_name = path.rsplit('/').next().unwrap().trim_end_matches(".mp3");
+        let id = sqlx::query(
+            "insert into filesystem_artifacts (relative_path, file_name, file_extension,
+                is_present, first_path_segment, second_path_segment, created_at)
+            values (?, ?, 'mp3', 1, ?, ?, 0)",
+        )
+        .bind(path)
+        .bind(file_name)
+        .bind(artist_dir)
+        .bind(album_dir)
+        .execute(db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        sqlx::query(
+            "insert into track_metadata (filesystem_artifact_id, artist, album_artist,
+                disc_number, track_number)
+            values (?, ?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(tags.artist)
+        .bind(tags.album_artist)
+        .bind(tags.disc)
+        .bind(tags.track)
+        .execute(db)
+        .await
+        .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn get_albums_counts_discs_and_lists_disc_1_first() {
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let mut ids = HashMap::new();
+        // File names sort against disc order
+        for (album, file_name, disc, track) in [
+            ("Double", "0_d2t1", Some(2), Some(1)),
+            ("Double", "a_d1t2", Some(1), Some(2)),
+            ("Double", "d1t1", Some(1), Some(1)),
+            ("Double", "d2t2", Some(2), Some(2)),
+            ("Single", "1", None, Some(1)),
+            ("Single", "2", Some(1), Some(2)),
+        ] {
+            let path = format!("Artist/{}/{}.mp3", album, file_name);
+            let tags = Tags {
+                disc,
+                track,
+                ..Tags::default()
+            };
+            ids.insert(file_name, add_track(&db, &path, tags).await);
+        }
+
+        let albums = get_albums(&db, None, LooseFilePolicy::default()).await.unwrap();
+        let names = albums.iter().map(|a| a.album.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Double", "Single"]);
+        assert_eq!((albums[0].track_count, albums[0].disc_count), (4, 2));
+        let in_order = ["d1t1", "a_d1t2", "0_d2t1", "d2t2"].map(|name| ids[name]);
+        assert_eq!(albums[0].song_ids, in_order);
+        // An untagged disc number is disc 1
+        assert_eq!((albums[1].track_count, albums[1].disc_count), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn get_albums_groups_a_compilation_under_various_artists() {
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let mut compilation = Vec::new();
+        for (track, artist) in ["Eve", "Dan", "Cat", "Bob", "Ann"].into_iter().enumerate() {
+            let path = format!("Various/Hits/{}.mp3", artist);
+            let tags = Tags {
+                artist: Some(artist),
+                track: Some(track as i64 + 1),
+                ..Tags::default()
+            };
+            compilation.push(add_track(&db, &path, tags).await);
+        }
+        // Tagged with an album artist, however few artists it has
+        for artist in ["Ann", "Bob"] {
+            let tags = Tags {
+                artist: Some(artist),
+                album_artist: Some("DJ"),
+                ..Tags::default()
+            };
+            add_track(&db, &format!("Mixes/Mix/{}.mp3", artist), tags).await;
+        }
+        // Too few artists to be a compilation, so an album by each
+        for artist in ["Ann", "Bob"] {
+            let tags = Tags {
+                artist: Some(artist),
+                ..Tags::default()
+            };
+            add_track(&db, &format!("Duets/Duets/{}.mp3", artist), tags).await;
+        }
+
+        let albums = get_albums(&db, None, LooseFilePolicy::default()).await.unwrap();
+        let listed = albums
+            .iter()
+            .map(|a| (a.artist.as_str(), a.album.as_str(), a.track_count, a.compilation))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            [
+                ("Ann", "Duets", 1, false),
+                ("Bob", "Duets", 1, false),
+                ("DJ", "Mix", 2, true),
+                (VARIOUS_ARTISTS, "Hits", 5, true),
+            ]
+        );
+        assert_eq!(albums[3].song_ids, compilation);
+
+        let (_, tracks) = get_album_tracks(&db, VARIOUS_ARTISTS, "Hits", LooseFilePolicy::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let artists = tracks.iter().map(|t| t.artist.as_str()).collect::<Vec<_>>();
+        assert_eq!(artists, ["Eve", "Dan", "Cat", "Bob", "Ann"]);
+    }
+}
@@ -0,0 +1,30 @@
+use sqlx::{pool::PoolConnection, Sqlite};
+
+pub async fn find_cover_path(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+) -> Result<Option<String>, sqlx::Error> {
+    Ok(sqlx::query!(
+        "select cover_path from track_metadata where filesystem_artifact_id = ?",
+        song_id
+    )
+    .fetch_optional(conn.as_mut())
+    .await?
+    .and_then(|r| r.cover_path))
+}
+
+/// Does nothing for songs without a metadata row yet; the directory is searched again next time
+pub async fn save_cover_path(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+    cover_path: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "update track_metadata set cover_path = ? where filesystem_artifact_id = ?",
+        cover_path,
+        song_id
+    )
+    .execute(conn.as_mut())
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use crate::types::{DuplicateFile, DuplicateGroup};
+use sqlx::{pool::PoolConnection, Sqlite};
+
+pub async fn get_duplicates(
+    conn: &mut PoolConnection<Sqlite>,
+) -> Result<Vec<DuplicateGroup>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        select
+            min(f.id) as "id!: i64",
+            f.relative_path,
+            f.content_hash as "content_hash!"
+        from filesystem_artifacts f
+        where
+            f.is_present = TRUE
+            and f.content_hash in (
+                select content_hash
+                from filesystem_artifacts
+                where is_present = TRUE and content_hash is not null
+                group by content_hash
+                having count(distinct relative_path) > 1
+            )
+        -- Tracks of a cue sheet share their file, which only counts once
+        group by f.content_hash, f.relative_path
+        order by f.content_hash, f.relative_path"#
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    for r in rows {
+        let file = DuplicateFile {
+            id: r.id,
+            path: r.relative_path,
+        };
+        match groups.last_mut() {
+            Some(last) if last.content_hash == r.content_hash => last.files.push(file),
+            _ => groups.push(DuplicateGroup {
+                content_hash: r.content_hash,
+                files: vec![file],
+            }),
+        }
+    }
+
+    Ok(groups)
+}
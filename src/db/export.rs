@@ -0,0 +1,373 @@
+use crate::types::{ExportMetadata, ExportRecord, ScanStatus};
+use sqlx::{pool::PoolConnection, Connection, Sqlite};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path};
+
+/// Every row of the library with its tags, in path order
+pub async fn export_library(
+    conn: &mut PoolConnection<Sqlite>,
+) -> Result<Vec<ExportRecord>, sqlx::Error> {
+    let mut additional_artists: HashMap<i64, Vec<String>> = HashMap::new();
+    for r in sqlx::query!(
+        "select filesystem_artifact_id, artist from track_artists order by filesystem_artifact_id, position"
+    )
+    .fetch_all(conn.as_mut())
+    .await?
+    {
+        additional_artists.entry(r.filesystem_artifact_id).or_default().push(r.artist);
+    }
+    let mut additional_genres: HashMap<i64, Vec<String>> = HashMap::new();
+    for r in sqlx::query!(
+        "select filesystem_artifact_id, genre from track_genres order by filesystem_artifact_id, position"
+    )
+    .fetch_all(conn.as_mut())
+    .await?
+    {
+        additional_genres.entry(r.filesystem_artifact_id).or_default().push(r.genre);
+    }
+
+    Ok(sqlx::query!(
+        r#"
+        select
+            f.id as "id!",
+            f.relative_path,
+            f.file_name,
+            f.file_extension,
+            f.is_present,
+            f.first_path_segment,
+            f.second_path_segment,
+            f.path_inferred,
+            f.created_at,
+            f.updated_at,
+            f.file_mtime,
+            f.file_size,
+            f.content_hash,
+            f.cue_track,
+            f.cue_start_ms,
+            f.cue_end_ms,
+            f.raw_path,
+            t.filesystem_artifact_id as "tagged?: i64",
+            t.artist,
+            t.album,
+            t.album_artist,
+            t.track_name,
+            t.genre,
+            t.composer,
+            t.release_year,
+            t.track_number,
+            t.disc_number,
+            t.duration,
+            t.track_gain,
+            t.album_gain,
+            t.bitrate,
+            t.sample_rate,
+            t.channels,
+            t.cover_path,
+            t.scan_status as "scan_status?: String",
+            t.scan_error
+        from filesystem_artifacts f
+        left join track_metadata t
+            on t.filesystem_artifact_id = f.id
+        order by f.relative_path, f.cue_track"#
+    )
+    .fetch_all(conn.as_mut())
+    .await?
+    .into_iter()
+    .map(|r| ExportRecord {
+        metadata: r.tagged.map(|_| ExportMetadata {
+            artist: r.artist,
+            album: r.album,
+            album_artist: r.album_artist,
+            track_name: r.track_name,
+            genre: r.genre,
+            composer: r.composer,
+            release_year: r.release_year,
+            track_number: r.track_number,
+            disc_number: r.disc_number,
+            duration: r.duration,
+            track_gain: r.track_gain,
+            album_gain: r.album_gain,
+            bitrate: r.bitrate,
+            sample_rate: r.sample_rate,
+            channels: r.channels,
+            cover_path: r.cover_path,
+            scan_status: r.scan_status.as_deref().map(ScanStatus::from_db).unwrap_or_default(),
+            scan_error: r.scan_error,
+            additional_artists: additional_artists.remove(&r.id).unwrap_or_default(),
+            additional_genres: additional_genres.remove(&r.id).unwrap_or_default(),
+        }),
+        relative_path: r.relative_path,
+        file_name: r.file_name,
+        file_extension: r.file_extension,
+        is_present: r.is_present != 0,
+        first_path_segment: r.first_path_segment,
+        second_path_segment: r.second_path_segment,
+        path_inferred: r.path_inferred != 0,
+        created_at: r.created_at,
+        updated_at: r.updated_at,
+        file_mtime: r.file_mtime,
+        file_size: r.file_size,
+        content_hash: r.content_hash,
+        cue_track: r.cue_track,
+        cue_start_ms: r.cue_start_ms,
+        cue_end_ms: r.cue_end_ms,
+        raw_path: r.raw_path,
+    })
+    .collect())
+}
+
+/// First problem that keeps `records` from being imported, naming the record by position
+pub fn validate_import(records: &[ExportRecord]) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for (i, record) in records.iter().enumerate() {
+        let path = Path::new(&record.relative_path);
+        if record.relative_path.is_empty()
+            || !path.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "record {}: relative_path '{}' is not a path inside the library",
+                i, record.relative_path
+            ));
+        }
+        if record.file_name.is_empty() || record.file_extension.is_empty() {
+            return Err(format!("record {}: file_name and file_extension are required", i));
+        }
+        if record.cue_track.is_some() != record.cue_start_ms.is_some() {
+            return Err(format!(
+                "record {}: cue_track and cue_start_ms must be given together",
+                i
+            ));
+        }
+        if !seen.insert((record.relative_path.as_str(), record.cue_track)) {
+            return Err(format!(
+                "record {}: '{}' appears more than once",
+                i, record.relative_path
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Loads `records` into a library with no rows yet, all or nothing. Returns how many were
+/// imported, or `None` when the library is not empty
+pub async fn import_library(
+    conn: &mut PoolConnection<Sqlite>,
+    records: &[ExportRecord],
+) -> Result<Option<u64>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+    let existing = sqlx::query!(r#"select count(*) as "total!: i64" from filesystem_artifacts"#)
+        .fetch_one(&mut *tx)
+        .await?
+        .total;
+    if existing > 0 {
+        return Ok(None);
+    }
+    for record in records {
+        let id = sqlx::query!(
+            "
+            insert into filesystem_artifacts (
+                relative_path,
+                file_name,
+                file_extension,
+                is_present,
+                first_path_segment,
+                second_path_segment,
+                path_inferred,
+                created_at,
+                updated_at,
+                file_mtime,
+                file_size,
+                content_hash,
+                cue_track,
+                cue_start_ms,
+                cue_end_ms,
+                raw_path
+            ) values (
+                ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
+            ) returning id;",
+            record.relative_path,
+            record.file_name,
+            record.file_extension,
+            record.is_present,
+            record.first_path_segment,
+            record.second_path_segment,
+            record.path_inferred,
+            record.created_at,
+            record.updated_at,
+            record.file_mtime,
+            record.file_size,
+            record.content_hash,
+            record.cue_track,
+            record.cue_start_ms,
+            record.cue_end_ms,
+            record.raw_path,
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .id;
+
+        let Some(meta) = &record.metadata else {
+            continue;
+        };
+        let scan_status = meta.scan_status.as_str();
+        sqlx::query!(
+            "
+            insert into track_metadata (
+                filesystem_artifact_id,
+                artist,
+                album,
+                album_artist,
+                track_name,
+                genre,
+                composer,
+                release_year,
+                track_number,
+                disc_number,
+                duration,
+                track_gain,
+                album_gain,
+                bitrate,
+                sample_rate,
+                channels,
+                cover_path,
+                scan_status,
+                scan_error
+            ) values (
+                ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ? )",
+            id,
+            meta.artist,
+            meta.album,
+            meta.album_artist,
+            meta.track_name,
+            meta.genre,
+            meta.composer,
+            meta.release_year,
+            meta.track_number,
+            meta.disc_number,
+            meta.duration,
+            meta.track_gain,
+            meta.album_gain,
+            meta.bitrate,
+            meta.sample_rate,
+            meta.channels,
+            meta.cover_path,
+            scan_status,
+            meta.scan_error
+        )
+        .execute(&mut *tx)
+        .await?;
+        for (position, artist) in meta.additional_artists.iter().enumerate() {
+            let position = position as i64;
+            sqlx::query!(
+                "insert into track_artists (filesystem_artifact_id, position, artist) values (?, ?, ?)",
+                id,
+                position,
+                artist
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        for (position, genre) in meta.additional_genres.iter().enumerate() {
+            let position = position as i64;
+            sqlx::query!(
+                "insert into track_genres (filesystem_artifact_id, position, genre) values (?, ?, ?)",
+                id,
+                position,
+                genre
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(Some(records.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_utils::{rescan_library, test_db, test_settings};
+
+    #[tokio::test]
+    async fn an_export_imports_into_an_empty_library_as_it_was() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let moved = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        for path in ["Artist/Album/01 One.mp3", "Artist/Album/02 Two.flac", "Loose.mp3"] {
+            let path = lib.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, [0u8; 2000]).unwrap();
+        }
+        rescan_library(&test_settings(), lib.path(), &db, false).await.unwrap();
+        sqlx::query(
+            "update track_metadata set artist = 'Tagged', genre = 'Rock', track_gain = -6.5
+            where filesystem_artifact_id = 1",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query(
+            "insert into track_genres (filesystem_artifact_id, position, genre)
+            values (1, 0, 'Pop'), (1, 1, 'Jazz')",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let exported = export_library(&mut db.acquire().await.unwrap()).await.unwrap();
+        assert_eq!(exported.len(), 3);
+
+        let json = serde_json::to_vec(&exported).unwrap();
+        let records = serde_json::from_slice::<Vec<ExportRecord>>(&json).unwrap();
+        validate_import(&records).unwrap();
+        let target = test_db(moved.path()).await;
+        let mut conn = target.acquire().await.unwrap();
+        assert_eq!(import_library(&mut conn, &records).await.unwrap(), Some(3));
+        let imported = export_library(&mut conn).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&imported).unwrap(),
+            serde_json::to_value(&exported).unwrap()
+        );
+
+        // Only into an empty library
+        assert_eq!(import_library(&mut conn, &records).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn an_export_keeps_the_raw_path_of_names_that_arent_utf8() {
+        let data = tempfile::tempdir().unwrap();
+        let moved = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let raw = b"Caf\xe9/Album/01 Cr\xe8me.mp3".to_vec();
+        sqlx::query(
+            "insert into filesystem_artifacts (relative_path, file_name, file_extension,
+                is_present, first_path_segment, second_path_segment, created_at, raw_path)
+            values (?, ?, 'mp3', 1, ?, 'Album', 0, ?)",
+        )
+        .bind("Caf\u{fffd}/Album/01 Cr\u{fffd}me.mp3")
+        .bind("01 Cr\u{fffd}me")
+        .bind("Caf\u{fffd}")
+        .bind(&raw)
+        .execute(&db)
+        .await
+        .unwrap();
+        let exported = export_library(&mut db.acquire().await.unwrap()).await.unwrap();
+        let json = serde_json::to_value(&exported).unwrap();
+
+        let target = test_db(moved.path()).await;
+        let mut conn = target.acquire().await.unwrap();
+        let records = serde_json::from_value::<Vec<ExportRecord>>(json).unwrap();
+        import_library(&mut conn, &records).await.unwrap();
+        let stored: Vec<u8> = sqlx::query_scalar("select raw_path from filesystem_artifacts")
+            .fetch_one(&target)
+            .await
+            .unwrap();
+        assert_eq!(stored, raw);
+
+        // Exports from before raw paths were kept still import
+        let mut old = serde_json::to_value(&exported).unwrap();
+        old[0].as_object_mut().unwrap().remove("raw_path");
+        let records = serde_json::from_value::<Vec<ExportRecord>>(old).unwrap();
+        assert_eq!(records[0].raw_path, None);
+    }
+}
@@ -0,0 +1,47 @@
+use crate::db::find_library_rows;
+use crate::types::LibraryRow;
+use sqlx::{pool::PoolConnection, Sqlite};
+
+/// Marks a song as a favorite, keeping the original time if it already was one
+pub async fn add_favorite(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+    created_at: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "
+        insert into favorites (filesystem_artifact_id, created_at) values (?, ?)
+        on conflict do nothing",
+        song_id,
+        created_at
+    )
+    .execute(conn.as_mut())
+    .await?;
+    Ok(())
+}
+
+pub async fn remove_favorite(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("delete from favorites where filesystem_artifact_id = ?", song_id)
+        .execute(conn.as_mut())
+        .await?;
+    Ok(())
+}
+
+/// Favorite songs, the most recently favorited first
+pub async fn get_favorites(
+    conn: &mut PoolConnection<Sqlite>,
+) -> Result<Vec<LibraryRow>, sqlx::Error> {
+    let favorites = sqlx::query_scalar!(
+        "select filesystem_artifact_id from favorites order by created_at desc, filesystem_artifact_id"
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+    let mut library = find_library_rows(conn, &favorites).await?;
+    Ok(favorites
+        .into_iter()
+        .filter_map(|id| library.remove(&id))
+        .collect())
+}
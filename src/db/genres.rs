@@ -0,0 +1,72 @@
+use crate::db::get_library;
+use crate::types::{GenreRow, LibraryRow, SongSort};
+use sqlx::{pool::PoolConnection, Sqlite};
+
+/// A song's primary and additional genres, or `unknown` when it has none
+fn song_genres<'a>(row: &'a LibraryRow, unknown: &'a str) -> Vec<&'a str> {
+    let genres = row
+        .genre
+        .iter()
+        .chain(row.additional_genres.iter())
+        .map(|g| g.trim())
+        .filter(|g| !g.is_empty())
+        .collect::<Vec<_>>();
+    if genres.is_empty() {
+        vec![unknown]
+    } else {
+        genres
+    }
+}
+
+/// Distinct genres, matched case-insensitively, most common first
+pub async fn get_genres(
+    conn: &mut PoolConnection<Sqlite>,
+    unknown: &str,
+) -> Result<Vec<GenreRow>, sqlx::Error> {
+    let songs = get_library(conn, -1, 0, SongSort::default()).await?;
+
+    let mut genres: Vec<GenreRow> = Vec::new();
+    for song in songs.iter() {
+        let mut seen: Vec<String> = Vec::new();
+        for genre in song_genres(song, unknown) {
+            let key = genre.to_lowercase();
+            // A song tagged `Rock; rock` still counts once
+            if seen.contains(&key) {
+                continue;
+            }
+            match genres.iter_mut().find(|g| g.genre.to_lowercase() == key) {
+                Some(row) => row.track_count += 1,
+                None => genres.push(GenreRow {
+                    genre: genre.to_string(),
+                    track_count: 1,
+                }),
+            }
+            seen.push(key);
+        }
+    }
+    genres.sort_by(|a, b| {
+        b.track_count
+            .cmp(&a.track_count)
+            .then_with(|| a.genre.to_lowercase().cmp(&b.genre.to_lowercase()))
+    });
+
+    Ok(genres)
+}
+
+/// Songs with `genre` as their primary or an additional genre, in library order
+pub async fn get_genre_songs(
+    conn: &mut PoolConnection<Sqlite>,
+    genre: &str,
+    unknown: &str,
+) -> Result<Vec<LibraryRow>, sqlx::Error> {
+    let genre = genre.trim().to_lowercase();
+    Ok(get_library(conn, -1, 0, SongSort::default())
+        .await?
+        .into_iter()
+        .filter(|song| {
+            song_genres(song, unknown)
+                .iter()
+                .any(|g| g.to_lowercase() == genre)
+        })
+        .collect())
+}
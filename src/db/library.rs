@@ -1,54 +1,949 @@
-use crate::file_utils::pretty_duration;
-use crate::types::LibraryRow;
+use crate::file_utils::{cue_from_row, pretty_duration, raw_path_from_bytes};
+use crate::types::{
+    AddedSong, LibraryRow, QueueScope, ScanStatus, Song, SongFilter, SongSort, SortOrder,
+    TrackMetadata,
+};
 use sqlx::{pool::PoolConnection, Sqlite};
+use std::collections::HashMap;
 
-pub async fn get_library(mut conn: PoolConnection<Sqlite>) -> Result<Vec<LibraryRow>, sqlx::Error> {
+/// `ids` as a JSON array, which queries take apart with `json_each` to match a list of songs
+fn json_ids(ids: &[i64]) -> String {
+    let ids = ids.iter().map(i64::to_string).collect::<Vec<_>>();
+    format!("[{}]", ids.join(","))
+}
+
+/// Additional artists and genres of the songs in `song_ids`
+async fn get_additional_values(
+    conn: &mut PoolConnection<Sqlite>,
+    song_ids: &[i64],
+) -> Result<(HashMap<i64, Vec<String>>, HashMap<i64, Vec<String>>), sqlx::Error> {
+    let song_ids = json_ids(song_ids);
+    let mut artists: HashMap<i64, Vec<String>> = HashMap::new();
+    for r in sqlx::query!(
+        "
+        select filesystem_artifact_id, artist from track_artists
+        where filesystem_artifact_id in (select value from json_each(?))
+        order by filesystem_artifact_id, position",
+        song_ids
+    )
+    .fetch_all(conn.as_mut())
+    .await?
+    {
+        artists.entry(r.filesystem_artifact_id).or_default().push(r.artist);
+    }
+
+    let mut genres: HashMap<i64, Vec<String>> = HashMap::new();
+    for r in sqlx::query!(
+        "
+        select filesystem_artifact_id, genre from track_genres
+        where filesystem_artifact_id in (select value from json_each(?))
+        order by filesystem_artifact_id, position",
+        song_ids
+    )
+    .fetch_all(conn.as_mut())
+    .await?
+    {
+        genres.entry(r.filesystem_artifact_id).or_default().push(r.genre);
+    }
+
+    Ok((artists, genres))
+}
+
+pub async fn count_library(conn: &mut PoolConnection<Sqlite>) -> Result<i64, sqlx::Error> {
+    Ok(sqlx::query!("select count(*) as total from filesystem_artifacts")
+        .fetch_one(conn.as_mut())
+        .await?
+        .total as i64)
+}
+
+/// Songs matching `filter`, as counted for paging through [`get_filtered_library`]
+pub async fn count_filtered_library(
+    conn: &mut PoolConnection<Sqlite>,
+    filter: &SongFilter,
+) -> Result<i64, sqlx::Error> {
+    let song_ids = filter.song_ids.as_deref().map(json_ids);
     Ok(sqlx::query!(
+        "
+        select count(*) as total from (
+        select
+            f.id,
+            ifnull(t.artist, f.first_path_segment) as artist,
+            ifnull(t.album, f.second_path_segment) as album,
+            t.genre,
+            t.release_year
+        from filesystem_artifacts f
+        left join track_metadata t
+            on t.filesystem_artifact_id = f.id
+        ) a
+        where (?1 is null or a.artist = ?1 collate nocase)
+            and (?2 is null or a.album = ?2 collate nocase)
+            and (?3 is null
+                or (?3 = ''
+                    and ifnull(trim(a.genre), '') = ''
+                    and not exists (
+                        select 1 from track_genres g
+                        where g.filesystem_artifact_id = a.id and trim(g.genre) != ''
+                    ))
+                or trim(a.genre) = ?3 collate nocase
+                or exists (
+                    select 1 from track_genres g
+                    where g.filesystem_artifact_id = a.id and trim(g.genre) = ?3 collate nocase
+                ))
+            and (?4 is null or a.release_year = ?4)
+            and (?5 is null or a.id in (select value from json_each(?5)))
+    ",
+        filter.artist,
+        filter.album,
+        filter.genre,
+        filter.year,
+        song_ids
+    )
+    .fetch_one(conn.as_mut())
+    .await?
+    .total as i64)
+}
+
+pub async fn get_library(
+    conn: &mut PoolConnection<Sqlite>,
+    limit: i64,
+    offset: i64,
+    sort: SongSort,
+) -> Result<Vec<LibraryRow>, sqlx::Error> {
+    let filter = SongFilter::default();
+    query_library(conn, limit, offset, sort, SortOrder::Asc, &filter).await
+}
+
+/// A page of the songs matching `filter`, sorted by `sort` in `order`
+pub async fn get_filtered_library(
+    conn: &mut PoolConnection<Sqlite>,
+    limit: i64,
+    offset: i64,
+    sort: SongSort,
+    order: SortOrder,
+    filter: &SongFilter,
+) -> Result<Vec<LibraryRow>, sqlx::Error> {
+    query_library(conn, limit, offset, sort, order, filter).await
+}
+
+pub async fn find_library_row(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+) -> Result<Option<LibraryRow>, sqlx::Error> {
+    Ok(find_library_rows(conn, &[song_id]).await?.remove(&song_id))
+}
+
+/// Rows of the songs in `song_ids` by id, leaving out ids without a song
+pub async fn find_library_rows(
+    conn: &mut PoolConnection<Sqlite>,
+    song_ids: &[i64],
+) -> Result<HashMap<i64, LibraryRow>, sqlx::Error> {
+    let filter = SongFilter {
+        song_ids: Some(song_ids.to_vec()),
+        ..Default::default()
+    };
+    Ok(
+        query_library(conn, -1, 0, SongSort::default(), SortOrder::Asc, &filter)
+            .await?
+            .into_iter()
+            .map(|row| (row.id, row))
+            .collect(),
+    )
+}
+
+async fn query_library(
+    conn: &mut PoolConnection<Sqlite>,
+    limit: i64,
+    offset: i64,
+    sort: SongSort,
+    order: SortOrder,
+    filter: &SongFilter,
+) -> Result<Vec<LibraryRow>, sqlx::Error> {
+    let sort = sort.as_str();
+    let order = order.as_str();
+    let song_ids = filter.song_ids.as_deref().map(json_ids);
+    let rows = sqlx::query!(
         "
         select * from (
         select
             f.id,
             f.file_name,
+            f.file_extension,
             f.first_path_segment,
             f.second_path_segment,
+            f.path_inferred,
             ifnull(t.artist, f.first_path_segment) as artist,
             ifnull(t.album, f.second_path_segment) as album,
             ifnull(t.track_name, f.file_name) as track_name,
+            t.album_artist,
             t.genre,
             t.composer,
             t.release_year,
             t.duration,
             t.track_number,
-            f.is_present
+            t.disc_number,
+            t.track_gain,
+            t.album_gain,
+            t.bitrate,
+            t.sample_rate,
+            t.channels,
+            f.file_size,
+            t.artist is null and f.path_inferred != 0 as artist_inferred,
+            t.album is null and f.path_inferred != 0 as album_inferred,
+            f.is_present,
+            f.created_at,
+            v.filesystem_artifact_id is not null as is_favorite
         from filesystem_artifacts f
         left join track_metadata t
             on t.filesystem_artifact_id = f.id
+        left join favorites v
+            on v.filesystem_artifact_id = f.id
         ) a
-        order by lower(a.artist), lower(a.album), a.track_number, a.track_name
-    "
+        where (?1 is null or a.id in (select value from json_each(?1)))
+            and (?6 is null or a.artist = ?6 collate nocase)
+            and (?7 is null or a.album = ?7 collate nocase)
+            and (?8 is null
+                or (?8 = ''
+                    and ifnull(trim(a.genre), '') = ''
+                    and not exists (
+                        select 1 from track_genres g
+                        where g.filesystem_artifact_id = a.id and trim(g.genre) != ''
+                    ))
+                or trim(a.genre) = ?8 collate nocase
+                or exists (
+                    select 1 from track_genres g
+                    where g.filesystem_artifact_id = a.id and trim(g.genre) = ?8 collate nocase
+                ))
+            and (?9 is null or a.release_year = ?9)
+        order by
+            -- The key is repeated as ascending, then descending, sorting on only one of them
+            case when ?5 = 'desc' then null else
+                case ?4
+                    when 'album' then lower(a.album)
+                    when 'title' then lower(a.track_name)
+                    when 'duration' then a.duration
+                    when 'added' then a.created_at
+                    else lower(a.artist)
+                end
+            end,
+            case when ?5 = 'desc' then
+                case ?4
+                    when 'album' then lower(a.album)
+                    when 'title' then lower(a.track_name)
+                    when 'duration' then a.duration
+                    when 'added' then a.created_at
+                    else lower(a.artist)
+                end
+            end desc,
+            lower(a.artist),
+            lower(a.album),
+            a.disc_number is null, a.disc_number,
+            a.track_number is null, a.track_number,
+            a.file_name
+        limit ?2 offset ?3
+    ",
+        song_ids,
+        limit,
+        offset,
+        sort,
+        order,
+        filter.artist,
+        filter.album,
+        filter.genre,
+        filter.year
     )
     .fetch_all(conn.as_mut())
+    .await?;
+    let ids = rows.iter().map(|r| r.id).collect::<Vec<_>>();
+    let (mut additional_artists, mut additional_genres) = get_additional_values(conn, &ids).await?;
+    Ok(rows
+        .iter()
+        .map(|r| LibraryRow {
+            id: r.id,
+            track_name: r.track_name.clone(),
+            file_extension: r.file_extension.clone(),
+            duration: r.duration.map(|d| d as u32),
+            duration_pretty: pretty_duration(r.duration.unwrap_or(0)),
+            artist: r
+                .artist
+                .clone()
+                .or(r.first_path_segment.clone())
+                .unwrap_or(String::from("Unknown")),
+            additional_artists: additional_artists.remove(&r.id).unwrap_or_default(),
+            album: r
+                .album
+                .clone()
+                .or(r.second_path_segment.clone())
+                .unwrap_or(String::from("Unknown")),
+            album_artist: r.album_artist.clone(),
+            // The placeholders of loose files aren't directories
+            album_dir: match r.path_inferred {
+                0 => (r.first_path_segment.clone(), r.second_path_segment.clone()),
+                _ => (None, None),
+            },
+            track_number: r.track_number.map(|t| t as u16),
+            disc_number: r.disc_number.map(|d| d as u16),
+            genre: r.genre.clone(),
+            additional_genres: additional_genres.remove(&r.id).unwrap_or_default(),
+            composer: r.composer.clone(),
+            release_year: r.release_year.map(|t| t as u16),
+            track_gain: r.track_gain,
+            album_gain: r.album_gain,
+            bitrate: r.bitrate.map(|b| b as u32),
+            sample_rate: r.sample_rate.map(|r| r as u32),
+            channels: r.channels.map(|c| c as u8),
+            file_size: r.file_size.map(|s| s as u64),
+            artist_inferred: r.artist_inferred != 0,
+            album_inferred: r.album_inferred != 0,
+            is_present: r.is_present != 0,
+            is_favorite: r.is_favorite != 0,
+        })
+        .collect::<Vec<_>>())
+}
+
+pub async fn find_song(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+) -> Result<Option<Song>, sqlx::Error> {
+    Ok(sqlx::query!(
+        "
+        select
+            id,
+            relative_path,
+            file_name,
+            file_extension,
+            first_path_segment,
+            second_path_segment,
+            path_inferred,
+            cue_track,
+            cue_start_ms,
+            cue_end_ms,
+            raw_path
+        from filesystem_artifacts
+        where id = ?",
+        song_id
+    )
+    .fetch_optional(conn.as_mut())
+    .await?
+    .map(|r| Song {
+        id: r.id as u64,
+        file_name: r.file_name,
+        file_extension: r.file_extension,
+        artist: r.first_path_segment.unwrap_or(String::from("Unknown")),
+        album: r.second_path_segment.unwrap_or(String::from("Unknown")),
+        relative_path: r.relative_path,
+        raw_path: r.raw_path.map(raw_path_from_bytes),
+        path_inferred: r.path_inferred != 0,
+        cue: cue_from_row(r.cue_track, r.cue_start_ms, r.cue_end_ms),
+    }))
+}
+
+/// The song's tags as last scanned, `None` until its metadata has been read
+pub async fn find_track_metadata(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+) -> Result<Option<TrackMetadata>, sqlx::Error> {
+    let Some(r) = sqlx::query!(
+        "
+        select
+            filesystem_artifact_id,
+            artist,
+            album,
+            album_artist,
+            track_name,
+            genre,
+            composer,
+            release_year,
+            track_number,
+            duration,
+            disc_number,
+            track_gain,
+            album_gain,
+            bitrate,
+            sample_rate,
+            channels,
+            scan_status,
+            scan_error
+        from track_metadata
+        where filesystem_artifact_id = ?",
+        song_id
+    )
+    .fetch_optional(conn.as_mut())
     .await?
-    .iter()
-    .map(|r| LibraryRow {
-        id: r.id,
-        track_name: r.track_name.clone(),
-        duration: r.duration.map(pretty_duration),
-        artist: r
-            .artist
-            .clone()
-            .or(r.first_path_segment.clone())
-            .unwrap_or(String::from("Unknown")),
-        album: r
-            .album
-            .clone()
-            .or(r.second_path_segment.clone())
-            .unwrap_or(String::from("Unknown")),
+    else {
+        return Ok(None);
+    };
+    let additional_artists = sqlx::query_scalar!(
+        "select artist from track_artists where filesystem_artifact_id = ? order by position",
+        song_id
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+    let additional_genres = sqlx::query_scalar!(
+        "select genre from track_genres where filesystem_artifact_id = ? order by position",
+        song_id
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+    Ok(Some(TrackMetadata {
+        file_artifact_id: r.filesystem_artifact_id,
+        title: r.track_name,
+        album: r.album,
+        artist: r.artist,
+        album_artist: r.album_artist,
+        year: r.release_year.map(|y| y as u16),
+        duration: r.duration.map(|d| d as u32),
+        genre: r.genre,
+        composer: r.composer,
         track_number: r.track_number.map(|t| t as u16),
-        genre: r.genre.clone(),
-        composer: r.composer.clone(),
-        release_year: r.release_year.map(|t| t as u16),
-        is_present: r.is_present != 0,
+        disc_number: r.disc_number.map(|d| d as u16),
+        track_gain: r.track_gain,
+        album_gain: r.album_gain,
+        bitrate: r.bitrate.map(|b| b as u32),
+        sample_rate: r.sample_rate.map(|s| s as u32),
+        channels: r.channels.map(|c| c as u8),
+        additional_artists,
+        additional_genres,
+        scan_status: ScanStatus::from_db(&r.scan_status),
+        scan_error: r.scan_error,
+    }))
+}
+
+/// The present song after `after` in library order among those `scope` shares with it,
+/// `None` once the scope runs out. With `wrap` the scope starts over after its last song
+pub async fn next_song_id(
+    conn: &mut PoolConnection<Sqlite>,
+    after: i64,
+    scope: QueueScope,
+    wrap: bool,
+) -> Result<Option<i64>, sqlx::Error> {
+    let scope = match scope {
+        QueueScope::Album => "album",
+        QueueScope::Artist => "artist",
+        QueueScope::All => "all",
+    };
+    sqlx::query_scalar!(
+        r#"
+        -- The keys of the library order, with nulls made comparable
+        with ordered as (
+        select
+            f.id,
+            f.is_present,
+            lower(ifnull(ifnull(t.artist, f.first_path_segment), '')) as artist,
+            lower(ifnull(ifnull(t.album, f.second_path_segment), '')) as album,
+            t.disc_number is null as no_disc,
+            ifnull(t.disc_number, 0) as disc,
+            t.track_number is null as no_track,
+            ifnull(t.track_number, 0) as track,
+            f.file_name
+        from filesystem_artifacts f
+        left join track_metadata t
+            on t.filesystem_artifact_id = f.id
+        ),
+        in_scope as (
+        select
+            o.*,
+            (o.artist, o.album, o.no_disc, o.disc, o.no_track, o.track, o.file_name, o.id)
+                > (c.artist, c.album, c.no_disc, c.disc, c.no_track, c.track, c.file_name, c.id)
+                as later
+        from ordered o, ordered c
+        where c.id = ?1
+            and o.is_present != 0
+            and (?2 = 'all' or o.artist = c.artist)
+            and (?2 != 'album' or o.album = c.album)
+        )
+        select id as "id!" from in_scope
+        where later or ?3
+        order by later desc, artist, album, no_disc, disc, no_track, track, file_name, id
+        limit 1
+    "#,
+        after,
+        scope,
+        wrap
+    )
+    .fetch_optional(conn.as_mut())
+    .await
+}
+
+/// Up to `count` distinct present songs matching `filter`, picked at random
+pub async fn random_song_ids(
+    conn: &mut PoolConnection<Sqlite>,
+    count: i64,
+    filter: &SongFilter,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "
+        select a.id from (
+        select
+            f.id,
+            f.is_present,
+            ifnull(t.artist, f.first_path_segment) as artist,
+            t.genre
+        from filesystem_artifacts f
+        left join track_metadata t
+            on t.filesystem_artifact_id = f.id
+        ) a
+        where a.is_present != 0
+            and (?1 is null or a.artist = ?1 collate nocase)
+            and (?2 is null
+                or (?2 = ''
+                    and ifnull(trim(a.genre), '') = ''
+                    and not exists (
+                        select 1 from track_genres g
+                        where g.filesystem_artifact_id = a.id and trim(g.genre) != ''
+                    ))
+                or trim(a.genre) = ?2 collate nocase
+                or exists (
+                    select 1 from track_genres g
+                    where g.filesystem_artifact_id = a.id and trim(g.genre) = ?2 collate nocase
+                ))
+        order by random()
+        limit ?3
+    ",
+        filter.artist,
+        filter.genre,
+        count
+    )
+    .fetch_all(conn.as_mut())
+    .await
+}
+
+/// Most recently discovered songs first
+pub async fn get_recently_added(
+    conn: &mut PoolConnection<Sqlite>,
+    limit: i64,
+) -> Result<Vec<AddedSong>, sqlx::Error> {
+    let added = sqlx::query!(
+        r#"
+        select
+            id as "id!",
+            created_at,
+            strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'unixepoch') as "created_at_iso!: String"
+        from filesystem_artifacts
+        order by created_at desc, id desc
+        limit ?"#,
+        limit
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+    let ids = added.iter().map(|r| r.id).collect::<Vec<_>>();
+    let mut library = find_library_rows(conn, &ids).await?;
+    Ok(added
+        .into_iter()
+        .filter_map(|r| {
+            Some(AddedSong {
+                created_at: r.created_at,
+                created_at_iso: r.created_at_iso,
+                song: library.remove(&r.id)?,
+            })
+        })
+        .collect())
+}
+
+/// A `like` pattern for fields containing `query`. SQLite only ignores the case of ASCII
+/// letters, so other characters match any one character and the match is checked again
+fn contains_pattern(query: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in query.chars() {
+        match c {
+            '%' | '_' | '\\' => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            c if !c.is_ascii() => pattern.push('_'),
+            c => pattern.push(c),
+        }
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Ids of songs in library order, with their lowercased track name, artist and album.
+/// When `pattern` is set, only songs with one of the three `like` it, or holding a NUL that
+/// `like` can't see past, are listed
+async fn search_fields(
+    conn: &mut PoolConnection<Sqlite>,
+    pattern: Option<&str>,
+) -> Result<Vec<(i64, [String; 3])>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+        select
+            a.id,
+            a.track_name as "track_name!: String",
+            a.artist as "artist!: String",
+            a.album as "album!: String"
+        from (
+        select
+            f.id,
+            f.file_name,
+            ifnull(t.track_name, f.file_name) as track_name,
+            coalesce(t.artist, f.first_path_segment, 'Unknown') as artist,
+            coalesce(t.album, f.second_path_segment, 'Unknown') as album,
+            t.disc_number,
+            t.track_number
+        from filesystem_artifacts f
+        left join track_metadata t
+            on t.filesystem_artifact_id = f.id
+        ) a
+        where ?1 is null
+            or a.track_name like ?1 escape '\'
+            or a.artist like ?1 escape '\'
+            or a.album like ?1 escape '\'
+            -- like stops at a NUL, which separates the values of some tags
+            or instr(cast(a.track_name || a.artist || a.album as blob), x'00') > 0
+        order by
+            lower(a.artist),
+            lower(a.album),
+            a.disc_number is null, a.disc_number,
+            a.track_number is null, a.track_number,
+            a.file_name
+        "#,
+        pattern
+    )
+    .fetch_all(conn.as_mut())
+    .await?
+    .into_iter()
+    .map(|r| {
+        let fields = [r.track_name, r.artist, r.album].map(|f| f.to_lowercase());
+        (r.id, fields)
     })
-    .collect::<Vec<_>>())
+    .collect())
+}
+
+/// Ids of songs whose track name, artist or album contains `query`, ignoring case, with
+/// prefix matches ranked above other matches
+pub async fn search_library(
+    conn: &mut PoolConnection<Sqlite>,
+    query: &str,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let pattern = contains_pattern(query);
+    let query = query.to_lowercase();
+    let mut matches = search_fields(conn, Some(&pattern))
+        .await?
+        .into_iter()
+        .filter_map(|(id, fields)| {
+            if fields.iter().any(|f| f.starts_with(&query)) {
+                Some((0, id))
+            } else if fields.iter().any(|f| f.contains(&query)) {
+                Some((1, id))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    matches.sort_by_key(|m| m.0);
+    Ok(matches.into_iter().map(|m| m.1).collect())
+}
+
+/// Lowest Jaro-Winkler similarity a fuzzy match must reach
+const FUZZY_THRESHOLD: f64 = 0.85;
+
+/// How well `query` matches `field`: 1.0 when it's a substring, otherwise the best
+/// Jaro-Winkler similarity to the whole field or any one of its words
+fn fuzzy_score(query: &str, field: &str) -> f64 {
+    if field.contains(query) {
+        return 1.0;
+    }
+    field
+        .split_whitespace()
+        .chain(std::iter::once(field))
+        .map(|candidate| strsim::jaro_winkler(query, candidate))
+        .fold(0.0, f64::max)
+}
+
+/// Ids of songs whose track name, artist or album is like `query` despite typos, best
+/// matches first. Misspellings can't be matched in SQL, so every song's three fields are
+/// scored, but nothing else of them is read
+pub async fn fuzzy_search_library(
+    conn: &mut PoolConnection<Sqlite>,
+    query: &str,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let query = query.to_lowercase();
+    let mut matches = search_fields(conn, None)
+        .await?
+        .into_iter()
+        .filter_map(|(id, fields)| {
+            let score = fields
+                .iter()
+                .map(|f| fuzzy_score(&query, f))
+                .fold(0.0, f64::max);
+            (score >= FUZZY_THRESHOLD).then_some((score, id))
+        })
+        .collect::<Vec<_>>();
+    // Stable, so equally good matches keep library order
+    matches.sort_by(|a, b| b.0.total_cmp(&a.0));
+    Ok(matches.into_iter().map(|m| m.1).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_utils::test_db;
+    use sqlx::Pool;
+
+    /// A tagged song added at `created_at`, returning its id
+    async fn add_song(db: &Pool<Sqlite>, title: &str, artist: &str, created_at: i64) -> i64 {
+        let id = sqlx::query(
+            "insert into filesystem_artifacts (relative_path, file_name, file_extension,
+                is_present, first_path_segment, second_path_segment, created_at)
+            values (?, ?, 'mp3', 1, ?, 'Album', ?)",
+        )
+        .bind(format!("{}/Album/{}.mp3", artist, title))
+        .bind(title)
+        .bind(artist)
+        .bind(created_at)
+        .execute(db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        sqlx::query(
+            "insert into track_metadata (filesystem_artifact_id, artist, album, track_name)
+            values (?, ?, 'Album', ?)",
+        )
+        .bind(id)
+        .bind(artist)
+        .bind(title)
+        .execute(db)
+        .await
+        .unwrap();
+        id
+    }
+
+    #[test]
+    fn fuzzy_score_rates_substrings_then_the_closest_word() {
+        assert_eq!(fuzzy_score("moon", "blue moon"), 1.0);
+        assert!(fuzzy_score("mooon", "blue moon") >= FUZZY_THRESHOLD);
+        assert!(fuzzy_score("beatels", "the beatles") >= FUZZY_THRESHOLD);
+        assert!(fuzzy_score("blue mon", "blue moon") >= FUZZY_THRESHOLD);
+        assert!(fuzzy_score("zeppelin", "blue moon") < FUZZY_THRESHOLD);
+        assert_eq!(fuzzy_score("moon", ""), 0.0);
+    }
+
+    #[test]
+    fn contains_pattern_escapes_wildcards_and_widens_other_characters() {
+        assert_eq!(contains_pattern("moon"), "%moon%");
+        assert_eq!(contains_pattern("100%_a\\b"), "%100\\%\\_a\\\\b%");
+        assert_eq!(contains_pattern("Björk"), "%Bj_rk%");
+    }
+
+    #[tokio::test]
+    async fn search_library_ranks_prefix_matches_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = test_db(dir.path()).await;
+        let blue_moon = add_song(&db, "Blue Moon", "Artist", 1).await;
+        let moonlight = add_song(&db, "Moonlight", "Beta", 1).await;
+        let by_moon = add_song(&db, "Song", "Moon Band", 1).await;
+        add_song(&db, "Sunrise", "Artist", 1).await;
+        let mut conn = db.acquire().await.unwrap();
+
+        let ids = search_library(&mut conn, "MOON").await.unwrap();
+        // Each kind of match in library order, which is by artist
+        assert_eq!(ids, [moonlight, by_moon, blue_moon]);
+        assert!(search_library(&mut conn, "zeppelin").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_library_matches_wildcards_and_unusual_text_literally() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = test_db(dir.path()).await;
+        let pure = add_song(&db, "100% Pure", "Artist", 1).await;
+        add_song(&db, "1000 Pure", "Artist", 1).await;
+        let bjork = add_song(&db, "Song", "BJÖRK", 1).await;
+        add_song(&db, "Song", "Bjark", 1).await;
+        let either_or = add_song(&db, "Either\0Or", "Artist", 1).await;
+        let mut conn = db.acquire().await.unwrap();
+
+        assert_eq!(search_library(&mut conn, "100%").await.unwrap(), [pure]);
+        assert!(search_library(&mut conn, "_").await.unwrap().is_empty());
+        assert_eq!(search_library(&mut conn, "björk").await.unwrap(), [bjork]);
+        assert_eq!(search_library(&mut conn, "or").await.unwrap(), [either_or]);
+    }
+
+    #[tokio::test]
+    async fn fuzzy_search_library_finds_misspellings_best_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = test_db(dir.path()).await;
+        let helo = add_song(&db, "Helo", "A", 1).await;
+        let hello = add_song(&db, "Hello", "B", 1).await;
+        let beatles = add_song(&db, "Song", "The Beatles", 1).await;
+        add_song(&db, "Song", "Zeppelin", 1).await;
+        let mut conn = db.acquire().await.unwrap();
+
+        let ids = fuzzy_search_library(&mut conn, "hello").await.unwrap();
+        assert_eq!(ids, [hello, helo]);
+        let ids = fuzzy_search_library(&mut conn, "beatels").await.unwrap();
+        assert_eq!(ids, [beatles]);
+        assert!(fuzzy_search_library(&mut conn, "qqqq").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_recently_added_reads_only_the_newest_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = test_db(dir.path()).await;
+        let old = add_song(&db, "Old", "Artist", 100).await;
+        let new = add_song(&db, "New", "Artist", 300).await;
+        let newer = add_song(&db, "Newer", "Artist", 300).await;
+        sqlx::query("insert into track_artists values (?, 0, 'Guest'), (?, 0, 'Other')")
+            .bind(newer)
+            .bind(old)
+            .execute(&db)
+            .await
+            .unwrap();
+        let mut conn = db.acquire().await.unwrap();
+
+        let added = get_recently_added(&mut conn, 2).await.unwrap();
+        let ids = added.iter().map(|a| a.song.id).collect::<Vec<_>>();
+        assert_eq!(ids, [newer, new]);
+        assert_eq!(added[0].song.additional_artists, ["Guest"]);
+        assert_eq!(added[0].created_at_iso, "1970-01-01T00:05:00Z");
+
+        let rows = find_library_rows(&mut conn, &[old, 999]).await.unwrap();
+        assert_eq!(rows.keys().collect::<Vec<_>>(), [&old]);
+        assert_eq!(rows[&old].additional_artists, ["Other"]);
+    }
+
+    #[tokio::test]
+    async fn random_song_ids_picks_distinct_present_songs() {
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let mut ids = Vec::new();
+        for n in 0..12 {
+            let artist = if n < 9 { "Artist" } else { "Other" };
+            ids.push(add_song(&db, &format!("Song {}", n), artist, n).await);
+        }
+        sqlx::query("update filesystem_artifacts set is_present = 0 where id = ?")
+            .bind(ids[0])
+            .execute(&db)
+            .await
+            .unwrap();
+        let mut conn = db.acquire().await.unwrap();
+
+        let picked = random_song_ids(&mut conn, 5, &SongFilter::default()).await.unwrap();
+        let distinct = picked.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(picked.len(), 5);
+        assert_eq!(distinct.len(), 5);
+        assert!(picked.iter().all(|id| ids[1..].contains(id)));
+
+        let filter = SongFilter {
+            artist: Some("other".into()),
+            ..Default::default()
+        };
+        let mut picked = random_song_ids(&mut conn, 100, &filter).await.unwrap();
+        picked.sort();
+        assert_eq!(picked, ids[9..]);
+
+        let all = random_song_ids(&mut conn, 100, &SongFilter::default()).await.unwrap();
+        assert_eq!(all.len(), 11);
+    }
+
+    /// A song tagged as track `track` of `album`, returning its id
+    async fn add_track(db: &Pool<Sqlite>, artist: &str, album: &str, track: i64) -> i64 {
+        let id = sqlx::query(
+            "insert into filesystem_artifacts (relative_path, file_name, file_extension,
+                is_present, first_path_segment, second_path_segment, created_at)
+            values (?, ?, 'mp3', 1, ?, ?, 0)",
+        )
+        .bind(format!("{}/{}/{}.mp3", artist, album, track))
+        .bind(track.to_string())
+        .bind(artist)
+        .bind(album)
+        .execute(db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        sqlx::query(
+            "insert into track_metadata (filesystem_artifact_id, artist, album, track_name,
+                track_number)
+            values (?, ?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(artist)
+        .bind(album)
+        .bind(format!("Track {}", track))
+        .bind(track)
+        .execute(db)
+        .await
+        .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn next_song_id_follows_the_album_and_stops_at_its_end() {
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        // Added out of order, so only the track numbers give the sequence
+        let third = add_track(&db, "Artist", "First", 3).await;
+        let first = add_track(&db, "Artist", "First", 1).await;
+        let second = add_track(&db, "Artist", "First", 2).await;
+        let missing = add_track(&db, "Artist", "First", 4).await;
+        let later_album = add_track(&db, "artist", "Second", 1).await;
+        let other_artist = add_track(&db, "Band", "Album", 1).await;
+        sqlx::query("update filesystem_artifacts set is_present = 0 where id = ?")
+            .bind(missing)
+            .execute(&db)
+            .await
+            .unwrap();
+        let next = |after, scope, wrap| {
+            let db = db.clone();
+            async move {
+                let mut conn = db.acquire().await.unwrap();
+                next_song_id(&mut conn, after, scope, wrap).await.unwrap()
+            }
+        };
+
+        assert_eq!(next(first, QueueScope::Album, false).await, Some(second));
+        assert_eq!(next(second, QueueScope::Album, false).await, Some(third));
+        // The missing fourth track is skipped, and the album ends there
+        assert_eq!(next(third, QueueScope::Album, false).await, None);
+        assert_eq!(next(third, QueueScope::Album, true).await, Some(first));
+        assert_eq!(next(third, QueueScope::Artist, false).await, Some(later_album));
+        assert_eq!(next(later_album, QueueScope::Artist, false).await, None);
+        assert_eq!(next(later_album, QueueScope::All, false).await, Some(other_artist));
+        assert_eq!(next(other_artist, QueueScope::All, false).await, None);
+        assert_eq!(next(other_artist, QueueScope::All, true).await, Some(first));
+        assert_eq!(next(other_artist, QueueScope::Album, true).await, Some(other_artist));
+        assert_eq!(next(9999, QueueScope::All, true).await, None);
+    }
+
+    #[tokio::test]
+    async fn get_filtered_library_plays_an_album_in_disc_and_track_order() {
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        for (file_name, disc, track) in [
+            ("zebra", Some(1), Some(1)),
+            ("cherry", None, None),
+            ("mango", Some(2), Some(1)),
+            ("apple", Some(1), Some(2)),
+            ("banana", None, None),
+        ] {
+            let id = sqlx::query(
+                "insert into filesystem_artifacts (relative_path, file_name, file_extension,
+                    is_present, first_path_segment, second_path_segment, created_at)
+                values (?, ?, 'mp3', 1, 'Artist', 'Album', 0)",
+            )
+            .bind(format!("Artist/Album/{}.mp3", file_name))
+            .bind(file_name)
+            .execute(&db)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+            sqlx::query(
+                "insert into track_metadata (filesystem_artifact_id, disc_number, track_number)
+                values (?, ?, ?)",
+            )
+            .bind(id)
+            .bind(disc)
+            .bind(track)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        let mut conn = db.acquire().await.unwrap();
+        let filter = SongFilter::default();
+        let (sort, order) = (SongSort::default(), SortOrder::Asc);
+        let rows = get_filtered_library(&mut conn, -1, 0, sort, order, &filter).await.unwrap();
+        let names = rows.iter().map(|row| row.track_name.as_str()).collect::<Vec<_>>();
+        // Numbered tracks by disc and number, then the rest by file name
+        assert_eq!(names, ["zebra", "apple", "mango", "banana", "cherry"]);
+    }
 }
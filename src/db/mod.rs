@@ -1,3 +1,29 @@
+mod albums;
+mod covers;
+mod duplicates;
+mod export;
+mod favorites;
+mod genres;
 mod library;
+mod peaks;
+mod plays;
+mod scan_errors;
+mod stats;
+mod tag_conflicts;
 
-pub use library::get_library;
+pub use albums::{get_album_tracks, get_albums, get_artists};
+pub use covers::{find_cover_path, save_cover_path};
+pub use duplicates::get_duplicates;
+pub use export::{export_library, import_library, validate_import};
+pub use favorites::{add_favorite, get_favorites, remove_favorite};
+pub use genres::{get_genre_songs, get_genres};
+pub use library::{
+    count_filtered_library, count_library, find_library_row, find_library_rows, find_song,
+    find_track_metadata, fuzzy_search_library, get_filtered_library, get_library,
+    get_recently_added, next_song_id, random_song_ids, search_library,
+};
+pub use peaks::{find_peaks, save_peaks};
+pub use plays::{get_recent_plays, get_top_tracks, record_play};
+pub use scan_errors::get_scan_errors;
+pub use stats::get_library_stats;
+pub use tag_conflicts::get_tag_conflicts;
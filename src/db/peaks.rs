@@ -0,0 +1,30 @@
+use sqlx::{pool::PoolConnection, Sqlite};
+
+pub async fn find_peaks(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    Ok(sqlx::query!(
+        "select peaks from track_metadata where filesystem_artifact_id = ?",
+        song_id
+    )
+    .fetch_optional(conn.as_mut())
+    .await?
+    .and_then(|r| r.peaks))
+}
+
+/// Does nothing for songs without a metadata row yet; the peaks are computed again next time
+pub async fn save_peaks(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+    peaks: &[u8],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "update track_metadata set peaks = ? where filesystem_artifact_id = ?",
+        peaks,
+        song_id
+    )
+    .execute(conn.as_mut())
+    .await?;
+    Ok(())
+}
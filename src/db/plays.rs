@@ -0,0 +1,79 @@
+use crate::db::find_library_rows;
+use crate::types::{PlayEvent, TopTrack};
+use sqlx::{pool::PoolConnection, Sqlite};
+
+pub async fn record_play(
+    conn: &mut PoolConnection<Sqlite>,
+    song_id: i64,
+    played_at: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "insert into play_events (filesystem_artifact_id, played_at) values (?, ?)",
+        song_id,
+        played_at
+    )
+    .execute(conn.as_mut())
+    .await?;
+    Ok(())
+}
+
+/// Most recent plays first
+pub async fn get_recent_plays(
+    conn: &mut PoolConnection<Sqlite>,
+    limit: i64,
+) -> Result<Vec<PlayEvent>, sqlx::Error> {
+    let plays = sqlx::query!(
+        "
+        select filesystem_artifact_id, played_at
+        from play_events
+        order by played_at desc, id desc
+        limit ?",
+        limit
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+    let ids = plays.iter().map(|r| r.filesystem_artifact_id).collect::<Vec<_>>();
+    let library = find_library_rows(conn, &ids).await?;
+    Ok(plays
+        .into_iter()
+        .filter_map(|r| {
+            Some(PlayEvent {
+                played_at: r.played_at,
+                song: library.get(&r.filesystem_artifact_id)?.clone(),
+            })
+        })
+        .collect())
+}
+
+/// Most played songs first, ties going to the more recently played
+pub async fn get_top_tracks(
+    conn: &mut PoolConnection<Sqlite>,
+    limit: i64,
+) -> Result<Vec<TopTrack>, sqlx::Error> {
+    let counts = sqlx::query!(
+        r#"
+        select
+            filesystem_artifact_id as "filesystem_artifact_id!: i64",
+            count(*) as "play_count!: i64",
+            max(played_at) as "last_played_at!: i64"
+        from play_events
+        group by filesystem_artifact_id
+        order by count(*) desc, max(played_at) desc
+        limit ?"#,
+        limit
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+    let ids = counts.iter().map(|r| r.filesystem_artifact_id).collect::<Vec<_>>();
+    let mut library = find_library_rows(conn, &ids).await?;
+    Ok(counts
+        .into_iter()
+        .filter_map(|r| {
+            Some(TopTrack {
+                play_count: r.play_count,
+                last_played_at: r.last_played_at,
+                song: library.remove(&r.filesystem_artifact_id)?,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,36 @@
+use crate::types::{ScanErrorRow, ScanStatus};
+use sqlx::{pool::PoolConnection, Sqlite};
+
+/// Present files whose last scan ended with `status`, by path
+pub async fn get_scan_errors(
+    conn: &mut PoolConnection<Sqlite>,
+    status: ScanStatus,
+) -> Result<Vec<ScanErrorRow>, sqlx::Error> {
+    let status = status.as_str();
+    Ok(sqlx::query!(
+        "
+        select
+            f.id,
+            f.relative_path,
+            f.cue_track,
+            t.scan_status,
+            t.scan_error
+        from filesystem_artifacts f
+        join track_metadata t
+            on t.filesystem_artifact_id = f.id
+        where f.is_present != 0 and t.scan_status = ?
+        order by f.relative_path, f.cue_track",
+        status
+    )
+    .fetch_all(conn.as_mut())
+    .await?
+    .into_iter()
+    .map(|r| ScanErrorRow {
+        song_id: r.id,
+        path: r.relative_path,
+        cue_track: r.cue_track.map(|t| t as u16),
+        status: r.scan_status,
+        error: r.scan_error,
+    })
+    .collect())
+}
@@ -0,0 +1,109 @@
+use crate::file_utils::pretty_duration;
+use crate::types::LibraryStats;
+use sqlx::{pool::PoolConnection, Sqlite};
+
+/// Totals over the songs present on disk, grouping artists and albums as `/api/albums` does
+pub async fn get_library_stats(
+    conn: &mut PoolConnection<Sqlite>,
+) -> Result<LibraryStats, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+        with songs as (
+            select
+                lower(coalesce(t.artist, f.first_path_segment, 'Unknown')) as artist,
+                lower(coalesce(t.album, f.second_path_segment, 'Unknown')) as album,
+                t.duration
+            from filesystem_artifacts f
+            left join track_metadata t
+                on t.filesystem_artifact_id = f.id
+            where f.is_present = TRUE
+        )
+        select
+            (select count(*) from songs) as "songs!: i64",
+            (select count(distinct artist) from songs) as "artists!: i64",
+            (select count(*) from (select distinct artist, album from songs)) as "albums!: i64",
+            (select ifnull(sum(duration), 0) from songs) as "duration!: i64",
+            -- Tracks of a cue sheet share their file, which only counts once
+            (
+                select ifnull(sum(file_size), 0) from (
+                    select distinct relative_path, file_size
+                    from filesystem_artifacts
+                    where is_present = TRUE
+                )
+            ) as "bytes!: i64"
+        "#
+    )
+    .fetch_one(conn.as_mut())
+    .await?;
+    Ok(LibraryStats {
+        songs: r.songs,
+        artists: r.artists,
+        albums: r.albums,
+        duration: r.duration,
+        duration_pretty: pretty_duration(r.duration),
+        bytes: r.bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_utils::test_db;
+
+    #[tokio::test]
+    async fn get_library_stats_totals_the_present_songs() {
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let songs = [
+            ("A/X/1.mp3", None, true, 1000, Some(("A", "X", 100))),
+            // The same artist and album spelled in another case
+            ("A/X/2.mp3", None, true, 2000, Some(("a", "x", 200))),
+            // Untagged, so named after its path
+            ("B/Y/1.mp3", None, true, 500, None),
+            // Two tracks of one file
+            ("C/Z/album.flac", Some(1), true, 10000, Some(("C", "Z", 300))),
+            ("C/Z/album.flac", Some(2), true, 10000, Some(("C", "Z", 400))),
+            ("D/W/1.mp3", None, false, 99999, Some(("D", "W", 9999))),
+        ];
+        for (path, cue_track, is_present, size, tags) in songs {
+            let (artist, album) = path.split_once('/').unwrap();
+            let album = album.split_once('/').unwrap().0;
+            let id = sqlx::query(
+                "insert into filesystem_artifacts (relative_path, file_name, file_extension,
+                    is_present, first_path_segment, second_path_segment, created_at,
+                    file_size, cue_track)
+                values (?, '1', 'mp3', ?, ?, ?, 0, ?, ?)",
+            )
+            .bind(path)
+            .bind(is_present)
+            .bind(artist)
+            .bind(album)
+            .bind(size)
+            .bind(cue_track)
+            .execute(&db)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+            if let Some((artist, album, duration)) = tags {
+                sqlx::query(
+                    "insert into track_metadata (filesystem_artifact_id, artist, album, duration)
+                    values (?, ?, ?, ?)",
+                )
+                .bind(id)
+                .bind(artist)
+                .bind(album)
+                .bind(duration)
+                .execute(&db)
+                .await
+                .unwrap();
+            }
+        }
+
+        let mut conn = db.acquire().await.unwrap();
+        let stats = get_library_stats(&mut conn).await.unwrap();
+        assert_eq!((stats.songs, stats.artists, stats.albums), (5, 3, 3));
+        assert_eq!(stats.duration, 1000);
+        assert_eq!(stats.duration_pretty, "16:40");
+        assert_eq!(stats.bytes, 1000 + 2000 + 500 + 10000);
+    }
+}
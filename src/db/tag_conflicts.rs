@@ -0,0 +1,116 @@
+use crate::types::TagConflict;
+use sqlx::{pool::PoolConnection, Sqlite};
+
+/// Artist/album directories whose present songs were tagged with more than one artist or
+/// album. Untagged values and songs whose path was too shallow to name a directory are ignored
+pub async fn get_tag_conflicts(
+    conn: &mut PoolConnection<Sqlite>,
+) -> Result<Vec<TagConflict>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        select
+            f.first_path_segment as "artist_dir!",
+            f.second_path_segment as "album_dir!",
+            t.artist,
+            t.album,
+            count(*) as "songs!: i64"
+        from filesystem_artifacts f
+        join track_metadata t
+            on t.filesystem_artifact_id = f.id
+        where
+            f.is_present = TRUE
+            and f.path_inferred = FALSE
+            and f.first_path_segment is not null
+            and f.second_path_segment is not null
+        group by f.first_path_segment, f.second_path_segment, t.artist, t.album
+        order by f.first_path_segment, f.second_path_segment"#
+    )
+    .fetch_all(conn.as_mut())
+    .await?;
+
+    let mut groups: Vec<TagConflict> = Vec::new();
+    for r in rows {
+        let group = match groups.last_mut() {
+            Some(last) if last.artist_dir == r.artist_dir && last.album_dir == r.album_dir => last,
+            _ => {
+                groups.push(TagConflict {
+                    artist_dir: r.artist_dir,
+                    album_dir: r.album_dir,
+                    song_count: 0,
+                    artists: Vec::new(),
+                    albums: Vec::new(),
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+        group.song_count += r.songs;
+        for (values, value) in [(&mut group.artists, r.artist), (&mut group.albums, r.album)] {
+            if let Some(value) = value.filter(|v| !values.contains(v)) {
+                values.push(value);
+            }
+        }
+    }
+    groups.retain(|g| g.artists.len() > 1 || g.albums.len() > 1);
+    for group in groups.iter_mut() {
+        group.artists.sort();
+        group.albums.sort();
+    }
+
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_utils::test_db;
+
+    #[tokio::test]
+    async fn get_tag_conflicts_finds_the_one_track_tagged_differently() {
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        for (path, artist, album) in [
+            ("The Beatles/Abbey Road/1.mp3", "The Beatles", "Abbey Road"),
+            ("The Beatles/Abbey Road/2.mp3", "Beatles", "Abbey Road"),
+            ("The Beatles/Abbey Road/3.mp3", "The Beatles", "Abbey Road"),
+            ("Other/Fine/1.mp3", "Other", "Fine"),
+            ("Other/Fine/2.mp3", "Other", "Fine"),
+        ] {
+            let mut dirs = path.split('/');
+            let id = sqlx::query(
+                "insert into filesystem_artifacts (relative_path, file_name, file_extension,
+                    is_present, first_path_segment, second_path_segment, path_inferred,
+                    created_at)
+                values (?, '1', 'mp3', 1, ?, ?, 0, 0)",
+            )
+            .bind(path)
+            .bind(dirs.next())
+            .bind(dirs.next())
+            .execute(&db)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+            sqlx::query(
+                "insert into track_metadata (filesystem_artifact_id, artist, album)
+                values (?, ?, ?)",
+            )
+            .bind(id)
+            .bind(artist)
+            .bind(album)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        let mut conn = db.acquire().await.unwrap();
+        let conflicts = get_tag_conflicts(&mut conn).await.unwrap();
+        assert_eq!(conflicts.len(), 1);
+        let conflict = &conflicts[0];
+        assert_eq!(
+            (conflict.artist_dir.as_str(), conflict.album_dir.as_str()),
+            ("The Beatles", "Abbey Road")
+        );
+        assert_eq!(conflict.song_count, 3);
+        assert_eq!(conflict.artists, ["Beatles", "The Beatles"]);
+        assert_eq!(conflict.albums, ["Abbey Road"]);
+    }
+}
@@ -1,34 +1,50 @@
 use std::io;
 
+use serde_json::json;
+
 #[derive(Debug)]
 pub enum GenError {
     Other(String),
     Database(sqlx::Error),
+    NotFound(String),
+    BadRequest(String),
+    Forbidden(String),
+    NotImplemented(String),
+    BadGateway(String),
 }
 
 impl actix_web::error::ResponseError for GenError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match *self {
-            Self::Other(_) => actix_web::http::StatusCode::SEE_OTHER,
+            Self::Other(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             Self::Database(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            Self::BadRequest(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            Self::Forbidden(_) => actix_web::http::StatusCode::FORBIDDEN,
+            Self::NotImplemented(_) => actix_web::http::StatusCode::NOT_IMPLEMENTED,
+            Self::BadGateway(_) => actix_web::http::StatusCode::BAD_GATEWAY,
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        match self {
-            GenError::Other(_) => {
-                actix_web::HttpResponse::build(actix_web::http::StatusCode::SEE_OTHER)
-                    .insert_header((
-                        actix_web::http::header::LOCATION,
-                        actix_web::http::header::HeaderValue::from_static("error"),
-                    ))
-                    .finish()
-            }
+        let message = match self {
+            GenError::Other(e) => e.clone(),
+            // The query and schema details are only for the log
             GenError::Database(e) => {
-                actix_web::HttpResponse::build(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(format!("<div><h1>Error</h1><p>{}</p></div>", e))
+                log::error!("Database error: {}", e);
+                "internal error".to_string()
             }
-        }
+            GenError::NotFound(e) => e.clone(),
+            GenError::BadRequest(e) => e.clone(),
+            GenError::Forbidden(e) => e.clone(),
+            GenError::NotImplemented(e) => e.clone(),
+            GenError::BadGateway(e) => e.clone(),
+        };
+        let status = self.status_code();
+        actix_web::HttpResponse::build(status).json(json!({
+            "error": message,
+            "code": status.as_u16(),
+        }))
     }
 }
 
@@ -37,6 +53,11 @@ impl std::fmt::Display for GenError {
         match self {
             GenError::Other(e) => write!(f, "other error: {e}"),
             GenError::Database(e) => write!(f, "database error: {e}"),
+            GenError::NotFound(e) => write!(f, "not found: {e}"),
+            GenError::BadRequest(e) => write!(f, "bad request: {e}"),
+            GenError::Forbidden(e) => write!(f, "forbidden: {e}"),
+            GenError::NotImplemented(e) => write!(f, "not implemented: {e}"),
+            GenError::BadGateway(e) => write!(f, "bad gateway: {e}"),
         }
     }
 }
@@ -55,6 +76,65 @@ impl From<sqlx::Error> for GenError {
 
 impl From<io::Error> for GenError {
     fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(format!("{}", value)),
+            _ => Self::Other(format!("{}", value)),
+        }
+    }
+}
+
+impl From<anyhow::Error> for GenError {
+    fn from(value: anyhow::Error) -> Self {
         Self::Other(format!("{}", value))
     }
 }
+
+impl From<actix_web::error::QueryPayloadError> for GenError {
+    fn from(value: actix_web::error::QueryPayloadError) -> Self {
+        Self::BadRequest(format!("{}", value))
+    }
+}
+
+impl From<actix_web::error::JsonPayloadError> for GenError {
+    fn from(value: actix_web::error::JsonPayloadError) -> Self {
+        Self::BadRequest(format!("{}", value))
+    }
+}
+
+impl From<actix_web::error::PathError> for GenError {
+    fn from(value: actix_web::error::PathError) -> Self {
+        // An unparseable id cannot name anything that exists
+        Self::NotFound(format!("{}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::MessageBody, ResponseError};
+
+    fn body_of(err: GenError) -> (u16, serde_json::Value) {
+        let resp = err.error_response();
+        let status = resp.status().as_u16();
+        let body = resp.into_body().try_into_bytes().unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn not_found_and_bad_request_keep_their_message() {
+        assert_eq!(
+            body_of(GenError::NotFound("song 4 not found".into())),
+            (404, json!({ "error": "song 4 not found", "code": 404 }))
+        );
+        assert_eq!(
+            body_of(GenError::BadRequest("q must not be empty".into())),
+            (400, json!({ "error": "q must not be empty", "code": 400 }))
+        );
+    }
+
+    #[test]
+    fn database_errors_are_not_shown_to_the_client() {
+        let err = GenError::Database(sqlx::Error::Protocol("no such table: secrets".into()));
+        assert_eq!(body_of(err), (500, json!({ "error": "internal error", "code": 500 })));
+    }
+}
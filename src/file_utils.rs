@@ -1,41 +1,241 @@
-use sqlx::pool::PoolConnection;
-use sqlx::{Pool, Sqlite};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use regex::Regex;
+use sqlx::{Connection, Pool, Sqlite, SqliteConnection};
+use tokio::sync::Semaphore;
 
-use crate::types::{PartialSong, Song, TrackMetadata};
+use crate::errors::GenError;
+use crate::ogg;
+use crate::transcode::TranscodeProfile;
+use crate::types::{
+    CoverArt, CueTrack, PartialSong, ScanStatus, ScanSummary, Song, TrackMetadata,
+};
+use std::collections::HashSet;
 use std::fs;
 use std::io::Result;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+#[derive(Clone)]
 pub struct Settings {
     pub allowed_extensions: Vec<String>,
+    /// Stand-ins for files too shallow in the tree to name their artist or album
+    pub unknown_artist: String,
+    pub unknown_album: String,
+    /// Groups songs without a genre tag when browsing by genre
+    pub unknown_genre: String,
+    /// Crawl into symlinked directories, wherever they point
+    pub follow_symlinks: bool,
+    /// Directory names never crawled, wherever they are in the tree
+    pub ignored_dirs: Vec<String>,
+    /// Crawl dotfiles and dot directories too, which are skipped by default
+    pub include_hidden: bool,
+    /// Songs saved per transaction while scanning
+    pub scan_batch_size: usize,
+    /// Images used as the cover of songs without embedded art, in order of preference
+    pub cover_names: Vec<String>,
+    pub path_layout: PathLayout,
+    pub loose_file_policy: LooseFilePolicy,
+    /// Splits a file name into a title and track number for files without a title tag,
+    /// `None` to use the file name as it is
+    pub title_pattern: Option<Regex>,
+    /// Audio files smaller than this many bytes are not crawled
+    pub min_file_size: u64,
+    /// Report only the library directory's name, never its absolute path
+    pub hide_paths: bool,
+    /// Bytes read from disk at a time when streaming a song
+    pub stream_chunk_size: usize,
+    /// Which file types are transcoded when streamed without a requested format
+    pub transcode_profile: TranscodeProfile,
+    /// Song streams the client reads nothing of for this long are closed, `None` to wait
+    /// on them forever
+    pub stream_idle_timeout: Option<Duration>,
 }
 
-fn parse_path(allowed_extensions: &[String], rel_path: &Path) -> Option<PartialSong> {
+/// `03 - Title`, `3. Title` or `03 Title`. A number alone, e.g. `1979`, is left as the title
+pub const DEFAULT_TITLE_PATTERN: &str = r"^(?P<track>\d{1,3})(?:\s*[-.]\s*|\s+)(?P<title>.+)$";
+
+/// A title pattern from config, which needs a `title` group and may have a `track` group.
+/// An empty pattern turns deriving titles off
+pub fn parse_title_pattern(pattern: &str) -> std::result::Result<Option<Regex>, String> {
+    if pattern.trim().is_empty() {
+        return Ok(None);
+    }
+    let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+    if !regex.capture_names().any(|name| name == Some("title")) {
+        return Err("it needs a (?P<title>...) group".into());
+    }
+    Ok(Some(regex))
+}
+
+/// Title and track number from a file name, for files without a title tag
+fn title_from_file_name(settings: &Settings, file_name: &str) -> (String, Option<u16>) {
+    let captures = settings
+        .title_pattern
+        .as_ref()
+        .and_then(|pattern| pattern.captures(file_name));
+    let title = captures
+        .as_ref()
+        .and_then(|c| c.name("title"))
+        .map(|t| t.as_str().trim())
+        .filter(|t| !t.is_empty());
+    match (&captures, title) {
+        (Some(captures), Some(title)) => (
+            title.to_string(),
+            captures.name("track").and_then(|t| t.as_str().parse().ok()),
+        ),
+        _ => (file_name.to_string(), None),
+    }
+}
+
+/// Which directories under the library root name a song's artist and album. Songs not deep
+/// enough to have both, or every song with the flat layout, get the unknown placeholders
+#[derive(Clone)]
+pub struct PathLayout {
+    /// Indexes into the song's directories, `None` for a flat library
+    pub artist_and_album: Option<(usize, usize)>,
+}
+
+impl Default for PathLayout {
+    /// `Artist/Album/...`
+    fn default() -> Self {
+        Self {
+            artist_and_album: Some((0, 1)),
+        }
+    }
+}
+
+impl FromStr for PathLayout {
+    type Err = String;
+
+    /// Directory names separated by `/`, of which `artist` and `album` must each appear once,
+    /// e.g. `genre/artist/album`. Other names only hold a place. `flat` ignores directories
+    fn from_str(layout: &str) -> std::result::Result<Self, Self::Err> {
+        let layout = layout.trim().trim_matches('/');
+        if layout.eq_ignore_ascii_case("flat") {
+            return Ok(Self {
+                artist_and_album: None,
+            });
+        }
+        let segments = layout.split('/').map(str::trim).collect::<Vec<_>>();
+        let find = |name: &str| {
+            let mut found = segments
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.eq_ignore_ascii_case(name))
+                .map(|(i, _)| i);
+            match (found.next(), found.next()) {
+                (Some(index), None) => Ok(index),
+                (None, _) => Err(format!("'{}' has no {} directory", layout, name)),
+                (Some(_), Some(_)) => Err(format!("'{}' names {} more than once", layout, name)),
+            }
+        };
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err(format!("'{}' has an empty directory name", layout));
+        }
+        Ok(Self {
+            artist_and_album: Some((find("artist")?, find("album")?)),
+        })
+    }
+}
+
+/// What becomes of loose files, which the path layout can't take an artist and album from,
+/// e.g. ones directly under the library root
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum LooseFilePolicy {
+    /// Grouped together under the unknown artist and album, beside whatever they're tagged
+    Bucket,
+    /// Left out of the library
+    Skip,
+    /// Grouped into albums by their tagged artist, the placeholders only standing in for
+    /// missing tags
+    #[default]
+    UseTags,
+}
+
+impl FromStr for LooseFilePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> std::result::Result<Self, Self::Err> {
+        match policy.trim().to_lowercase().as_str() {
+            "bucket" => Ok(Self::Bucket),
+            "skip" => Ok(Self::Skip),
+            "use_tags" => Ok(Self::UseTags),
+            other => Err(format!("'{}' is not one of bucket, skip or use_tags", other)),
+        }
+    }
+}
+
+/// Whether a file or directory named `name` stays out of the library. Dotfiles are macOS
+/// `._` resource forks and the like far more often than songs
+fn is_ignored(settings: &Settings, name: &std::ffi::OsStr, is_dir: bool) -> bool {
+    let name = name.to_string_lossy();
+    (is_dir && settings.ignored_dirs.iter().any(|d| *d == name))
+        || (!settings.include_hidden && name.starts_with('.'))
+}
+
+fn parse_path(settings: &Settings, rel_path: &Path) -> Option<PartialSong> {
+    // Checked for the whole path, since the watcher hands over files without crawling to them
+    let mut components = rel_path.components().peekable();
+    while let Some(component) = components.next() {
+        let is_dir = components.peek().is_some();
+        if is_ignored(settings, component.as_os_str(), is_dir) {
+            return None;
+        }
+    }
     let ext = rel_path.extension();
     if let Some(extension) = ext {
-        let parsed_extension = String::from(extension.to_str().unwrap());
-        if allowed_extensions
+        let ext = extension.to_string_lossy().into_owned();
+        if settings
+            .allowed_extensions
             .iter()
-            .any(|f| *f == *parsed_extension)
+            .any(|f| f.eq_ignore_ascii_case(&ext))
         {
-            let filepath = String::from(rel_path.to_str().unwrap());
-            let segments = filepath.split("/").collect::<Vec<_>>();
-            let (artist, album) = if segments.len() == 3 {
-                (segments[0], segments[1])
-            } else {
-                ("Unknown", "Unknown")
-            };
-            let filename_with_ext = String::from(rel_path.file_name().unwrap().to_str().unwrap());
-            let ext = String::from(extension.to_str().unwrap());
-            let filename = filename_with_ext.replace(&format!(".{}", ext), "");
+            // Artist/Album[/Disc N/...]/track by default, the layout says which directories
+            let dirs = rel_path
+                .parent()
+                .map(|p| {
+                    p.components()
+                        .filter_map(|c| match c {
+                            Component::Normal(name) => Some(name.to_string_lossy()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            let from_path = settings.path_layout.artist_and_album.and_then(|(artist, album)| {
+                Some((dirs.get(artist)?.as_ref(), dirs.get(album)?.as_ref()))
+            });
+            let path_inferred = from_path.is_none();
+            if path_inferred && settings.loose_file_policy == LooseFilePolicy::Skip {
+                return None;
+            }
+            let (artist, album) = from_path.unwrap_or((
+                settings.unknown_artist.as_str(),
+                settings.unknown_album.as_str(),
+            ));
+            let filename_with_ext = rel_path.file_name()?.to_string_lossy().into_owned();
+            let file_name = filename_with_ext.replace(&format!(".{}", ext), "");
+            let relative_path = lossy_relative_path(rel_path);
+            // Names in legacy encodings are listed lossily, but the file is still opened by
+            // the path it really has
+            let raw_path = rel_path.to_str().is_none().then(|| {
+                log::warn!(
+                    "{:?} is not valid UTF-8, listing it as {}",
+                    rel_path,
+                    relative_path
+                );
+                rel_path.to_path_buf()
+            });
             let l = PartialSong {
-                filename,
-                filepath: filepath.clone(),
-                extension: String::from(extension.to_str().unwrap()),
+                file_name,
+                file_extension: ext,
                 artist: String::from(artist),
                 album: String::from(album),
-                full_path: rel_path.to_str().unwrap().to_string(),
+                relative_path,
+                raw_path,
+                path_inferred,
+                cue: None,
             };
             return Some(l);
         }
@@ -43,135 +243,1347 @@ fn parse_path(allowed_extensions: &[String], rel_path: &Path) -> Option<PartialS
     None
 }
 
-pub fn crawl_dir(
-    allowed_extensions: &Vec<String>,
+/// Directories read at once while crawling, which bounds the file descriptors a crawl holds
+const CRAWL_CONCURRENCY: usize = 32;
+
+/// One directory's songs, and the directories found in it
+struct DirListing {
+    dir: PathBuf,
+    canonical: PathBuf,
+    songs: Vec<PartialSong>,
+    sub_dirs: Vec<PathBuf>,
+    links: Vec<PathBuf>,
+}
+
+/// Songs in `dir` and every directory under it. Directories are worked off a queue rather
+/// than by recursion, so however deep the tree goes the stack stays the same size
+pub async fn crawl_dir(
+    settings: &Settings,
     base_path: &Path,
     dir: &Path,
 ) -> Result<Vec<PartialSong>> {
-    let mut entries: Vec<PartialSong> = Vec::new();
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            print!(".");
-            let entry = entry?;
-            let full_path = entry.path();
-            if full_path.is_dir() {
-                let sub_entries = crawl_dir(allowed_extensions, base_path, &full_path)?;
-                for sentry in sub_entries {
-                    entries.push(sentry);
-                }
+    let semaphore = Semaphore::new(CRAWL_CONCURRENCY);
+    let mut visited = HashSet::new();
+    let mut links = Vec::new();
+    let mut entries = crawl(settings, base_path, dir, &semaphore, &mut visited, &mut links).await?;
+    // Links are only followed once every real directory has been crawled, one at a time and in
+    // order, so a directory reachable several ways is always found under the same path
+    while !links.is_empty() {
+        let mut pending = std::mem::take(&mut links);
+        pending.sort();
+        for link in pending {
+            let found = crawl(settings, base_path, &link, &semaphore, &mut visited, &mut links);
+            entries.extend(found.await?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Crawls `dir` and the real directories under it, reading up to [`CRAWL_CONCURRENCY`] at a
+/// time. `visited` holds canonical directory paths, so a symlink cycle or two links to the
+/// same directory are only crawled once. Symlinked directories are queued on `links` rather
+/// than crawled, when followed at all
+async fn crawl(
+    settings: &Settings,
+    base_path: &Path,
+    dir: &Path,
+    semaphore: &Semaphore,
+    visited: &mut HashSet<PathBuf>,
+    links: &mut Vec<PathBuf>,
+) -> Result<Vec<PartialSong>> {
+    let mut entries = Vec::new();
+    let mut reading = FuturesUnordered::new();
+    reading.push(read_listing(settings, base_path, dir.to_path_buf(), None, semaphore));
+    while let Some(listing) = reading.next().await {
+        let Some(listing) = listing else {
+            continue;
+        };
+        if !visited.insert(listing.canonical.clone()) {
+            log::debug!("Skipping {}, already crawled", listing.dir.display());
+            continue;
+        }
+        entries.extend(listing.songs);
+        links.extend(listing.links);
+        for sub_dir in listing.sub_dirs {
+            // A real directory in a canonical one is canonical under its own name
+            let canonical = sub_dir.file_name().map(|name| listing.canonical.join(name));
+            reading.push(read_listing(settings, base_path, sub_dir, canonical, semaphore));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Lists a single directory, `None` when it is not a readable directory. `canonical` is
+/// worked out when not given, which takes a lookup per directory above it, so deep trees
+/// pass it down instead
+async fn read_listing(
+    settings: &Settings,
+    base_path: &Path,
+    dir: PathBuf,
+    canonical: Option<PathBuf>,
+    semaphore: &Semaphore,
+) -> Option<DirListing> {
+    let _permit = semaphore.acquire().await.ok()?;
+    if !tokio::fs::metadata(&dir).await.is_ok_and(|m| m.is_dir()) {
+        return None;
+    }
+    let canonical = match canonical {
+        Some(canonical) => canonical,
+        None => tokio::fs::canonicalize(&dir)
+            .await
+            .unwrap_or_else(|_| dir.clone()),
+    };
+
+    let mut sub_dirs: Vec<PathBuf> = Vec::new();
+    let mut links: Vec<PathBuf> = Vec::new();
+    let mut files: Vec<PathBuf> = Vec::new();
+    // An unreadable directory only loses its own subtree, not the whole scan
+    let mut read_dir = match tokio::fs::read_dir(&dir).await {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            log::warn!("Skipping unreadable directory {}: {}", dir.display(), e);
+            return None;
+        }
+    };
+    loop {
+        let entry = match read_dir.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("Skipping unreadable entry in {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        let full_path = entry.path();
+        let is_symlink = entry.file_type().await.is_ok_and(|t| t.is_symlink());
+        let is_dir = tokio::fs::metadata(&full_path)
+            .await
+            .is_ok_and(|m| m.is_dir());
+        if full_path
+            .file_name()
+            .is_some_and(|name| is_ignored(settings, name, is_dir))
+        {
+            log::debug!("Skipping ignored {}", full_path.display());
+            continue;
+        }
+        if is_dir {
+            if !is_symlink {
+                sub_dirs.push(full_path);
+            } else if settings.follow_symlinks {
+                links.push(full_path);
+            } else {
+                log::debug!("Skipping symlinked directory {}", full_path.display());
+            }
+        } else {
+            files.push(full_path);
+        }
+    }
+    drop(read_dir);
+
+    let songs = dir_songs(settings, base_path, &dir, &files).await;
+    log::debug!(
+        "Crawled {}: {} songs, {} subdirectories",
+        dir.display(),
+        songs.len(),
+        sub_dirs.len()
+    );
+    Some(DirListing {
+        dir,
+        canonical,
+        songs,
+        sub_dirs,
+        links,
+    })
+}
+
+/// Songs for `files`, which all sit directly in `dir`. A file described by a CUE sheet in
+/// `dir` becomes one song per sheet track instead of a single song
+async fn dir_songs(
+    settings: &Settings,
+    base_path: &Path,
+    dir: &Path,
+    files: &[PathBuf],
+) -> Vec<PartialSong> {
+    let sheets = cue_sheets_in(dir, files).await;
+    let mut songs = Vec::new();
+    for full_path in files {
+        let rel_path = full_path
+            .strip_prefix(base_path)
+            .expect("Could not strip prefix of file");
+        let Some(song) = parse_path(settings, rel_path) else {
+            log::debug!("Ignoring {}", rel_path.display());
+            continue;
+        };
+        // Empty or cut-off downloads would otherwise be listed as unplayable songs
+        let size = tokio::fs::metadata(full_path).await.map_or(0, |m| m.len());
+        if size < settings.min_file_size {
+            log::info!("Skipping {}, only {} bytes", rel_path.display(), size);
+            continue;
+        }
+        match sheets.get(full_path) {
+            Some(tracks) => songs.extend(tracks.iter().map(|track| PartialSong {
+                cue: Some(track.clone()),
+                ..song.clone()
+            })),
+            None => songs.push(song),
+        }
+    }
+    songs
+}
+
+/// Reads every `.cue` file among `files` in `dir`, keyed by the absolute path of the audio
+/// file each sheet section describes
+async fn cue_sheets_in(
+    dir: &Path,
+    files: &[PathBuf],
+) -> std::collections::HashMap<PathBuf, Vec<CueTrack>> {
+    let mut sheets = std::collections::HashMap::new();
+    let cue_paths = files
+        .iter()
+        .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("cue")));
+    for cue_path in cue_paths {
+        let Ok(bytes) = tokio::fs::read(cue_path).await else {
+            log::warn!("Could not read cue sheet {}", cue_path.display());
+            continue;
+        };
+        for (file_name, tracks) in parse_cue_sheet(&String::from_utf8_lossy(&bytes)) {
+            // Rippers often name the pre-encoding file, e.g. `Album.wav` next to `Album.flac`
+            let named = dir.join(&file_name);
+            let audio_path = if tokio::fs::metadata(&named).await.is_ok_and(|m| m.is_file()) {
+                Some(named)
             } else {
-                let full_path = entry.path();
-                let rel_path = full_path
-                    .strip_prefix(base_path)
-                    .expect("Could not strip prefix of file");
-                let p = parse_path(allowed_extensions, rel_path);
-                if let Some(part_song) = p {
-                    entries.push(part_song);
+                let stem = Path::new(&file_name).file_stem();
+                files
+                    .iter()
+                    .find(|p| p.file_stem() == stem && p.as_path() != cue_path.as_path())
+                    .cloned()
+            };
+            match audio_path {
+                Some(audio_path) => {
+                    sheets.insert(audio_path, tracks);
                 }
+                None => log::warn!(
+                    "Cue sheet {} refers to missing file {}",
+                    cue_path.display(),
+                    file_name
+                ),
             }
         }
     }
+    sheets
+}
 
-    Ok(entries)
+/// Rebuilds the stored part of a [`CueTrack`] from its database columns
+pub fn cue_from_row(
+    track: Option<i64>,
+    start_ms: Option<i64>,
+    end_ms: Option<i64>,
+) -> Option<CueTrack> {
+    Some(CueTrack {
+        number: track? as u16,
+        start_ms: start_ms.unwrap_or(0),
+        end_ms,
+        ..Default::default()
+    })
+}
+
+/// Strips surrounding quotes from a CUE value, e.g. `"Album Title"`
+fn cue_value(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// Parses CUE sheet text into the audio files it names and their tracks. Tracks without
+/// an `INDEX 01` are dropped, and each track ends where the next one in its file starts
+pub fn parse_cue_sheet(sheet: &str) -> Vec<(String, Vec<CueTrack>)> {
+    let mut files: Vec<(String, Vec<CueTrack>)> = Vec::new();
+    let mut album: Option<String> = None;
+    let mut album_performer: Option<String> = None;
+    let mut track: Option<CueTrack> = None;
+    let mut track_started = false;
+
+    fn finish(files: &mut [(String, Vec<CueTrack>)], track: Option<CueTrack>, started: bool) {
+        if let (Some(track), true, Some(file)) = (track, started, files.last_mut()) {
+            file.1.push(track);
+        }
+    }
+
+    for line in sheet.lines() {
+        let line = line.trim();
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match keyword.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                finish(&mut files, track.take(), track_started);
+                // FILE "name with spaces.flac" WAVE
+                let rest = rest.trim();
+                let name = match rest.strip_prefix('"') {
+                    Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+                    None => rest.rsplit_once(' ').map_or(rest, |(name, _)| name),
+                };
+                files.push((name.to_string(), Vec::new()));
+            }
+            "TRACK" => {
+                finish(&mut files, track.take(), track_started);
+                track_started = false;
+                let number = rest.split_whitespace().next().and_then(|n| n.parse().ok());
+                let is_audio = rest.to_ascii_uppercase().contains("AUDIO");
+                track = number.filter(|_| is_audio).map(|number| CueTrack {
+                    number,
+                    album: album.clone(),
+                    performer: album_performer.clone(),
+                    ..Default::default()
+                });
+            }
+            "TITLE" => match track.as_mut() {
+                Some(track) => track.title = Some(cue_value(rest)),
+                None => album = Some(cue_value(rest)),
+            },
+            "PERFORMER" => match track.as_mut() {
+                Some(track) => track.performer = Some(cue_value(rest)),
+                None => album_performer = Some(cue_value(rest)),
+            },
+            "INDEX" => {
+                let mut parts = rest.split_whitespace();
+                let (Some("01"), Some(time), Some(track)) =
+                    (parts.next(), parts.next(), track.as_mut())
+                else {
+                    continue;
+                };
+                // mm:ss:ff with 75 frames per second
+                let parts = time
+                    .split(':')
+                    .map(|p| p.parse::<i64>().ok())
+                    .collect::<Option<Vec<_>>>();
+                if let Some([minutes, seconds, frames]) = parts.as_deref() {
+                    track.start_ms = (minutes * 60 + seconds) * 1000 + frames * 1000 / 75;
+                    track_started = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    finish(&mut files, track, track_started);
+
+    for (_, tracks) in files.iter_mut() {
+        let starts = tracks
+            .iter()
+            .skip(1)
+            .map(|t| t.start_ms)
+            .collect::<Vec<_>>();
+        for (track, next_start) in tracks.iter_mut().zip(starts) {
+            track.end_ms = Some(next_start);
+        }
+    }
+    files.retain(|(_, tracks)| !tracks.is_empty());
+    files
+}
+
+/// A path under the library root as stored in the database: its names joined with `/` on
+/// every platform, so Windows crawls match and make valid URLs. `None` if a name isn't UTF-8
+pub fn relative_path_string(rel_path: &Path) -> Option<String> {
+    let names = rel_path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_str()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(names.join("/"))
+}
+
+/// As [`relative_path_string`], but with whatever isn't UTF-8 replaced by U+FFFD
+pub fn lossy_relative_path(rel_path: &Path) -> String {
+    let names = rel_path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    names.join("/")
+}
+
+/// A `raw_path` as stored in the database, the filesystem's own bytes where it has them
+pub fn raw_path_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// Reads a `raw_path` written by [`raw_path_bytes`]
+pub fn raw_path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// Crawls the library and assigns each file a stable in-memory id by sorted position
+pub async fn load_library(settings: &Settings, base_path: &Path) -> Result<Vec<Song>> {
+    let mut songs = crawl_dir(settings, base_path, base_path).await?;
+    songs.sort_unstable_by_key(|a| {
+        (
+            a.artist.clone(),
+            a.album.clone(),
+            a.file_name.clone(),
+            a.cue.as_ref().map(|c| c.number),
+        )
+    });
+    Ok(songs
+        .into_iter()
+        .enumerate()
+        .map(|ps| ps.1.with_id(ps.0 as u64))
+        .collect())
+}
+
+/// Joins a stored relative path onto the library root, refusing paths that resolve outside it.
+/// With `follow_symlinks` links may lead anywhere, so only the stored path itself is checked
+pub fn resolve_in_library(
+    settings: &Settings,
+    base_path: &Path,
+    relative_path: &Path,
+) -> std::result::Result<PathBuf, GenError> {
+    let root = base_path.canonicalize()?;
+    let resolved = root.join(relative_path).canonicalize()?;
+    let escapes = if settings.follow_symlinks {
+        !relative_path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    } else {
+        !resolved.starts_with(&root)
+    };
+    if escapes {
+        return Err(GenError::Forbidden(format!(
+            "'{}' is outside the library",
+            relative_path.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// The `Content-Type` a file with this extension is served as. Some players refuse a
+/// generic type, so only extensions nothing here knows fall back to `application/octet-stream`
+pub fn audio_mime_type(extension: &str) -> &'static str {
+    match extension.to_ascii_lowercase().as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "m4a" | "mp4" => "audio/mp4",
+        "aac" => "audio/aac",
+        "aif" | "aiff" => "audio/aiff",
+        "wma" => "audio/x-ms-wma",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Replaces characters that are unsafe in a download filename or header value
+pub fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Splits a tag like `"A feat. B; C"` into a primary value and the rest
+pub fn split_multi_value(value: &str) -> (String, Vec<String>) {
+    let mut normalized = value.to_string();
+    // ASCII lowercasing keeps byte offsets aligned with the original string
+    while let Some(pos) = normalized.to_ascii_lowercase().find("feat.") {
+        normalized.replace_range(pos..pos + "feat.".len(), ";");
+    }
+    let mut values = normalized
+        // ID3v2.4 frames separate multiple values with NUL
+        .split([';', '/', '\0'])
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    let primary = values.next().unwrap_or_else(|| value.trim().to_string());
+    (primary, values.collect())
 }
 
 pub fn pretty_duration(duration: i64) -> String {
     format!("{}:{:02}", duration / 60, duration % 60)
 }
 
-fn unix_timestamp() -> i64 {
+pub fn read_cover(abs_path: &Path) -> Option<CoverArt> {
+    let tag = audiotags::Tag::new().read_from_path(abs_path).ok()?;
+    let cover = tag.album_cover()?;
+    Some(CoverArt {
+        mime_type: cover.mime_type.into(),
+        data: cover.data.to_vec().into(),
+    })
+}
+
+/// Unsynchronized lyrics embedded in the song's tags: ID3v2 `USLT` frames, `LYRICS` Vorbis
+/// comments, or the MP4 `©lyr` atom
+pub fn read_lyrics(abs_path: &Path, extension: &str) -> Option<String> {
+    use audiotags::{FlacTag, Id3v2Tag, Mp4Tag};
+
+    let lyrics = if OGG_EXTENSIONS.iter().any(|e| extension.eq_ignore_ascii_case(e)) {
+        let info = ogg::read_ogg(abs_path).ok()?;
+        info.first("LYRICS")
+            .or_else(|| info.first("UNSYNCEDLYRICS"))
+            .map(String::from)
+    } else {
+        let tag = audiotags::Tag::new().read_from_path(abs_path).ok()?;
+        let any = tag.to_any();
+        if any.is::<Id3v2Tag>() {
+            let inner: id3::Tag = Id3v2Tag::from(tag).into();
+            let lyrics = inner.lyrics().next().map(|l| l.text.clone());
+            lyrics
+        } else if any.is::<FlacTag>() {
+            let inner: metaflac::Tag = FlacTag::from(tag).into();
+            let vorbis = |key: &str| {
+                inner
+                    .get_vorbis(key)
+                    .and_then(|mut values| values.next())
+                    .map(String::from)
+            };
+            vorbis("LYRICS").or_else(|| vorbis("UNSYNCEDLYRICS"))
+        } else if any.is::<Mp4Tag>() {
+            let inner: mp4ameta::Tag = Mp4Tag::from(tag).into();
+            inner.lyrics().map(String::from)
+        } else {
+            None
+        }
+    };
+    lyrics.filter(|l| !l.trim().is_empty())
+}
+
+/// The `.lrc` file sharing the song's file name, matched case-insensitively
+pub fn read_sidecar_lyrics(abs_path: &Path) -> Option<String> {
+    let dir = abs_path.parent()?;
+    let stem = abs_path.file_stem()?.to_str()?;
+    let path = fs::read_dir(dir).ok()?.filter_map(|e| e.ok()).find_map(|e| {
+        let path = e.path();
+        let matches = path.file_stem().and_then(|s| s.to_str()) == Some(stem)
+            && path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| e.eq_ignore_ascii_case("lrc"));
+        matches.then_some(path)
+    })?;
+    let data = fs::read(path).ok()?;
+    let text = String::from_utf8_lossy(&data);
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+    (!text.trim().is_empty()).then(|| text.to_string())
+}
+
+/// The cover image next to the song at `abs_path`, with its path relative to the library root
+/// unless a followed symlink leads outside it. `stored` is tried before searching the
+/// directory for one of `cover_names`
+pub fn read_folder_cover(
+    settings: &Settings,
+    base_path: &Path,
+    abs_path: &Path,
+    stored: Option<&str>,
+) -> Option<(CoverArt, Option<String>)> {
+    let root = base_path.canonicalize().ok()?;
+    let stored = stored.and_then(|p| resolve_in_library(settings, base_path, Path::new(p)).ok());
+    let path = match stored {
+        Some(path) => path,
+        None => {
+            let dir = abs_path.parent()?;
+            let names = fs::read_dir(dir)
+                .ok()?
+                .filter_map(|e| e.ok().map(|e| e.file_name()))
+                .collect::<Vec<_>>();
+            // Matched case-insensitively, e.g. `Folder.JPG` as written by some rippers
+            let name = settings.cover_names.iter().find_map(|wanted| {
+                names
+                    .iter()
+                    .find(|n| n.to_str().is_some_and(|n| n.eq_ignore_ascii_case(wanted)))
+            })?;
+            dir.join(name)
+        }
+    };
+    let data = fs::read(&path).ok()?;
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let mime_type = match extension.to_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        _ => "application/octet-stream",
+    };
+    let relative = path
+        .strip_prefix(&root)
+        .ok()
+        .and_then(relative_path_string);
+    Some((
+        CoverArt {
+            mime_type,
+            data: data.into(),
+        },
+        relative,
+    ))
+}
+
+fn file_mtime(abs_path: &Path) -> Option<i64> {
+    fs::metadata(abs_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+}
+
+/// Size in bytes, `None` when the file went away after being crawled
+fn file_size(abs_path: &Path) -> Option<i64> {
+    match fs::metadata(abs_path) {
+        Ok(meta) => Some(meta.len() as i64),
+        Err(e) => {
+            log::warn!("Could not read the size of {}: {}", abs_path.display(), e);
+            None
+        }
+    }
+}
+
+/// Only the start of the file is hashed, along with its size, to keep scans fast
+const CONTENT_HASH_PREFIX: u64 = 1024 * 1024;
+
+fn content_hash(abs_path: &Path) -> Option<String> {
+    use std::io::Read;
+
+    let file = fs::File::open(abs_path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&size.to_le_bytes());
+    let mut prefix = Vec::new();
+    file.take(CONTENT_HASH_PREFIX)
+        .read_to_end(&mut prefix)
+        .ok()?;
+    hasher.update(&prefix);
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+pub fn unix_timestamp() -> i64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs() as i64
 }
 
+/// Parses a ReplayGain value such as `-6.54 dB`
+fn parse_gain(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Fields only the format-specific tag or the stream itself can tell us
+#[derive(Default)]
+struct FormatDetails {
+    track_gain: Option<f64>,
+    album_gain: Option<f64>,
+    /// Average bitrate in kbps
+    bitrate: Option<u32>,
+    sample_rate: Option<u32>,
+    channels: Option<u8>,
+}
+
+/// Reads ReplayGain and stream properties from the format-specific tag, since `AnyTag`
+/// only exposes the common fields
+fn read_format_details(tag: Box<dyn audiotags::AudioTag>, abs_path: &Path) -> FormatDetails {
+    use audiotags::{FlacTag, Id3v2Tag, Mp4Tag};
+
+    let any = tag.to_any();
+    if any.is::<Id3v2Tag>() {
+        let inner: id3::Tag = Id3v2Tag::from(tag).into();
+        let gain = |key: &str| {
+            inner
+                .extended_texts()
+                .find(|t| t.description.eq_ignore_ascii_case(key))
+                .and_then(|t| parse_gain(&t.value))
+        };
+        FormatDetails {
+            track_gain: gain("REPLAYGAIN_TRACK_GAIN"),
+            album_gain: gain("REPLAYGAIN_ALBUM_GAIN"),
+            ..read_mpeg_details(abs_path)
+        }
+    } else if any.is::<FlacTag>() {
+        let inner: metaflac::Tag = FlacTag::from(tag).into();
+        let gain = |key: &str| {
+            inner
+                .get_vorbis(key)
+                .and_then(|mut values| values.next())
+                .and_then(parse_gain)
+        };
+        let info = inner.get_streaminfo();
+        // FLAC has no nominal bitrate, so average it over the whole file
+        let bitrate = info.and_then(|i| {
+            let seconds = i.total_samples.checked_div(i.sample_rate as u64)?;
+            let bytes = fs::metadata(abs_path).ok()?.len();
+            bytes.checked_div(seconds).map(|b| (b * 8 / 1000) as u32)
+        });
+        FormatDetails {
+            track_gain: gain("REPLAYGAIN_TRACK_GAIN"),
+            album_gain: gain("REPLAYGAIN_ALBUM_GAIN"),
+            bitrate,
+            sample_rate: info.map(|i| i.sample_rate),
+            channels: info.map(|i| i.num_channels),
+        }
+    } else if any.is::<Mp4Tag>() {
+        let inner: mp4ameta::Tag = Mp4Tag::from(tag).into();
+        let gain = |key: &str| {
+            let ident = mp4ameta::FreeformIdent::new("com.apple.iTunes", key);
+            let gain = inner.strings_of(&ident).next().and_then(parse_gain);
+            gain
+        };
+        FormatDetails {
+            track_gain: gain("replaygain_track_gain"),
+            album_gain: gain("replaygain_album_gain"),
+            bitrate: inner.avg_bitrate().map(|b| b / 1000),
+            sample_rate: inner.sample_rate().map(|r| r.hz()),
+            channels: inner.channel_config().map(|c| c.channel_count()),
+        }
+    } else {
+        FormatDetails::default()
+    }
+}
+
+/// Reads the first MPEG audio frame header after any ID3v2 tag. Only Layer III is
+/// understood, and VBR files report the first frame's bitrate
+fn read_mpeg_details(abs_path: &Path) -> FormatDetails {
+    use std::io::{Read, Seek, SeekFrom};
+
+    const BITRATES_V1: [u32; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    let Ok(mut file) = fs::File::open(abs_path) else {
+        return FormatDetails::default();
+    };
+    let mut header = [0u8; 10];
+    let mut start = 0;
+    if file.read_exact(&mut header).is_ok() && &header[..3] == b"ID3" {
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        start = 10 + size + footer;
+    }
+    let mut buf = Vec::new();
+    if file.seek(SeekFrom::Start(start)).is_err()
+        || file.take(64 * 1024).read_to_end(&mut buf).is_err()
+    {
+        return FormatDetails::default();
+    }
+
+    for w in buf.windows(4) {
+        if w[0] != 0xff || w[1] & 0xe0 != 0xe0 {
+            continue;
+        }
+        let version = (w[1] >> 3) & 0b11;
+        let layer = (w[1] >> 1) & 0b11;
+        let bitrate_index = (w[2] >> 4) as usize;
+        let rate_index = ((w[2] >> 2) & 0b11) as usize;
+        // 0b01 is a reserved version and layer 0b01 is Layer III
+        if version == 0b01
+            || layer != 0b01
+            || bitrate_index == 0
+            || bitrate_index == 15
+            || rate_index == 3
+        {
+            continue;
+        }
+        let (bitrates, divisor) = match version {
+            0b11 => (&BITRATES_V1, 1),
+            0b10 => (&BITRATES_V2, 2),
+            _ => (&BITRATES_V2, 4),
+        };
+        return FormatDetails {
+            bitrate: Some(bitrates[bitrate_index]),
+            sample_rate: Some(SAMPLE_RATES[rate_index] / divisor),
+            channels: Some(if w[3] >> 6 == 0b11 { 1 } else { 2 }),
+            ..Default::default()
+        };
+    }
+    FormatDetails::default()
+}
+
+/// Whether a tag reader ran out of file partway, e.g. through an interrupted copy
+fn is_truncated(e: &audiotags::Error) -> bool {
+    let io_error = match e {
+        audiotags::Error::ReadError { source } | audiotags::Error::IOError(source) => Some(source),
+        audiotags::Error::Id3TagError(e) => match &e.kind {
+            id3::ErrorKind::Io(e) => Some(e),
+            _ => None,
+        },
+        audiotags::Error::FlacTagError(e) => match &e.kind {
+            metaflac::ErrorKind::Io(e) => Some(e),
+            _ => None,
+        },
+        audiotags::Error::Mp4TagError(e) => match &e.kind {
+            mp4ameta::ErrorKind::Io(e) => Some(e),
+            _ => None,
+        },
+        _ => None,
+    };
+    io_error.is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+/// Whether a failure to read tags means the file has none, rather than that it is broken
+fn tag_error_status(e: &audiotags::Error) -> ScanStatus {
+    if is_truncated(e) {
+        return ScanStatus::Truncated;
+    }
+    match e {
+        audiotags::Error::Id3TagError(e) if matches!(e.kind, id3::ErrorKind::NoTag) => {
+            ScanStatus::NoTags
+        }
+        audiotags::Error::Mp4TagError(e) if matches!(e.kind, mp4ameta::ErrorKind::NoTag) => {
+            ScanStatus::NoTags
+        }
+        // Formats tags are not read from at all, e.g. wav
+        audiotags::Error::UnknownFileExtension(_) | audiotags::Error::UnsupportedFormat(_) => {
+            ScanStatus::NoTags
+        }
+        _ => ScanStatus::ReadError,
+    }
+}
+
+/// What a song's path and file name tell about it, for when its tags can't be read
+fn path_metadata(settings: &Settings, song: &Song, id: i64) -> TrackMetadata {
+    let (file_title, file_track) = title_from_file_name(settings, &song.file_name);
+    // Placeholders stay out of the tags so they can still be told apart
+    let from_path = |value: &String| (!song.path_inferred).then(|| value.clone());
+    TrackMetadata {
+        file_artifact_id: id,
+        title: Some(file_title),
+        track_number: file_track,
+        artist: from_path(&song.artist),
+        album: from_path(&song.album),
+        ..Default::default()
+    }
+}
+
+/// The leading number of a value like `3` or `3/12`
+fn parse_position(value: &str) -> Option<u16> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Tags and stream details of an Opus or Vorbis file, from its Vorbis comments
+fn read_ogg_metadata(settings: &Settings, abs_path: &Path, song: &Song, id: i64) -> TrackMetadata {
+    let info = match ogg::read_ogg(abs_path) {
+        Ok(info) => info,
+        Err(e) => {
+            let scan_status = match &e {
+                ogg::OggError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    ScanStatus::Truncated
+                }
+                ogg::OggError::UnsupportedCodec => ScanStatus::NoTags,
+                _ => ScanStatus::ReadError,
+            };
+            log::warn!("Could not read {}: {}", abs_path.display(), e);
+            return TrackMetadata {
+                scan_status,
+                scan_error: Some(e.to_string()),
+                ..path_metadata(settings, song, id)
+            };
+        }
+    };
+    let text = |key: &str| info.first(key).map(str::trim).filter(|v| !v.is_empty());
+    // Several artists may be listed as one comment each, or as one separated comment
+    let (artist, additional_artists) = {
+        let mut artists = info.values("ARTIST").map(split_multi_value);
+        match artists.next() {
+            Some((artist, mut additional)) => {
+                for (next, rest) in artists {
+                    additional.push(next);
+                    additional.extend(rest);
+                }
+                (Some(artist), additional)
+            }
+            None => (None, Vec::new()),
+        }
+    };
+    let (genre, additional_genres) = text("GENRE").map(split_multi_value).unzip();
+    // Opus R128 gains are Q7.8 dB relative to -23 LUFS, 5 dB below ReplayGain's reference
+    let r128 = |key: &str| {
+        text(key)
+            .and_then(|v| v.parse::<i16>().ok())
+            .map(|q| q as f64 / 256.0 + 5.0)
+    };
+    let gain = |replaygain: &str, r128_key: &str| {
+        text(replaygain).and_then(parse_gain).or_else(|| r128(r128_key))
+    };
+    let bitrate = info.nominal_bitrate.map(|b| b / 1000).or_else(|| {
+        let seconds = info.duration_secs.filter(|s| *s > 0.0)?;
+        let bytes = fs::metadata(abs_path).ok()?.len();
+        Some((bytes as f64 * 8.0 / seconds / 1000.0).round() as u32).filter(|b| *b > 0)
+    });
+    let tagged = text("TITLE").is_some() || artist.is_some() || text("ALBUM").is_some();
+    let fallback = path_metadata(settings, song, id);
+    TrackMetadata {
+        title: text("TITLE").map(String::from).or(fallback.title),
+        album: text("ALBUM").map(String::from),
+        artist,
+        album_artist: text("ALBUMARTIST")
+            .or_else(|| text("ALBUM ARTIST"))
+            .map(String::from),
+        year: text("DATE").and_then(|d| d.get(..4)).and_then(|y| y.parse().ok()),
+        duration: info.duration_secs.map(|d| d.ceil() as u32),
+        genre,
+        composer: text("COMPOSER").map(String::from),
+        track_number: text("TRACKNUMBER")
+            .and_then(parse_position)
+            .or(fallback.track_number),
+        disc_number: text("DISCNUMBER").and_then(parse_position),
+        track_gain: gain("REPLAYGAIN_TRACK_GAIN", "R128_TRACK_GAIN"),
+        album_gain: gain("REPLAYGAIN_ALBUM_GAIN", "R128_ALBUM_GAIN"),
+        bitrate,
+        sample_rate: Some(info.sample_rate),
+        channels: Some(info.channels),
+        additional_artists,
+        additional_genres: additional_genres.unwrap_or_default(),
+        scan_status: if tagged {
+            ScanStatus::Ok
+        } else {
+            ScanStatus::NoTags
+        },
+        ..fallback
+    }
+}
+
+/// Extensions of Ogg files, whose tags are read by [`read_ogg_metadata`]
+const OGG_EXTENSIONS: &[&str] = &["ogg", "oga", "opus"];
+
+fn read_metadata(settings: &Settings, abs_path: &Path, song: &Song, id: i64) -> TrackMetadata {
+    let (file_title, file_track) = title_from_file_name(settings, &song.file_name);
+    let is_ogg = OGG_EXTENSIONS
+        .iter()
+        .any(|e| song.file_extension.eq_ignore_ascii_case(e));
+    let metadata = if is_ogg {
+        read_ogg_metadata(settings, abs_path, song, id)
+    } else {
+        match audiotags::Tag::new().read_from_path(abs_path) {
+            Ok(tag) => {
+                let (artist, additional_artists) = tag.artist().map(split_multi_value).unzip();
+                let (genre, additional_genres) = tag.genre().map(split_multi_value).unzip();
+                let metadata = TrackMetadata {
+                    file_artifact_id: id,
+                    title: tag.title().map(String::from),
+                    album: tag.album_title().map(String::from),
+                    artist,
+                    album_artist: tag
+                        .album_artist()
+                        .map(str::trim)
+                        .filter(|a| !a.is_empty())
+                        .map(String::from),
+                    year: tag.year().map(|y| y as u16),
+                    duration: tag.duration().map(|d| d.ceil() as u32),
+                    genre,
+                    composer: tag.composer().map(String::from),
+                    track_number: tag.track_number(),
+                    disc_number: tag.disc_number(),
+                    additional_artists: additional_artists.unwrap_or_default(),
+                    additional_genres: additional_genres.unwrap_or_default(),
+                    ..Default::default()
+                };
+                let untagged = metadata.title.is_none()
+                    && metadata.artist.is_none()
+                    && metadata.album.is_none();
+                let details = read_format_details(tag, abs_path);
+                // Only tags that are there are trusted, the file name fills in what is missing
+                let (title, track_number) = match metadata.title {
+                    Some(title) => (Some(title), metadata.track_number),
+                    None => (Some(file_title), metadata.track_number.or(file_track)),
+                };
+                TrackMetadata {
+                    title,
+                    track_number,
+                    scan_status: if untagged {
+                        ScanStatus::NoTags
+                    } else {
+                        ScanStatus::Ok
+                    },
+                    track_gain: details.track_gain,
+                    album_gain: details.album_gain,
+                    bitrate: details.bitrate,
+                    sample_rate: details.sample_rate,
+                    channels: details.channels,
+                    ..metadata
+                }
+            }
+            Err(e) => {
+                let is_mp3 = song.file_extension.eq_ignore_ascii_case("mp3");
+                // Fall back to what the path tells us, as parse_path derived it
+                let details = if is_mp3 {
+                    read_mpeg_details(abs_path)
+                } else {
+                    FormatDetails::default()
+                };
+                let (scan_status, scan_error) = match tag_error_status(&e) {
+                    // Random bytes lack an ID3 tag too, but only a real mp3 has audio frames
+                    ScanStatus::NoTags if is_mp3 && details.bitrate.is_none() => (
+                        ScanStatus::ReadError,
+                        format!("{}, and no MPEG audio frames were found", e),
+                    ),
+                    status => (status, e.to_string()),
+                };
+                if scan_status == ScanStatus::NoTags {
+                    log::debug!("No tags in {}: {}", abs_path.display(), scan_error);
+                } else {
+                    log::warn!("Could not read {}: {}", abs_path.display(), scan_error);
+                }
+                TrackMetadata {
+                    bitrate: details.bitrate,
+                    sample_rate: details.sample_rate,
+                    channels: details.channels,
+                    scan_status,
+                    scan_error: Some(scan_error),
+                    ..path_metadata(settings, song, id)
+                }
+            }
+        }
+    };
+
+    let Some(cue) = &song.cue else {
+        return metadata;
+    };
+    // One track of a single-file album: the sheet describes the track, the file the album
+    let end_ms = cue.end_ms.or(metadata.duration.map(|d| d as i64 * 1000));
+    let (artist, additional_artists) = match &cue.performer {
+        Some(performer) => {
+            let (artist, additional) = split_multi_value(performer);
+            (Some(artist), additional)
+        }
+        None => (metadata.artist, metadata.additional_artists),
+    };
+    TrackMetadata {
+        title: cue
+            .title
+            .clone()
+            .or_else(|| Some(format!("{} (track {})", song.file_name, cue.number))),
+        artist,
+        additional_artists,
+        album: cue.album.clone().or(metadata.album),
+        track_number: Some(cue.number),
+        duration: end_ms.map(|end| ((end - cue.start_ms).max(0) as u32).div_ceil(1000)),
+        ..metadata
+    }
+}
+
 async fn save_metadata(
-    conn: &mut PoolConnection<Sqlite>,
+    settings: &Settings,
+    conn: &mut SqliteConnection,
     song: &Song,
     id: i64,
     base_path: &Path,
-) -> anyhow::Result<()> {
-    let joined_path = base_path.join(song.full_path.clone());
+) -> anyhow::Result<u64> {
+    let joined_path = song.absolute(base_path);
     let abs_path = joined_path.as_path();
-    let tag_res = audiotags::Tag::new().read_from_path(abs_path);
-
-    let metadata: TrackMetadata = match tag_res {
-        Ok(tag) => TrackMetadata {
-            file_artifact_id: id,
-            title: tag.title().map(String::from),
-            album: tag.album_title().map(String::from),
-            artist: tag.artist().map(String::from),
-            year: tag.year().map(|y| y as u16),
-            duration: tag.duration().map(|d| d.ceil() as u32),
-            genre: tag.genre().map(String::from),
-            composer: tag.composer().map(String::from),
-            track_number: tag.track_number(),
-        },
-        Err(_e) => {
-            TrackMetadata {
-                file_artifact_id: id,
-                ..Default::default()
-            }
-        }
-    };
+    let metadata = read_metadata(settings, abs_path, song, id);
+    let scan_status = metadata.scan_status.as_str();
 
+    // An upsert rather than `insert or replace`, which would drop the row's cached peaks and
+    // cover path along with the old tags
     let meta_insert = sqlx::query!(
         "
         insert into track_metadata (
             filesystem_artifact_id,
             artist,
             album,
+            album_artist,
             track_name,
             genre,
             composer,
             release_year,
             track_number,
-            duration
+            duration,
+            disc_number,
+            track_gain,
+            album_gain,
+            bitrate,
+            sample_rate,
+            channels,
+            scan_status,
+            scan_error
         ) values (
-            ?, ?, ?, ?, ?, ?, ?, ?, ? )",
+            ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ? )
+        on conflict(filesystem_artifact_id) do update set
+            artist = excluded.artist,
+            album = excluded.album,
+            album_artist = excluded.album_artist,
+            track_name = excluded.track_name,
+            genre = excluded.genre,
+            composer = excluded.composer,
+            release_year = excluded.release_year,
+            track_number = excluded.track_number,
+            duration = excluded.duration,
+            disc_number = excluded.disc_number,
+            track_gain = excluded.track_gain,
+            album_gain = excluded.album_gain,
+            bitrate = excluded.bitrate,
+            sample_rate = excluded.sample_rate,
+            channels = excluded.channels,
+            scan_status = excluded.scan_status,
+            scan_error = excluded.scan_error",
         metadata.file_artifact_id,
         metadata.artist,
         metadata.album,
+        metadata.album_artist,
         metadata.title,
         metadata.genre,
         metadata.composer,
         metadata.year,
         metadata.track_number,
-        metadata.duration
+        metadata.duration,
+        metadata.disc_number,
+        metadata.track_gain,
+        metadata.album_gain,
+        metadata.bitrate,
+        metadata.sample_rate,
+        metadata.channels,
+        scan_status,
+        metadata.scan_error
     )
-    .execute(conn.as_mut())
+    .execute(&mut *conn)
     .await?;
 
-    println!("Inserted {} rows", meta_insert.rows_affected());
+    log::debug!(
+        "Saved metadata for {} ({} rows)",
+        song.relative_path,
+        meta_insert.rows_affected()
+    );
 
-    Ok(())
+    sqlx::query!("delete from track_artists where filesystem_artifact_id = ?", id)
+        .execute(&mut *conn)
+        .await?;
+    for (position, artist) in metadata.additional_artists.iter().enumerate() {
+        let position = position as i64;
+        sqlx::query!(
+            "insert into track_artists (filesystem_artifact_id, position, artist) values (?, ?, ?)",
+            id,
+            position,
+            artist
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    sqlx::query!("delete from track_genres where filesystem_artifact_id = ?", id)
+        .execute(&mut *conn)
+        .await?;
+    for (position, genre) in metadata.additional_genres.iter().enumerate() {
+        let position = position as i64;
+        sqlx::query!(
+            "insert into track_genres (filesystem_artifact_id, position, genre) values (?, ?, ?)",
+            id,
+            position,
+            genre
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(meta_insert.rows_affected())
 }
 
-async fn find_or_create_song(conn: &mut PoolConnection<Sqlite>, song: &Song) -> sqlx::Result<i64> {
-    let existing_id = sqlx::query!(
-        "
-        select 
-            f.id
+enum SongLookup {
+    Existing(i64),
+    /// The file changed on disk since its tags were last read
+    Modified(i64),
+    Restored(i64),
+    /// A row whose file went missing matched this file's size and mtime, so the file was
+    /// renamed or moved and the row now has its path
+    Renamed(i64),
+    Created(i64),
+}
+
+async fn find_or_create_song(
+    conn: &mut SqliteConnection,
+    song: &Song,
+    base_path: &Path,
+) -> sqlx::Result<SongLookup> {
+    let cue_track = song.cue.as_ref().map(|c| c.number);
+    let cue_start_ms = song.cue.as_ref().map(|c| c.start_ms);
+    let cue_end_ms = song.cue.as_ref().and_then(|c| c.end_ms);
+    let raw_path = song.raw_path.as_deref().map(raw_path_bytes);
+    let existing = sqlx::query!(
+        r#"
+        select
+            f.id as "id!",
+            f.is_present,
+            f.file_mtime,
+            f.content_hash,
+            f.file_size,
+            f.first_path_segment,
+            f.second_path_segment,
+            f.path_inferred,
+            f.cue_start_ms,
+            f.cue_end_ms,
+            f.raw_path
         from filesystem_artifacts f
         where
             f.file_name = ?
             and f.file_extension = ?
-            and f.relative_path = ?",
+            and f.relative_path = ?
+            and f.cue_track is ?"#,
         song.file_name,
         song.file_extension,
-        song.file_path
+        song.relative_path,
+        cue_track
     )
-    .fetch_optional(conn.as_mut())
-    .await
-    .map(|r| r.map(|g| g.id))?;
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let abs_path = song.absolute(base_path);
+    let mtime = file_mtime(&abs_path);
+    let size = file_size(&abs_path);
 
-    if let Some(id) = existing_id {
-        return Ok(id);
+    if let Some(row) = existing {
+        let modified = match (mtime, row.file_mtime) {
+            (Some(disk), Some(stored)) => disk > stored,
+            (Some(_), None) => true,
+            _ => false,
+        } || (row.cue_start_ms, row.cue_end_ms) != (cue_start_ms, cue_end_ms)
+            // Scanned under another PATH_LAYOUT, so untagged songs need the new names
+            || row.first_path_segment.as_deref() != Some(song.artist.as_str())
+            || row.second_path_segment.as_deref() != Some(song.album.as_str())
+            || (row.path_inferred != 0) != song.path_inferred
+            || row.raw_path != raw_path;
+        let restored = row.is_present == 0;
+        if !modified && !restored {
+            // Rows scanned before content hashes were stored get one once
+            if row.content_hash.is_none() {
+                let hash = content_hash(&abs_path);
+                sqlx::query!(
+                    "update filesystem_artifacts set content_hash = ? where id = ?",
+                    hash,
+                    row.id
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+            // Likewise for sizes
+            if row.file_size.is_none() && size.is_some() {
+                sqlx::query!(
+                    "update filesystem_artifacts set file_size = ? where id = ?",
+                    size,
+                    row.id
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+            return Ok(SongLookup::Existing(row.id));
+        }
+        // The file was flagged missing by an earlier scan but is back on disk,
+        // or it has been rewritten since it was last scanned
+        let now = unix_timestamp();
+        let hash = content_hash(&abs_path);
+        sqlx::query!(
+            "
+            update filesystem_artifacts
+            set
+                is_present = TRUE,
+                file_mtime = ?,
+                content_hash = ?,
+                file_size = ?,
+                first_path_segment = ?,
+                second_path_segment = ?,
+                path_inferred = ?,
+                cue_start_ms = ?,
+                cue_end_ms = ?,
+                raw_path = ?,
+                updated_at = ?
+            where id = ?",
+            mtime,
+            hash,
+            size,
+            song.artist,
+            song.album,
+            song.path_inferred,
+            cue_start_ms,
+            cue_end_ms,
+            raw_path,
+            now,
+            row.id
+        )
+        .execute(&mut *conn)
+        .await?;
+        if modified {
+            // Peaks follow the audio, which may have changed, as may a cue track's part of it
+            sqlx::query!(
+                "update track_metadata set peaks = null where filesystem_artifact_id = ?",
+                row.id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        return Ok(if restored {
+            SongLookup::Restored(row.id)
+        } else {
+            SongLookup::Modified(row.id)
+        });
+    }
+
+    if let Some(id) = find_renamed(conn, song, base_path, mtime, size).await? {
+        let now = unix_timestamp();
+        sqlx::query!(
+            "
+            update filesystem_artifacts
+            set
+                relative_path = ?,
+                file_name = ?,
+                file_extension = ?,
+                is_present = TRUE,
+                first_path_segment = ?,
+                second_path_segment = ?,
+                path_inferred = ?,
+                cue_start_ms = ?,
+                cue_end_ms = ?,
+                raw_path = ?,
+                updated_at = ?
+            where id = ?",
+            song.relative_path,
+            song.file_name,
+            song.file_extension,
+            song.artist,
+            song.album,
+            song.path_inferred,
+            cue_start_ms,
+            cue_end_ms,
+            raw_path,
+            now,
+            id
+        )
+        .execute(&mut *conn)
+        .await?;
+        return Ok(SongLookup::Renamed(id));
     }
 
     let now = unix_timestamp();
-    let created_id = sqlx::query!(
+    let hash = content_hash(&abs_path);
+    let created = sqlx::query!(
         "
         insert into filesystem_artifacts (
             relative_path,
@@ -181,82 +1593,1286 @@ async fn find_or_create_song(conn: &mut PoolConnection<Sqlite>, song: &Song) ->
             first_path_segment,
             second_path_segment,
             created_at,
-            updated_at
+            updated_at,
+            file_mtime,
+            content_hash,
+            file_size,
+            path_inferred,
+            cue_track,
+            cue_start_ms,
+            cue_end_ms,
+            raw_path
         ) values (
-            ?, ?, ?, TRUE, ?, ?, ?, NULL
-        ) returning id;",
-        song.file_path,
+            ?, ?, ?, TRUE, ?, ?, ?, NULL, ?, ?, ?, ?, ?, ?, ?, ?
+        )
+        on conflict do nothing
+        returning id;",
+        song.relative_path,
         song.file_name,
         song.file_extension,
         song.artist,
         song.album,
         now,
+        mtime,
+        hash,
+        size,
+        song.path_inferred,
+        cue_track,
+        cue_start_ms,
+        cue_end_ms,
+        raw_path,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(created) = created {
+        return Ok(SongLookup::Created(created.id));
+    }
+
+    // Another scan, e.g. the watcher's, added the file since the lookup above
+    let raced_id = sqlx::query!(
+        r#"select id as "id!" from filesystem_artifacts where relative_path = ? and cue_track is ?"#,
+        song.relative_path,
+        cue_track
     )
-    .fetch_one(conn.as_mut())
+    .fetch_one(&mut *conn)
     .await?
     .id;
+    Ok(SongLookup::Existing(raced_id))
+}
 
-    Ok(created_id)
+/// The row of a file that is no longer on disk with the same size, modification time and
+/// CUE track as `song`, taken to be the same file under a new path. Rows flagged missing by
+/// an earlier scan count, as do present ones whose file is gone, since a scan adds new
+/// files before flagging missing ones
+async fn find_renamed(
+    conn: &mut SqliteConnection,
+    song: &Song,
+    base_path: &Path,
+    mtime: Option<i64>,
+    size: Option<i64>,
+) -> sqlx::Result<Option<i64>> {
+    let (Some(mtime), Some(size)) = (mtime, size) else {
+        return Ok(None);
+    };
+    let cue_track = song.cue.as_ref().map(|c| c.number);
+    let candidates = sqlx::query!(
+        r#"
+        select id as "id!", relative_path, raw_path, is_present
+        from filesystem_artifacts
+        where file_size = ? and file_mtime = ? and cue_track is ? and relative_path != ?
+        order by id"#,
+        size,
+        mtime,
+        cue_track,
+        song.relative_path
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(candidates
+        .into_iter()
+        .find(|c| {
+            let path = match &c.raw_path {
+                Some(raw) => raw_path_from_bytes(raw.clone()),
+                None => PathBuf::from(&c.relative_path),
+            };
+            c.is_present == 0 || !base_path.join(path).exists()
+        })
+        .map(|c| c.id))
 }
 
+/// Adds rows for new files and reads tags for files that lack them or changed on disk.
+/// With `force` every file's tags are read again, replacing what was saved
 pub async fn scan_for_unadded(
+    settings: &Settings,
     base_path: &Path,
     files: &[Song],
     db: &Pool<Sqlite>,
+    force: bool,
+) -> anyhow::Result<ScanSummary> {
+    let mut conn = db.acquire().await?;
+    let mut summary = ScanSummary::default();
+
+    // Committing per batch rather than per statement is what keeps a first scan of a big
+    // library quick on SQLite. A failure rolls back its own batch, earlier ones stay saved
+    for batch in files.chunks(settings.scan_batch_size.max(1)) {
+        let mut tx = conn.begin().await?;
+        for song in batch {
+            if let Err(e) = scan_song(settings, &mut tx, song, base_path, force, &mut summary).await {
+                log::error!(
+                    "Could not scan {}, rolling back its batch of {} files: {}",
+                    song.relative_path,
+                    batch.len(),
+                    e
+                );
+                return Err(e);
+            }
+        }
+        tx.commit().await?;
+    }
+
+    Ok(summary)
+}
+
+async fn scan_song(
+    settings: &Settings,
+    conn: &mut SqliteConnection,
+    song: &Song,
+    base_path: &Path,
+    force: bool,
+    summary: &mut ScanSummary,
 ) -> anyhow::Result<()> {
-    // for each song
     // look for a song in the same file path
     // if it exists do nothing
+    // if it exists but was flagged missing, flag it present again
+    // if it exists but changed on disk, re-read its tags
+    // if a missing row has its size and mtime, give that row the new path
     // if it does not exist, create a row
-    // For each row in the database,
-    // if the file exists in the list we were given
-    // do nothing
-    // if the file does not exist in the list we were given
-    // update the db row to is_present = false
-    let mut conn = db.acquire().await?;
-
-    for song in files.iter() {
-        let song_id = find_or_create_song(&mut conn, song).await?;
-        let has_meta = sqlx::query!(
-            "
-            select filesystem_artifact_id from track_metadata
-            where filesystem_artifact_id = ?
-        ",
-            song_id
-        )
-        .fetch_optional(conn.as_mut())
-        .await?
-        .is_some();
-        if !has_meta {
-            save_metadata(&mut conn, song, song_id, base_path).await?;
+    let (song_id, stale) = match find_or_create_song(conn, song, base_path).await? {
+        SongLookup::Existing(id) => {
+            summary.unchanged += 1;
+            (id, false)
+        }
+        SongLookup::Modified(id) | SongLookup::Renamed(id) => {
+            summary.updated += 1;
+            (id, true)
         }
+        SongLookup::Restored(id) | SongLookup::Created(id) => {
+            summary.added += 1;
+            (id, true)
+        }
+    };
+    let has_meta = sqlx::query!(
+        "
+        select filesystem_artifact_id from track_metadata
+        where filesystem_artifact_id = ?
+    ",
+        song_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .is_some();
+    if !has_meta || stale || force {
+        summary.metadata_saved += save_metadata(settings, conn, song, song_id, base_path).await?;
     }
-
     Ok(())
 }
 
-pub async fn scan_and_flag_missing(base_path: &Path, db: &Pool<Sqlite>,) -> anyhow::Result<()> {
+/// Flags every present row whose path is absent from `files`, returning how many were flagged
+pub async fn scan_and_flag_missing(files: &[Song], db: &Pool<Sqlite>) -> anyhow::Result<u64> {
     let mut conn = db.acquire().await?;
-    let songs = sqlx::query!("
+    flag_missing(files, &mut conn, None).await
+}
+
+/// As [`scan_and_flag_missing`], but when `dir` is given only rows for files directly
+/// inside that relative directory are considered
+async fn flag_missing(
+    files: &[Song],
+    conn: &mut SqliteConnection,
+    dir: Option<&Path>,
+) -> anyhow::Result<u64> {
+    // A file split by a cue sheet has one row per track
+    let crawled_paths = files
+        .iter()
+        .map(|s| {
+            (
+                s.relative_path.as_str(),
+                s.cue.as_ref().map(|c| c.number as i64),
+            )
+        })
+        .collect::<HashSet<_>>();
+    let songs = sqlx::query!(
+        "
         select
             id,
             relative_path,
-            is_present
+            cue_track
         from filesystem_artifacts
-    ").fetch_all(conn.as_mut()).await?.iter().map(|r| (r.id, r.relative_path.clone(), r.is_present)).collect::<Vec::<_>>();
+        where is_present != 0
+    "
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .iter()
+    .filter(|r| dir.is_none_or(|d| Path::new(&r.relative_path).parent() == Some(d)))
+    .map(|r| (r.id, r.relative_path.clone(), r.cue_track))
+    .collect::<Vec<_>>();
+    let mut removed = 0;
     for song in songs.iter() {
-        let song_path = base_path.join(song.1.clone());
-        let song_exists = fs::exists(song_path).unwrap_or(false);
-        if !song_exists {
+        if !crawled_paths.contains(&(song.1.as_str(), song.2)) {
+            let now = unix_timestamp();
             let update_res = sqlx::query!("
                 update filesystem_artifacts
-                set is_present = 0
+                set is_present = FALSE, updated_at = ?
                 where id = ?
-            ", song.0).execute(conn.as_mut()).await?;
+            ", now, song.0).execute(&mut *conn).await?;
             if update_res.rows_affected() == 1 {
-                println!("Missing: {}", song.1);
+                log::info!("Missing: {}", song.1);
+                removed += 1;
             }
         }
     }
-    Ok(())
+    Ok(removed)
+}
+
+/// Brings the rows for `paths` in line with the filesystem without crawling the whole library
+pub async fn sync_paths(
+    settings: &Settings,
+    base_path: &Path,
+    paths: &[PathBuf],
+    db: &Pool<Sqlite>,
+) -> anyhow::Result<ScanSummary> {
+    let mut found: Vec<Song> = Vec::new();
+    let mut gone: Vec<String> = Vec::new();
+    // A changed file is resynced along with its siblings, since a cue sheet next to it
+    // decides whether it is one song or several
+    let mut file_dirs: HashSet<PathBuf> = HashSet::new();
+    for path in paths {
+        let Ok(rel_path) = path.strip_prefix(base_path) else {
+            continue;
+        };
+        if path.is_dir() {
+            let songs = crawl_dir(settings, base_path, path).await?;
+            found.extend(songs.into_iter().map(Song::from));
+        } else {
+            if let Some(parent) = path.parent().filter(|p| p.is_dir()) {
+                file_dirs.insert(parent.to_path_buf());
+            }
+            if !path.exists() {
+                gone.push(lossy_relative_path(rel_path));
+            }
+        }
+    }
+    let mut dir_found: Vec<(PathBuf, Vec<Song>)> = Vec::new();
+    for dir in file_dirs {
+        let Ok(mut read_dir) = tokio::fs::read_dir(&dir).await else {
+            continue;
+        };
+        let mut files = Vec::new();
+        while let Ok(Some(entry)) = read_dir.next_entry().await {
+            if tokio::fs::metadata(entry.path()).await.is_ok_and(|m| m.is_file()) {
+                files.push(entry.path());
+            }
+        }
+        let songs = dir_songs(settings, base_path, &dir, &files)
+            .await
+            .into_iter()
+            .map(Song::from)
+            .collect::<Vec<_>>();
+        found.extend(songs.iter().cloned());
+        let rel_dir = dir.strip_prefix(base_path).unwrap_or(&dir).to_path_buf();
+        dir_found.push((rel_dir, songs));
+    }
+
+    let mut summary = scan_for_unadded(settings, base_path, &found, db, false).await?;
+    let mut conn = db.acquire().await?;
+    for (rel_dir, songs) in dir_found {
+        summary.removed += flag_missing(&songs, &mut conn, Some(&rel_dir)).await?;
+    }
+    for rel_path in gone {
+        // The path may have been a single file or a whole directory
+        let now = unix_timestamp();
+        // An exact prefix rather than LIKE, which ignores case and takes `_` and `%` in names
+        // as wildcards
+        let dir_prefix = format!("{}/", rel_path);
+        let update_res = sqlx::query!(
+            "
+            update filesystem_artifacts
+            set is_present = FALSE, updated_at = ?1
+            where is_present != 0
+                and (relative_path = ?2 or substr(relative_path, 1, length(?3)) = ?3)
+        ",
+            now,
+            rel_path,
+            dir_prefix
+        )
+        .execute(conn.as_mut())
+        .await?;
+        if update_res.rows_affected() > 0 {
+            log::info!("Missing: {}", rel_path);
+        }
+        summary.removed += update_res.rows_affected();
+    }
+    Ok(summary)
+}
+
+/// Re-crawls the library, adding new files and flagging ones that have disappeared.
+/// `force` re-reads every file's tags, see [`scan_for_unadded`]
+pub async fn rescan_library(
+    settings: &Settings,
+    base_path: &Path,
+    db: &Pool<Sqlite>,
+    force: bool,
+) -> anyhow::Result<ScanSummary> {
+    let songs = load_library(settings, base_path).await?;
+    let mut summary = scan_for_unadded(settings, base_path, &songs, db, force).await?;
+    summary.removed = scan_and_flag_missing(&songs, db).await?;
+    Ok(summary)
+}
+
+/// As [`rescan_library`], but every change is saved in one transaction rather than a batch
+/// at a time. The new library is built up in it while requests keep reading the old one,
+/// and the commit swaps it in at once, so no request sees a half-synced library
+pub async fn reload_library(
+    settings: &Settings,
+    base_path: &Path,
+    db: &Pool<Sqlite>,
+    force: bool,
+) -> anyhow::Result<ScanSummary> {
+    let songs = load_library(settings, base_path).await?;
+    let mut conn = db.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let mut summary = ScanSummary::default();
+    for song in &songs {
+        if let Err(e) = scan_song(settings, &mut tx, song, base_path, force, &mut summary).await {
+            log::error!("Could not scan {}, keeping the old library: {}", song.relative_path, e);
+            return Err(e);
+        }
+    }
+    summary.removed = flag_missing(&songs, &mut tx, None).await?;
+    tx.commit().await?;
+    Ok(summary)
+}
+
+/// Settings as they are with no environment set, for tests
+#[cfg(test)]
+pub(crate) fn test_settings() -> Settings {
+    Settings {
+        allowed_extensions: ["ogg", "opus", "flac", "mp3", "wav", "m4a", "aac"]
+            .iter()
+            .map(|e| (*e).to_string())
+            .collect(),
+        unknown_artist: "Unknown Artist".into(),
+        unknown_album: "Unknown Album".into(),
+        unknown_genre: "Unknown".into(),
+        follow_symlinks: false,
+        ignored_dirs: vec![".git".into(), "@eaDir".into(), ".Trash".into()],
+        include_hidden: false,
+        scan_batch_size: 500,
+        cover_names: ["cover.jpg", "cover.png", "folder.jpg", "folder.png", "front.jpg"]
+            .iter()
+            .map(|n| (*n).to_string())
+            .collect(),
+        path_layout: PathLayout::default(),
+        loose_file_policy: LooseFilePolicy::default(),
+        title_pattern: parse_title_pattern(DEFAULT_TITLE_PATTERN).unwrap(),
+        // Unlike an unset MIN_FILE_SIZE, since fixture files are a few bytes long
+        min_file_size: 0,
+        hide_paths: false,
+        stream_chunk_size: 64 * 1024,
+        transcode_profile: TranscodeProfile::default(),
+        stream_idle_timeout: None,
+    }
+}
+
+/// A migrated database in `dir`, for tests
+#[cfg(test)]
+pub(crate) async fn test_db(dir: &Path) -> Pool<Sqlite> {
+    let options = sqlx::sqlite::SqliteConnectOptions::new()
+        .filename(dir.join("test.db"))
+        .create_if_missing(true)
+        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .connect_with(options)
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_song(base: &Path, rel_path: &str) {
+        let path = base.join(rel_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"not really audio").unwrap();
+    }
+
+    async fn present_paths(db: &Pool<Sqlite>) -> Vec<String> {
+        sqlx::query_scalar(
+            "select relative_path from filesystem_artifacts where is_present != 0
+            order by relative_path",
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn crawl_dir_finds_a_file_1000_directories_down() {
+        let lib = tempfile::tempdir().unwrap();
+        let deep = vec!["d"; 1000].join("/");
+        write_song(lib.path(), &format!("{}/song.mp3", deep));
+
+        let songs = crawl_dir(&test_settings(), lib.path(), lib.path()).await.unwrap();
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].relative_path, format!("{}/song.mp3", deep));
+        assert_eq!(songs[0].file_name, "song");
+    }
+
+    #[tokio::test]
+    async fn crawl_dir_finds_apple_files_whatever_the_extension_case() {
+        let lib = tempfile::tempdir().unwrap();
+        for path in ["A/B/1.M4A", "A/B/2.m4a", "A/B/3.aac", "A/B/4.FLAC", "A/B/5.M4P"] {
+            write_song(lib.path(), path);
+        }
+
+        let mut songs = crawl_dir(&test_settings(), lib.path(), lib.path()).await.unwrap();
+        songs.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        let found = songs
+            .iter()
+            .map(|song| (song.file_name.as_str(), song.file_extension.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [("1", "M4A"), ("2", "m4a"), ("3", "aac"), ("4", "FLAC")]);
+    }
+
+    #[tokio::test]
+    async fn crawl_dir_leaves_out_hidden_files_and_ignored_directories() {
+        let lib = tempfile::tempdir().unwrap();
+        for path in [
+            "A/B/01.flac",
+            "A/B/.hidden.flac",
+            "A/B/._01.flac",
+            "A/B/@eaDir/01.flac",
+            "A/B/@eaDir/SYNOPHOTO/02.mp3",
+            "A/.Trash/B/03.mp3",
+        ] {
+            write_song(lib.path(), path);
+        }
+        let crawled = |settings: Settings| {
+            let base = lib.path().to_path_buf();
+            async move {
+                let songs = crawl_dir(&settings, &base, &base).await.unwrap();
+                let mut paths = songs.into_iter().map(|s| s.relative_path).collect::<Vec<_>>();
+                paths.sort();
+                paths
+            }
+        };
+
+        assert_eq!(crawled(test_settings()).await, ["A/B/01.flac"]);
+        let settings = Settings {
+            include_hidden: true,
+            ..test_settings()
+        };
+        // Ignored directories stay out even then
+        assert_eq!(
+            crawled(settings).await,
+            ["A/B/._01.flac", "A/B/.hidden.flac", "A/B/01.flac"]
+        );
+    }
+
+    #[tokio::test]
+    async fn crawl_dir_skips_empty_files_and_scans_cut_off_ones_as_truncated() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        fs::create_dir_all(lib.path().join("A/B")).unwrap();
+        fs::write(lib.path().join("A/B/empty.flac"), b"").unwrap();
+        // An ID3v2.4 tag promising 4000 bytes of frames. A 1500 byte title frame is whole,
+        // but the file ends in the header of the next one
+        let mut cut_off = b"ID3\x04\x00\x00\x00\x00\x1f\x20".to_vec();
+        cut_off.extend(b"TIT2\x00\x00\x0b\x5c\x00\x00\x03");
+        cut_off.resize(cut_off.len() + 1499, b'T');
+        cut_off.extend(b"TALB\x00\x00\x00\x64\x00\x01");
+        fs::write(lib.path().join("A/B/cut off.mp3"), cut_off).unwrap();
+        let settings = Settings {
+            min_file_size: 1024,
+            ..test_settings()
+        };
+
+        let songs = crawl_dir(&settings, lib.path(), lib.path()).await.unwrap();
+        let paths = songs.iter().map(|s| s.relative_path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["A/B/cut off.mp3"]);
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        let status: String = sqlx::query_scalar("select scan_status from track_metadata")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(status, "truncated");
+    }
+
+    #[tokio::test]
+    async fn sync_paths_only_flags_files_inside_a_removed_directory() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = test_settings();
+        for path in ["a_b/Album/1.mp3", "axb/Album/1.mp3", "A_B/Album/1.mp3", "a_bc/Album/1.mp3"] {
+            write_song(lib.path(), path);
+        }
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+
+        fs::remove_dir_all(lib.path().join("a_b")).unwrap();
+        let summary = sync_paths(&settings, lib.path(), &[lib.path().join("a_b")], &db)
+            .await
+            .unwrap();
+        assert_eq!(summary.removed, 1);
+        assert_eq!(
+            present_paths(&db).await,
+            vec!["A_B/Album/1.mp3", "a_bc/Album/1.mp3", "axb/Album/1.mp3"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn crawl_dir_crawls_a_directory_reached_through_links_once() {
+        let lib = tempfile::tempdir().unwrap();
+        write_song(lib.path(), "Artist/Album/1.mp3");
+        std::os::unix::fs::symlink(lib.path().join("Artist"), lib.path().join("Link")).unwrap();
+        let cycle = lib.path().join("Artist/Album/Up");
+        std::os::unix::fs::symlink(lib.path().join("Artist"), cycle).unwrap();
+        let settings = Settings {
+            follow_symlinks: true,
+            ..test_settings()
+        };
+
+        let songs = crawl_dir(&settings, lib.path(), lib.path()).await.unwrap();
+        let paths = songs.iter().map(|s| s.relative_path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["Artist/Album/1.mp3"]);
+    }
+
+    #[tokio::test]
+    async fn reading_tags_again_keeps_cached_peaks_and_cover_path() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = test_settings();
+        write_song(lib.path(), "Artist/Album/01 Song.mp3");
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        sqlx::query("update track_metadata set peaks = x'0100', cover_path = ?")
+            .bind("Artist/Album/cover.jpg")
+            .execute(&db)
+            .await
+            .unwrap();
+        let cached = || async {
+            sqlx::query_as::<_, (Option<Vec<u8>>, Option<String>)>(
+                "select peaks, cover_path from track_metadata",
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap()
+        };
+
+        let summary = rescan_library(&settings, lib.path(), &db, true).await.unwrap();
+        assert_eq!(summary.metadata_saved, 1);
+        assert_eq!(
+            cached().await,
+            (Some(vec![1, 0]), Some("Artist/Album/cover.jpg".to_string()))
+        );
+
+        // A rewritten file's peaks are stale, its folder's cover isn't
+        sqlx::query("update filesystem_artifacts set file_mtime = 0")
+            .execute(&db)
+            .await
+            .unwrap();
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!(cached().await, (None, Some("Artist/Album/cover.jpg".to_string())));
+    }
+
+    #[tokio::test]
+    async fn rescan_reads_the_tags_of_a_touched_file_again() {
+        use id3::TagLike;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = test_settings();
+        let path = lib.path().join("Artist/Album/01.mp3");
+        write_song(lib.path(), "Artist/Album/01.mp3");
+        let retag = |title: &str, mtime: std::time::SystemTime| {
+            let mut tag = id3::Tag::new();
+            tag.set_title(title);
+            tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+            fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        };
+        let title = || async {
+            sqlx::query_scalar::<_, String>("select track_name from track_metadata")
+                .fetch_one(&db)
+                .await
+                .unwrap()
+        };
+        let scanned_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        retag("Before", scanned_at);
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!(title().await, "Before");
+
+        // Same mtime, so the file isn't read again
+        retag("After", scanned_at);
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!(title().await, "Before");
+
+        retag("After", scanned_at + Duration::from_secs(60));
+        let summary = rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!(summary.metadata_saved, 1);
+        assert_eq!(title().await, "After");
+    }
+
+    #[tokio::test]
+    async fn only_a_forced_rescan_reads_an_unchanged_file_again() {
+        use id3::TagLike;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = test_settings();
+        let path = lib.path().join("Artist/Album/01.mp3");
+        write_song(lib.path(), "Artist/Album/01.mp3");
+        let scanned_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        // Titles of one length, so the size doesn't change either
+        let retag = |title: &str| {
+            let mut tag = id3::Tag::new();
+            tag.set_title(title);
+            tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+            fs::File::options().write(true).open(&path).unwrap().set_modified(scanned_at).unwrap();
+        };
+        let rows = || async {
+            sqlx::query_as::<_, (i64, String)>(
+                "select filesystem_artifact_id, track_name from track_metadata",
+            )
+            .fetch_all(&db)
+            .await
+            .unwrap()
+        };
+
+        retag("Before");
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        let before = rows().await;
+        assert_eq!(before.len(), 1);
+        assert_eq!(before[0].1, "Before");
+        let id = before[0].0;
+
+        retag("Fixed!");
+        let summary = rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!((summary.unchanged, summary.metadata_saved), (1, 0));
+        assert_eq!(rows().await, [(id, "Before".to_string())]);
+
+        let summary = rescan_library(&settings, lib.path(), &db, true).await.unwrap();
+        assert_eq!((summary.unchanged, summary.metadata_saved), (1, 1));
+        assert_eq!(rows().await, [(id, "Fixed!".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn rescan_keeps_the_row_of_a_renamed_file() {
+        let lib = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = test_settings();
+        write_song(lib.path(), "Artist/Album/01.mp3");
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        let rows = || async {
+            sqlx::query_as::<_, (i64, String, bool)>(
+                "select id, relative_path, is_present from filesystem_artifacts",
+            )
+            .fetch_all(&db)
+            .await
+            .unwrap()
+        };
+        let id = rows().await[0].0;
+        sqlx::query("insert into favorites (filesystem_artifact_id, created_at) values (?, 0)")
+            .bind(id)
+            .execute(&db)
+            .await
+            .unwrap();
+        let album = lib.path().join("Artist/Album");
+
+        // Renaming keeps the size and mtime
+        fs::rename(album.join("01.mp3"), album.join("01 Intro.mp3")).unwrap();
+        let summary = rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!((summary.added, summary.updated, summary.removed), (0, 1, 0));
+        assert_eq!(rows().await, [(id, "Artist/Album/01 Intro.mp3".to_string(), true)]);
+
+        // Also once a scan has flagged it missing
+        fs::rename(album.join("01 Intro.mp3"), outside.path().join("away.mp3")).unwrap();
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!(rows().await, [(id, "Artist/Album/01 Intro.mp3".to_string(), false)]);
+        fs::create_dir_all(lib.path().join("Other")).unwrap();
+        fs::rename(outside.path().join("away.mp3"), lib.path().join("Other/back.mp3")).unwrap();
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        assert_eq!(rows().await, [(id, "Other/back.mp3".to_string(), true)]);
+
+        let favorites = sqlx::query_scalar::<_, i64>("select filesystem_artifact_id from favorites")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(favorites, [id]);
+    }
+
+    #[tokio::test]
+    async fn a_file_that_is_not_audio_is_named_after_its_path() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        write_song(lib.path(), "Artist/Album/Not Audio.mp3");
+
+        rescan_library(&test_settings(), lib.path(), &db, false).await.unwrap();
+        let row = sqlx::query_as::<_, (String, String, String, Option<String>)>(
+            "select track_name, artist, album, scan_error from track_metadata",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            (row.0.as_str(), row.1.as_str(), row.2.as_str()),
+            ("Not Audio", "Artist", "Album")
+        );
+        assert!(row.3.is_some());
+    }
+
+    #[tokio::test]
+    async fn scan_records_file_sizes_and_null_for_a_vanished_file() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = test_settings();
+        write_song(lib.path(), "A/B/1.mp3");
+        fs::write(lib.path().join("A/B/1.mp3"), [0u8; 1234]).unwrap();
+        write_song(lib.path(), "A/B/2.mp3");
+        let files = load_library(&settings, lib.path()).await.unwrap();
+        // Gone between the crawl and the scan
+        fs::remove_file(lib.path().join("A/B/2.mp3")).unwrap();
+
+        scan_for_unadded(&settings, lib.path(), &files, &db, false).await.unwrap();
+        let sizes = sqlx::query_as::<_, (String, Option<i64>)>(
+            "select relative_path, file_size from filesystem_artifacts order by relative_path",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(sizes, [("A/B/1.mp3".into(), Some(1234)), ("A/B/2.mp3".into(), None)]);
+    }
+
+    #[tokio::test]
+    async fn a_failing_batch_rolls_back_only_itself() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = Settings {
+            scan_batch_size: 2,
+            ..test_settings()
+        };
+        for i in 1..=6 {
+            write_song(lib.path(), &format!("A/B/{:02}.mp3", i));
+        }
+        let files = load_library(&settings, lib.path()).await.unwrap();
+        // The second file of the second batch can't be inserted
+        sqlx::query(
+            "create trigger fail_04 before insert on filesystem_artifacts
+            when new.relative_path = 'A/B/04.mp3'
+            begin select raise(abort, 'simulated failure'); end",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let scanned = scan_for_unadded(&settings, lib.path(), &files, &db, false).await;
+        let err = scanned.err().unwrap();
+        assert!(err.to_string().contains("simulated failure"), "{}", err);
+        assert_eq!(present_paths(&db).await, ["A/B/01.mp3", "A/B/02.mp3"]);
+        let metadata_rows: i64 = sqlx::query_scalar("select count(*) from track_metadata")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(metadata_rows, 2);
+
+        sqlx::query("drop trigger fail_04").execute(&db).await.unwrap();
+        let summary = scan_for_unadded(&settings, lib.path(), &files, &db, false).await.unwrap();
+        assert_eq!((summary.added, summary.unchanged), (4, 2));
+        assert_eq!(present_paths(&db).await.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn reload_library_swaps_the_library_in_at_once() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        let settings = Settings {
+            // Small batches, which a rescan would commit one at a time
+            scan_batch_size: 2,
+            ..test_settings()
+        };
+        for i in 0..20 {
+            write_song(lib.path(), &format!("Old/Album/{:02}.mp3", i));
+        }
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        for i in 0..5 {
+            fs::remove_file(lib.path().join(format!("Old/Album/{:02}.mp3", i))).unwrap();
+        }
+        for i in 0..40 {
+            let path = format!("New/Album/{:02}.mp3", i);
+            write_song(lib.path(), &path);
+            // Another size, so it isn't taken for a removed file that moved
+            fs::write(lib.path().join(path), b"new").unwrap();
+        }
+
+        let reloading = tokio::spawn({
+            let (settings, base, db) = (settings.clone(), lib.path().to_path_buf(), db.clone());
+            async move { reload_library(&settings, &base, &db, false).await }
+        });
+        let mut seen = HashSet::new();
+        while !reloading.is_finished() {
+            seen.insert(present_paths(&db).await.len());
+            tokio::task::yield_now().await;
+        }
+        let summary = reloading.await.unwrap().unwrap();
+        seen.insert(present_paths(&db).await.len());
+
+        assert_eq!((summary.added, summary.removed), (40, 5));
+        assert!(seen.is_subset(&HashSet::from([20, 55])), "saw {:?} songs", seen);
+        assert_eq!(present_paths(&db).await.len(), 55);
+        assert!(present_paths(&db).await.contains(&"New/Album/00.mp3".to_string()));
+    }
+
+    fn parse(settings: &Settings, rel_path: &str) -> PartialSong {
+        parse_path(settings, Path::new(rel_path)).unwrap()
+    }
+
+    #[test]
+    fn parse_path_takes_artist_and_album_from_the_first_two_directories() {
+        let settings = test_settings();
+        let song = parse(&settings, "Artist/Album/01 - Song.flac");
+        assert_eq!((song.artist.as_str(), song.album.as_str()), ("Artist", "Album"));
+        assert_eq!(song.file_name, "01 - Song");
+        assert_eq!(song.file_extension, "flac");
+        assert_eq!(song.relative_path, "Artist/Album/01 - Song.flac");
+        assert!(!song.path_inferred);
+
+        let song = parse(&settings, "Artist/Album/Disc 1/01 - Song.flac");
+        assert_eq!((song.artist.as_str(), song.album.as_str()), ("Artist", "Album"));
+        assert_eq!(song.relative_path, "Artist/Album/Disc 1/01 - Song.flac");
+    }
+
+    #[test]
+    fn parse_path_falls_back_to_unknown_without_two_directories() {
+        let settings = test_settings();
+        for rel_path in ["Album/01 - Song.mp3", "01 - Song.mp3"] {
+            let song = parse(&settings, rel_path);
+            assert_eq!(
+                (song.artist.as_str(), song.album.as_str()),
+                ("Unknown Artist", "Unknown Album")
+            );
+            assert!(song.path_inferred);
+        }
+    }
+
+    #[test]
+    fn parse_path_skips_other_extensions_and_ignored_names() {
+        let settings = test_settings();
+        for rel_path in [
+            "Artist/Album/cover.jpg",
+            "Artist/Album/README",
+            "Artist/Album/._01 - Song.flac",
+            "Artist/.git/Album/01 - Song.flac",
+        ] {
+            assert!(parse_path(&settings, Path::new(rel_path)).is_none(), "{}", rel_path);
+        }
+        assert!(parse_path(&settings, Path::new("Artist/Album/01 - Song.FLAC")).is_some());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn parse_path_splits_windows_separators() {
+        let song = parse(&test_settings(), r"Artist\Album\Disc 1\01 - Song.flac");
+        assert_eq!((song.artist.as_str(), song.album.as_str()), ("Artist", "Album"));
+        assert_eq!(song.relative_path, "Artist/Album/Disc 1/01 - Song.flac");
+    }
+
+    #[test]
+    fn path_layout_finds_the_artist_and_album_directories() {
+        let layout = "genre/artist/album".parse::<PathLayout>().unwrap();
+        assert_eq!(layout.artist_and_album, Some((1, 2)));
+        let layout = " /Album/x/Artist/ ".parse::<PathLayout>().unwrap();
+        assert_eq!(layout.artist_and_album, Some((2, 0)));
+        let layout = "FLAT".parse::<PathLayout>().unwrap();
+        assert_eq!(layout.artist_and_album, None);
+
+        for bad in ["artist", "album", "artist/album/artist", "artist//album", ""] {
+            assert!(bad.parse::<PathLayout>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parse_path_follows_the_layout() {
+        let mut settings = test_settings();
+        settings.path_layout = "genre/artist/album".parse().unwrap();
+        let song = parse(&settings, "Jazz/Artist/Album/01 - Song.ogg");
+        assert_eq!((song.artist.as_str(), song.album.as_str()), ("Artist", "Album"));
+        assert!(parse(&settings, "Artist/Album/01 - Song.ogg").path_inferred);
+
+        settings.path_layout = "flat".parse().unwrap();
+        let song = parse(&settings, "Artist/Album/01 - Song.ogg");
+        assert_eq!(song.artist, "Unknown Artist");
+        assert!(song.path_inferred);
+    }
+
+    #[test]
+    fn loose_file_policy_parses_each_name() {
+        assert!("bucket".parse::<LooseFilePolicy>() == Ok(LooseFilePolicy::Bucket));
+        assert!(" Skip ".parse::<LooseFilePolicy>() == Ok(LooseFilePolicy::Skip));
+        assert!("USE_TAGS".parse::<LooseFilePolicy>() == Ok(LooseFilePolicy::UseTags));
+        assert!("tags".parse::<LooseFilePolicy>().is_err());
+        assert!("".parse::<LooseFilePolicy>().is_err());
+    }
+
+    #[test]
+    fn parse_path_leaves_out_loose_files_when_skipping_them() {
+        let mut settings = test_settings();
+        settings.loose_file_policy = LooseFilePolicy::Skip;
+        assert!(parse_path(&settings, Path::new("01 - Song.flac")).is_none());
+        assert!(parse_path(&settings, Path::new("Album/01 - Song.flac")).is_none());
+        assert!(parse_path(&settings, Path::new("Artist/Album/01 - Song.flac")).is_some());
+    }
+
+    #[test]
+    fn split_multi_value_separates_the_primary_value() {
+        let split = split_multi_value;
+        assert_eq!(split("Artist"), ("Artist".into(), vec![]));
+        assert_eq!(
+            split("A feat. B; C"),
+            ("A".into(), vec!["B".to_string(), "C".to_string()])
+        );
+        assert_eq!(split("A FEAT. B"), ("A".into(), vec!["B".to_string()]));
+        assert_eq!(
+            split("Rock/Pop\0Jazz"),
+            ("Rock".into(), vec!["Pop".to_string(), "Jazz".to_string()])
+        );
+        assert_eq!(split(" A ;; ; B "), ("A".into(), vec!["B".to_string()]));
+        // Nothing but separators leaves the value as it is
+        assert_eq!(split(" / "), ("/".into(), vec![]));
+    }
+
+    #[test]
+    fn parse_cue_sheet_reads_files_and_track_times() {
+        let sheet = "REM GENRE Rock
+PERFORMER \"Album Artist\"
+TITLE \"Album Title\"
+FILE \"Disc One.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"First\"
+    INDEX 00 00:00:00
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Second\"
+    PERFORMER \"Guest\"
+    INDEX 01 03:20:37
+  TRACK 03 MODE1/2352
+    INDEX 01 05:00:00
+  TRACK 04 AUDIO
+    TITLE \"No Index\"
+FILE disc2.wav WAVE
+  track 01 audio
+    index 01 01:00:74
+FILE \"Empty.flac\" WAVE
+";
+        let files = parse_cue_sheet(sheet);
+        let names = files.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Disc One.flac", "disc2.wav"]);
+
+        let tracks = &files[0].1;
+        assert_eq!(tracks.len(), 2);
+        assert_eq!((tracks[0].number, tracks[0].start_ms), (1, 0));
+        assert_eq!(tracks[0].end_ms, Some(200_493));
+        assert_eq!(tracks[0].title.as_deref(), Some("First"));
+        assert_eq!(tracks[0].performer.as_deref(), Some("Album Artist"));
+        assert_eq!(tracks[0].album.as_deref(), Some("Album Title"));
+        assert_eq!((tracks[1].number, tracks[1].start_ms), (2, 200_493));
+        assert_eq!(tracks[1].end_ms, None);
+        assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+
+        let tracks = &files[1].1;
+        assert_eq!(tracks.len(), 1);
+        assert_eq!((tracks[0].start_ms, tracks[0].end_ms), (60_986, None));
+        assert_eq!(tracks[0].title, None);
+    }
+
+    #[test]
+    fn parse_cue_sheet_ignores_text_that_is_not_a_sheet() {
+        assert!(parse_cue_sheet("").is_empty());
+        assert!(parse_cue_sheet("TRACK 01 AUDIO\nINDEX 01 00:00:00\n").is_empty());
+        assert!(parse_cue_sheet("FILE a.flac WAVE\nTRACK 01 AUDIO\nINDEX 01 0:xx:00\n").is_empty());
+    }
+
+    #[test]
+    fn title_from_file_name_reads_the_default_pattern() {
+        let settings = test_settings();
+        let title = |file_name| title_from_file_name(&settings, file_name);
+        assert_eq!(title("03 - Title"), ("Title".into(), Some(3)));
+        assert_eq!(title("3. Title"), ("Title".into(), Some(3)));
+        assert_eq!(title("103 Title - Live"), ("Title - Live".into(), Some(103)));
+        assert_eq!(title("1979"), ("1979".into(), None));
+        assert_eq!(title("Title"), ("Title".into(), None));
+        assert_eq!(title("03 -  "), ("03 -  ".into(), None));
+    }
+
+    #[test]
+    fn title_from_file_name_follows_the_configured_pattern() {
+        let mut settings = test_settings();
+        settings.title_pattern = parse_title_pattern(r"^(?P<artist>.+?) - (?P<title>.+)$").unwrap();
+        assert_eq!(
+            title_from_file_name(&settings, "Artist - Title"),
+            ("Title".into(), None)
+        );
+        settings.title_pattern = parse_title_pattern(" ").unwrap();
+        assert!(settings.title_pattern.is_none());
+        assert_eq!(
+            title_from_file_name(&settings, "03 - Title"),
+            ("03 - Title".into(), None)
+        );
+    }
+
+    #[test]
+    fn parse_title_pattern_needs_a_title_group() {
+        assert!(parse_title_pattern(r"^(?P<track>\d+) (?P<title>.+)$").unwrap().is_some());
+        assert!(parse_title_pattern(r"^(?P<track>\d+) (.+)$").is_err());
+        assert!(parse_title_pattern(r"^(?P<title>.+").is_err());
+    }
+
+    #[test]
+    fn audio_mime_type_knows_common_extensions() {
+        assert_eq!(audio_mime_type("mp3"), "audio/mpeg");
+        assert_eq!(audio_mime_type("FLAC"), "audio/flac");
+        assert_eq!(audio_mime_type("oga"), "audio/ogg");
+        assert_eq!(audio_mime_type("m4a"), "audio/mp4");
+        assert_eq!(audio_mime_type("aiff"), "audio/aiff");
+        assert_eq!(audio_mime_type("xyz"), "application/octet-stream");
+        assert_eq!(audio_mime_type(""), "application/octet-stream");
+    }
+
+    #[test]
+    fn sanitize_filename_replaces_unsafe_characters() {
+        assert_eq!(sanitize_filename("Artist - Album"), "Artist - Album");
+        assert_eq!(sanitize_filename("AC/DC: Live?"), "AC_DC_ Live_");
+        assert_eq!(sanitize_filename(r#"a\b*c"d<e>f|g"#), "a_b_c_d_e_f_g");
+        assert_eq!(sanitize_filename("line\r\nbreak\t"), "line__break_");
+        assert_eq!(sanitize_filename("  Björk  "), "Björk");
+    }
+
+    #[test]
+    fn parse_gain_reads_replaygain_values() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain("+1.20dB"), Some(1.2));
+        assert_eq!(parse_gain(" 0.5 "), Some(0.5));
+        assert_eq!(parse_gain("-3 db"), Some(-3.0));
+        assert_eq!(parse_gain("dB"), None);
+        assert_eq!(parse_gain("loud"), None);
+    }
+
+    #[test]
+    fn relative_path_string_joins_names_with_slashes() {
+        assert_eq!(
+            relative_path_string(Path::new("Artist/Album/01 - Song.flac")).as_deref(),
+            Some("Artist/Album/01 - Song.flac")
+        );
+        assert_eq!(
+            relative_path_string(Path::new("./Artist//Album/")).as_deref(),
+            Some("Artist/Album")
+        );
+    }
+
+    #[test]
+    fn paths_built_with_the_platform_separator_are_stored_with_slashes() {
+        let sep = std::path::MAIN_SEPARATOR;
+        let rel_path = format!("Artist{0}Album{0}Disc 1{0}01 - Song.flac", sep);
+        let stored = "Artist/Album/Disc 1/01 - Song.flac";
+        assert_eq!(relative_path_string(Path::new(&rel_path)).as_deref(), Some(stored));
+        let song = parse(&test_settings(), &rel_path);
+        assert_eq!(song.relative_path, stored);
+        // And the stored form opens the file on this platform
+        let lib = tempfile::tempdir().unwrap();
+        write_song(lib.path(), &rel_path);
+        let resolved = resolve_in_library(&test_settings(), lib.path(), Path::new(stored));
+        assert!(resolved.unwrap().is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_listed_lossily_and_opened_by_their_real_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let rel_path = Path::new("Artist/Album")
+            .join(std::ffi::OsStr::from_bytes(b"Caf\xe9.flac"));
+        assert_eq!(relative_path_string(&rel_path), None);
+        assert_eq!(lossy_relative_path(&rel_path), "Artist/Album/Caf\u{fffd}.flac");
+
+        let song = parse_path(&test_settings(), &rel_path).unwrap();
+        assert_eq!(song.relative_path, "Artist/Album/Caf\u{fffd}.flac");
+        assert_eq!(song.raw_path.as_deref(), Some(rel_path.as_path()));
+        assert_eq!(parse(&test_settings(), "Artist/Album/Café.flac").raw_path, None);
+    }
+
+    #[test]
+    fn resolve_in_library_refuses_paths_outside_the_library() {
+        use actix_web::ResponseError;
+
+        let top = tempfile::tempdir().unwrap();
+        let lib = top.path().join("lib");
+        write_song(&lib, "Artist/Album/1.mp3");
+        write_song(top.path(), "secret.mp3");
+        let follow = Settings {
+            follow_symlinks: true,
+            ..test_settings()
+        };
+
+        for settings in [test_settings(), follow] {
+            let resolved = resolve_in_library(&settings, &lib, Path::new("Artist/Album/1.mp3"));
+            assert!(resolved.unwrap().ends_with("lib/Artist/Album/1.mp3"));
+            for stored in ["../secret.mp3", "Artist/../../secret.mp3"] {
+                let err = resolve_in_library(&settings, &lib, Path::new(stored)).unwrap_err();
+                assert!(matches!(err, GenError::Forbidden(_)), "{}", stored);
+                assert_eq!(err.status_code(), 403);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_in_library_follows_links_out_only_with_follow_symlinks() {
+        let top = tempfile::tempdir().unwrap();
+        let lib = top.path().join("lib");
+        write_song(&lib, "Artist/Album/1.mp3");
+        write_song(top.path(), "elsewhere/Album/1.mp3");
+        std::os::unix::fs::symlink(top.path().join("elsewhere"), lib.join("Linked")).unwrap();
+        let stored = Path::new("Linked/Album/1.mp3");
+
+        let err = resolve_in_library(&test_settings(), &lib, stored).unwrap_err();
+        assert!(matches!(err, GenError::Forbidden(_)));
+        let follow = Settings {
+            follow_symlinks: true,
+            ..test_settings()
+        };
+        let resolved = resolve_in_library(&follow, &lib, stored).unwrap();
+        assert!(resolved.ends_with("elsewhere/Album/1.mp3"));
+    }
+
+    #[tokio::test]
+    async fn find_or_create_song_on_two_connections_makes_one_row() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = test_db(data.path()).await;
+        write_song(lib.path(), "Artist/Album/1.mp3");
+        let song = parse(&test_settings(), "Artist/Album/1.mp3").with_id(0);
+        let mut first = db.acquire().await.unwrap();
+        let mut second = db.acquire().await.unwrap();
+
+        let (a, b) = tokio::join!(
+            find_or_create_song(&mut first, &song, lib.path()),
+            find_or_create_song(&mut second, &song, lib.path()),
+        );
+        let ids = [a.unwrap(), b.unwrap()].map(|lookup| match lookup {
+            SongLookup::Created(id) => (true, id),
+            SongLookup::Existing(id) => (false, id),
+            _ => panic!("neither created nor found"),
+        });
+        assert_eq!(ids[0].1, ids[1].1);
+        assert!(ids[0].0 != ids[1].0, "exactly one call creates the row");
+        let rows: i64 = sqlx::query_scalar("select count(*) from filesystem_artifacts")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    #[tokio::test]
+    async fn crawl_dir_finds_every_file_of_a_few_thousand() {
+        let lib = tempfile::tempdir().unwrap();
+        let mut expected = Vec::new();
+        for artist in 0..20 {
+            for album in 0..10 {
+                for track in 0..15 {
+                    let path = format!("Artist {}/Album {}/{:02} Song.mp3", artist, album, track);
+                    write_song(lib.path(), &path);
+                    expected.push(path);
+                }
+            }
+        }
+        expected.sort();
+
+        let songs = crawl_dir(&test_settings(), lib.path(), lib.path()).await.unwrap();
+        let mut found = songs.into_iter().map(|s| s.relative_path).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found.len(), 3000);
+        assert_eq!(found, expected);
+    }
+
+    /// The crawl as a plain blocking walk, for comparing against the async one
+    fn walk_sync(settings: &Settings, base: &Path, dir: &Path, found: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                if !settings.ignored_dirs.contains(&name) {
+                    walk_sync(settings, base, &path, found);
+                }
+            } else if path.extension().is_some_and(|ext| {
+                let ext = ext.to_string_lossy();
+                settings.allowed_extensions.iter().any(|e| e.eq_ignore_ascii_case(&ext))
+            }) {
+                let rel = path.strip_prefix(base).unwrap();
+                found.push(rel.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn crawl_dir_finds_what_a_blocking_walk_finds() {
+        let lib = tempfile::tempdir().unwrap();
+        for path in [
+            "Artist/Album/01 One.mp3",
+            "Artist/Album/02 Two.FLAC",
+            "Artist/Album/cover.jpg",
+            "Artist/Album/notes.txt",
+            "Artist/Album/.hidden.mp3",
+            "Artist/Box Set/CD1/01 One.ogg",
+            "Artist/Box Set/CD2/01 One.opus",
+            "Artist/Box Set/CD2/Bonus/Deeper/01 Demo.wav",
+            "Other/Single/Song.m4a",
+            "Other/.stash/Song.mp3",
+            "Other/.git/Song.mp3",
+            "Loose.mp3",
+            "Readme.md",
+        ] {
+            write_song(lib.path(), path);
+        }
+        fs::create_dir_all(lib.path().join("Empty/Album")).unwrap();
+        let settings = test_settings();
+
+        let mut expected = Vec::new();
+        walk_sync(&settings, lib.path(), lib.path(), &mut expected);
+        expected.sort();
+        let songs = crawl_dir(&settings, lib.path(), lib.path()).await.unwrap();
+        let mut found = songs.into_iter().map(|s| s.relative_path).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(expected.len(), 7);
+        assert_eq!(found, expected);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn crawl_dir_skips_an_unreadable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let lib = tempfile::tempdir().unwrap();
+        write_song(lib.path(), "Artist/Album/1.mp3");
+        write_song(lib.path(), "Artist/Locked/1.mp3");
+        write_song(lib.path(), "Other/Album/1.mp3");
+        let locked = lib.path().join("Artist/Locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // Root reads it anyway, as in most containers
+        let readable = fs::read_dir(&locked).is_ok();
+
+        let songs = crawl_dir(&test_settings(), lib.path(), lib.path()).await;
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut found = songs.unwrap().into_iter().map(|s| s.relative_path).collect::<Vec<_>>();
+        found.sort();
+        let mut expected = vec!["Artist/Album/1.mp3", "Other/Album/1.mp3"];
+        if readable {
+            expected.insert(1, "Artist/Locked/1.mp3");
+        }
+        assert_eq!(found, expected);
+    }
 }
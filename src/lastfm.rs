@@ -0,0 +1,193 @@
+use std::env::var;
+use std::fmt::Write;
+
+use md5::{Digest, Md5};
+use serde_json::Value;
+
+use crate::errors::GenError;
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// A track as submitted to `track.scrobble`
+pub struct Scrobble<'a> {
+    pub artist: &'a str,
+    pub track: &'a str,
+    pub album: Option<&'a str>,
+    pub track_number: Option<u16>,
+    pub duration: Option<u32>,
+    /// Unix time the track started playing
+    pub timestamp: i64,
+}
+
+pub struct Lastfm {
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+    client: reqwest::Client,
+}
+
+impl Lastfm {
+    /// `None` unless `LASTFM_API_KEY`, `LASTFM_API_SECRET` and `LASTFM_SESSION` are all set
+    pub fn from_env() -> Option<Self> {
+        let vars = (
+            var("LASTFM_API_KEY"),
+            var("LASTFM_API_SECRET"),
+            var("LASTFM_SESSION"),
+        );
+        match vars {
+            (Ok(api_key), Ok(api_secret), Ok(session_key)) => Some(Self {
+                api_key,
+                api_secret,
+                session_key,
+                client: reqwest::Client::new(),
+            }),
+            (Err(_), Err(_), Err(_)) => None,
+            _ => {
+                log::warn!(
+                    "Last.fm scrobbling needs LASTFM_API_KEY, LASTFM_API_SECRET and LASTFM_SESSION, disabling it"
+                );
+                None
+            }
+        }
+    }
+
+    /// The form fields of a signed `track.scrobble` call
+    pub fn scrobble_params(&self, scrobble: &Scrobble) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("method", "track.scrobble".to_string()),
+            ("api_key", self.api_key.clone()),
+            ("sk", self.session_key.clone()),
+            ("artist", scrobble.artist.to_string()),
+            ("track", scrobble.track.to_string()),
+            ("timestamp", scrobble.timestamp.to_string()),
+        ];
+        if let Some(album) = scrobble.album {
+            params.push(("album", album.to_string()));
+        }
+        if let Some(number) = scrobble.track_number {
+            params.push(("trackNumber", number.to_string()));
+        }
+        if let Some(duration) = scrobble.duration {
+            params.push(("duration", duration.to_string()));
+        }
+        let signature = sign(&params, &self.api_secret);
+        params.push(("api_sig", signature));
+        // Not part of the signature
+        params.push(("format", "json".to_string()));
+        params
+    }
+
+    pub async fn scrobble(&self, scrobble: &Scrobble<'_>) -> Result<(), GenError> {
+        let params = self.scrobble_params(scrobble);
+        let response = self
+            .client
+            .post(API_URL)
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| bad_gateway(format!("could not reach Last.fm: {}", e)))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| bad_gateway(format!("could not read Last.fm response: {}", e)))?;
+        // Errors come back as `{"error": 9, "message": "Invalid session key"}`, not always with
+        // an error status
+        let json = serde_json::from_slice::<Value>(&body).unwrap_or_default();
+        if let Some(code) = json.get("error") {
+            let message = json
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error");
+            return Err(bad_gateway(format!("Last.fm error {}: {}", code, message)));
+        }
+        if !status.is_success() {
+            return Err(bad_gateway(format!("Last.fm responded with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+/// `api_sig`: md5 of every `{name}{value}` sorted by name, followed by the shared secret
+pub fn sign(params: &[(&str, String)], secret: &str) -> String {
+    let mut sorted = params.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(name, _)| *name);
+    let mut hasher = Md5::new();
+    for (name, value) in sorted {
+        hasher.update(name.as_bytes());
+        hasher.update(value.as_bytes());
+    }
+    hasher.update(secret.as_bytes());
+    hasher.finalize().iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+fn bad_gateway(message: String) -> GenError {
+    log::error!("Scrobble failed: {}", message);
+    GenError::BadGateway(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lastfm() -> Lastfm {
+        Lastfm {
+            api_key: "key".into(),
+            api_secret: "secret".into(),
+            session_key: "session".into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    #[test]
+    fn scrobble_params_are_signed_over_every_field_but_format() {
+        let scrobble = Scrobble {
+            artist: "Artist",
+            track: "Song",
+            album: Some("Album"),
+            track_number: Some(3),
+            duration: Some(215),
+            timestamp: 1_700_000_000,
+        };
+        let params = lastfm().scrobble_params(&scrobble);
+        let expected = [
+            ("method", "track.scrobble"),
+            ("api_key", "key"),
+            ("sk", "session"),
+            ("artist", "Artist"),
+            ("track", "Song"),
+            ("timestamp", "1700000000"),
+            ("album", "Album"),
+            ("trackNumber", "3"),
+            ("duration", "215"),
+            // md5 of albumAlbumapi_keykey...trackNumber3secret
+            ("api_sig", "1d81969231d19740052c946645aa5fdb"),
+            ("format", "json"),
+        ];
+        let params = params.iter().map(|(k, v)| (*k, v.as_str())).collect::<Vec<_>>();
+        assert_eq!(params, expected);
+    }
+
+    #[test]
+    fn scrobble_params_leave_out_missing_fields_and_sign_utf8() {
+        let scrobble = Scrobble {
+            artist: "Björk",
+            track: "Jóga",
+            album: None,
+            track_number: None,
+            duration: None,
+            timestamp: 1_700_000_000,
+        };
+        let params = lastfm().scrobble_params(&scrobble);
+        let names = params.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["method", "api_key", "sk", "artist", "track", "timestamp", "api_sig", "format"]
+        );
+        let (_, sig) = params.iter().find(|(k, _)| *k == "api_sig").unwrap();
+        assert_eq!(sig, "3c6a07a3f3b76a4b5080ac35d44eca63");
+    }
+}
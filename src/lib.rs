@@ -1,5 +1,14 @@
+pub mod auth;
+pub mod cover_cache;
 pub mod state;
 pub mod errors;
 pub mod file_utils;
+pub mod lastfm;
+pub mod lyrics;
+pub mod ogg;
+pub mod peaks;
+pub mod cue_bytes;
 pub mod db;
-pub mod types;
\ No newline at end of file
+pub mod transcode;
+pub mod types;
+pub mod watcher;
@@ -0,0 +1,157 @@
+//! Parsing of LRC lyrics, as found in `.lrc` files next to songs and sometimes in their tags
+
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct LyricLine {
+    /// When the line starts being sung, `None` for lyrics without timestamps
+    pub time_ms: Option<u64>,
+    pub text: String,
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss.xxx` in milliseconds
+fn parse_timestamp(tag: &str) -> Option<u64> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    // Some writers separate the hundredths with a second colon
+    let (seconds, fraction) = match seconds.split_once(['.', ':']) {
+        Some((seconds, fraction)) => (seconds, fraction),
+        None => (seconds, ""),
+    };
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let valid_fraction = fraction.is_empty() || all_digits(fraction);
+    if !all_digits(minutes) || !all_digits(seconds) || !valid_fraction {
+        return None;
+    }
+    let fraction_ms = match fraction.len() {
+        0 => 0,
+        len => {
+            let digits: u64 = fraction[..len.min(3)].parse().ok()?;
+            digits * 10u64.pow(3 - len.min(3) as u32)
+        }
+    };
+    let (minutes, seconds) = (minutes.parse::<u64>().ok()?, seconds.parse::<u64>().ok()?);
+    Some(minutes * 60_000 + seconds * 1000 + fraction_ms)
+}
+
+/// The lines of `text` in the order they are sung. A line with several timestamps is repeated
+/// at each, `[offset:ms]` shifts them all, and other ID tags such as `[ar:...]` are dropped.
+/// Text without any timestamps comes back line by line with `time_ms` left unset
+pub fn parse_lrc(text: &str) -> Vec<LyricLine> {
+    let mut offset_ms = 0i64;
+    let mut timed = Vec::new();
+    let mut untimed = Vec::new();
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+        let mut tagged = false;
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            if let Some(time) = parse_timestamp(tag.trim()) {
+                times.push(time);
+            } else if let Some((key, value)) = tag.split_once(':') {
+                if key.trim().eq_ignore_ascii_case("offset") {
+                    offset_ms = value.trim().parse().unwrap_or(0);
+                }
+            } else {
+                break;
+            }
+            tagged = true;
+            rest = after;
+        }
+        let text = rest.trim().to_string();
+        if !times.is_empty() {
+            timed.extend(times.into_iter().map(|time| (time, text.clone())));
+        } else if !tagged {
+            untimed.push(text);
+        }
+    }
+    if timed.is_empty() {
+        // Blank lines separate verses, but not at the ends
+        let start = untimed.iter().position(|l| !l.is_empty()).unwrap_or(untimed.len());
+        let end = untimed.iter().rposition(|l| !l.is_empty()).map_or(start, |i| i + 1);
+        return untimed
+            .drain(start..end)
+            .map(|text| LyricLine {
+                time_ms: None,
+                text,
+            })
+            .collect();
+    }
+    // A positive offset shows the lyrics sooner
+    timed.sort_by_key(|(time, _)| *time);
+    timed
+        .into_iter()
+        .map(|(time, text)| LyricLine {
+            time_ms: Some((time as i64 - offset_ms).max(0) as u64),
+            text,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<(Option<u64>, String)> {
+        parse_lrc(text)
+            .into_iter()
+            .map(|l| (l.time_ms, l.text))
+            .collect()
+    }
+
+    #[test]
+    fn parse_lrc_orders_timed_lines() {
+        let text = "[ar:Artist]\n[ti:Title]\n\
+            [00:12.50]Second\n\
+            [00:01]First\n\
+            [00:20.1][01:02.345]Chorus\n\
+            [00:30:07]  Spaced  \n\
+            not a lyric line\n";
+        assert_eq!(
+            lines(text),
+            [
+                (Some(1000), "First".to_string()),
+                (Some(12_500), "Second".to_string()),
+                (Some(20_100), "Chorus".to_string()),
+                (Some(30_070), "Spaced".to_string()),
+                (Some(62_345), "Chorus".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_lrc_applies_the_offset() {
+        let text = "[offset:+500]\n[00:00.20]Intro\n[00:02.00]Verse\n";
+        assert_eq!(
+            lines(text),
+            [(Some(0), "Intro".to_string()), (Some(1500), "Verse".to_string())]
+        );
+        let text = "[00:02.00]Verse\n[offset: -250]\n";
+        assert_eq!(lines(text), [(Some(2250), "Verse".to_string())]);
+    }
+
+    #[test]
+    fn parse_lrc_keeps_untimed_lyrics_line_by_line() {
+        let text = "\n\nFirst verse\nstill first\n\n[Chorus]\n\n";
+        assert_eq!(
+            lines(text),
+            [
+                (None, "First verse".to_string()),
+                (None, "still first".to_string()),
+                (None, String::new()),
+                (None, "[Chorus]".to_string()),
+            ]
+        );
+        assert!(lines("[ar:Artist]\n\n").is_empty());
+    }
+
+    #[test]
+    fn parse_timestamp_reads_each_precision() {
+        assert_eq!(parse_timestamp("01:02"), Some(62_000));
+        assert_eq!(parse_timestamp("01:02.3"), Some(62_300));
+        assert_eq!(parse_timestamp("01:02.34"), Some(62_340));
+        assert_eq!(parse_timestamp("01:02.3456"), Some(62_345));
+        for bad in ["ar:Artist", "1:x2", ":02", "01:", "01:02.3a"] {
+            assert_eq!(parse_timestamp(bad), None, "{}", bad);
+        }
+    }
+}
@@ -1,105 +1,886 @@
-#![feature(type_alias_impl_trait)]
+mod auth;
+mod cover_cache;
+mod cue_bytes;
 mod db;
 mod errors;
 mod file_utils;
+mod lastfm;
+mod lyrics;
+mod ogg;
+mod peaks;
 mod routes;
 mod state;
+mod transcode;
 mod types;
+mod watcher;
 
 use std::env::var;
-use std::path::Path;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_cors::Cors;
+use actix_web::{
+    guard,
+    http::header,
+    middleware::{self, Logger},
+    web, App, HttpServer,
+};
 use actix_web_static_files::ResourceFiles;
-use file_utils::{crawl_dir, scan_and_flag_missing, Settings};
-use routes::api;
-use sqlx::sqlite::SqlitePoolOptions;
-use types::Song;
+use cover_cache::CoverCache;
+use file_utils::{
+    load_library, parse_title_pattern, scan_and_flag_missing, LooseFilePolicy, PathLayout,
+    Settings, DEFAULT_TITLE_PATTERN,
+};
+use routes::{api, health};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use socket2::{Domain, Protocol, Socket, Type};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{Pool, Sqlite};
+use transcode::TranscodeProfile;
+use types::{ExportRecord, Song};
 
 use crate::{
-    file_utils::scan_for_unadded, routes::song::get_song, state::AppStateStruct,
+    errors::GenError,
+    file_utils::scan_for_unadded,
+    routes::song::{get_song, song_head},
+    state::AppStateStruct,
 };
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
+/// Largest `/api/import` body, roughly a few hundred thousand songs
+const IMPORT_LIMIT: usize = 256 * 1024 * 1024;
+
 #[tokio::main]
 async fn main() {
     if cfg!(debug_assertions) {
         dotenvy::dotenv().expect("Failed to load dotenv");
     }
 
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    init_logger();
 
     let lib_path = var("MUS_DIR").expect("MUS_DIR var is required");
-    let web_port_str: String = var("WEB_PORT").expect("WEB_PORT var is required");
-    let web_port: u16 = web_port_str.parse().expect("Could not parse web port");
-    let web_addr_string = var("WEB_ADDR").expect("WEB_ADDR var is required");
-    let web_addr = web_addr_string.as_str();
-    let start_path = Path::new(&lib_path);
-    println!("Loading library...");
-    let songs: Vec<Song> = tokio::task::block_in_place(|| {
-        let extns = ["ogg", "flac", "mp3", "wav"];
-        let settings = Settings {
-            allowed_extensions: extns.iter().map(|e| (**e).to_string()).collect(),
-        };
-        let mut songs = crawl_dir(&settings.allowed_extensions, start_path, start_path).unwrap();
-        songs.sort_unstable_by_key(|a| (a.artist.clone(), a.album.clone(), a.filename.clone()));
-        songs
-            .iter()
-            .enumerate()
-            .map(|ps| ps.1.with_id(ps.0 as u64))
-            .collect()
+    let listen_addrs = listen_addrs(var).unwrap_or_else(|e| {
+        log::error!("{}", e);
+        std::process::exit(1);
+    });
+    let tls = tls_config().unwrap_or_else(|e| {
+        log::error!("{}", e);
+        std::process::exit(1);
     });
-    println!("Done loading library. Loaded {} songs", songs.len());
+    let start_path = Path::new(&lib_path);
+    if !start_path.exists() || !start_path.is_dir() {
+        log::error!("MUS_DIR '{}' does not exist or is not a directory", lib_path);
+        std::process::exit(1);
+    }
+    log::info!("Loading library from {}", lib_path);
+    let allowed_extensions = match var("ALLOWED_EXTENSIONS") {
+        Ok(extns) => extns
+            .split(',')
+            .map(|e| {
+                let e = e.trim().to_lowercase();
+                if e.is_empty() {
+                    panic!("ALLOWED_EXTENSIONS contains an empty entry: '{}'", extns);
+                }
+                e
+            })
+            .collect(),
+        Err(_) => {
+            let extns = ["ogg", "opus", "flac", "mp3", "wav", "m4a", "aac"];
+            extns.iter().map(|e| (**e).to_string()).collect()
+        }
+    };
+    let settings = Settings {
+        allowed_extensions,
+        unknown_artist: var("UNKNOWN_ARTIST").unwrap_or_else(|_| "Unknown Artist".into()),
+        unknown_album: var("UNKNOWN_ALBUM").unwrap_or_else(|_| "Unknown Album".into()),
+        unknown_genre: var("UNKNOWN_GENRE").unwrap_or_else(|_| "Unknown".into()),
+        follow_symlinks: env_flag("FOLLOW_SYMLINKS"),
+        ignored_dirs: match var("IGNORED_DIRS") {
+            Ok(dirs) => dirs
+                .split(',')
+                .map(|d| d.trim().to_string())
+                .filter(|d| !d.is_empty())
+                .collect(),
+            Err(_) => [".git", "@eaDir", ".Trash"]
+                .iter()
+                .map(|d| (*d).to_string())
+                .collect(),
+        },
+        include_hidden: env_flag("INCLUDE_HIDDEN"),
+        scan_batch_size: match var("SCAN_BATCH_SIZE") {
+            Ok(size) => match size.parse() {
+                Ok(size) if size > 0 => size,
+                _ => panic!("SCAN_BATCH_SIZE '{}' is not a positive number", size),
+            },
+            Err(_) => 500,
+        },
+        cover_names: match var("COVER_NAMES") {
+            Ok(names) => names
+                .split(',')
+                .map(|n| n.trim().to_string())
+                .filter(|n| !n.is_empty())
+                .collect(),
+            Err(_) => ["cover.jpg", "cover.png", "folder.jpg", "folder.png", "front.jpg"]
+                .iter()
+                .map(|n| (*n).to_string())
+                .collect(),
+        },
+        path_layout: match var("PATH_LAYOUT") {
+            Ok(layout) => layout
+                .parse()
+                .unwrap_or_else(|e| panic!("PATH_LAYOUT is not a valid layout: {}", e)),
+            Err(_) => PathLayout::default(),
+        },
+        loose_file_policy: match var("LOOSE_FILES") {
+            Ok(policy) => policy
+                .parse()
+                .unwrap_or_else(|e| panic!("LOOSE_FILES is not a valid policy: {}", e)),
+            Err(_) => LooseFilePolicy::default(),
+        },
+        title_pattern: parse_title_pattern(
+            &var("TITLE_PATTERN").unwrap_or_else(|_| DEFAULT_TITLE_PATTERN.into()),
+        )
+        .unwrap_or_else(|e| panic!("TITLE_PATTERN is not a valid pattern: {}", e)),
+        min_file_size: match var("MIN_FILE_SIZE") {
+            Ok(size) => size
+                .trim()
+                .parse()
+                .unwrap_or_else(|e| panic!("MIN_FILE_SIZE '{}' is not a number of bytes: {}", size, e)),
+            Err(_) => 1024,
+        },
+        hide_paths: env_flag("HIDE_PATHS"),
+        stream_chunk_size: match var("STREAM_CHUNK_SIZE") {
+            Ok(size) => match size.trim().parse() {
+                Ok(size) if size > 0 => size,
+                _ => panic!("STREAM_CHUNK_SIZE '{}' is not a positive number of bytes", size),
+            },
+            Err(_) => 64 * 1024,
+        },
+        transcode_profile: match var("TRANSCODE") {
+            Ok(profile) => profile
+                .parse()
+                .unwrap_or_else(|e| panic!("TRANSCODE is not a valid profile: {}", e)),
+            Err(_) => TranscodeProfile::default(),
+        },
+        stream_idle_timeout: match var("STREAM_IDLE_TIMEOUT") {
+            Ok(secs) => match secs.trim().parse() {
+                Ok(0) => None,
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(e) => panic!(
+                    "STREAM_IDLE_TIMEOUT '{}' is not a number of seconds: {}",
+                    secs, e
+                ),
+            },
+            Err(_) => None,
+        },
+    };
+    if settings.loose_file_policy == LooseFilePolicy::Skip
+        && settings.path_layout.artist_and_album.is_none()
+    {
+        panic!("LOOSE_FILES=skip would leave out every file with the flat PATH_LAYOUT");
+    }
+    let songs: Vec<Song> = load_library(&settings, start_path)
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("Could not read library at '{}': {}", lib_path, e);
+            std::process::exit(1);
+        });
+    log::info!("Loaded {} songs from disk", songs.len());
 
-    let db_url = var("DATABASE_URL").expect("'DATABASE_URL is required");
+    let (connect_options, db_name) = db_options().unwrap_or_else(|e| {
+        log::error!("{}", e);
+        std::process::exit(1);
+    });
+    log::info!("Using database at {}", db_name);
+    let pool_size: u32 = match var("DB_POOL_SIZE") {
+        Ok(size) => size
+            .parse()
+            .unwrap_or_else(|e| panic!("DB_POOL_SIZE '{}' is not a valid number: {}", size, e)),
+        Err(_) => 5,
+    };
+    if pool_size < 1 {
+        panic!("DB_POOL_SIZE must be at least 1");
+    }
+    // Seconds in-flight requests, mostly song streams, get to finish once shutdown starts
+    let shutdown_timeout: u64 = match var("SHUTDOWN_TIMEOUT") {
+        Ok(secs) => secs.parse().unwrap_or_else(|e| {
+            panic!(
+                "SHUTDOWN_TIMEOUT '{}' is not a number of seconds: {}",
+                secs, e
+            )
+        }),
+        Err(_) => 30,
+    };
     let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect(&db_url)
+        .max_connections(pool_size)
+        .connect_with(connect_options)
         .await
         .expect("Could not connect to db");
 
-    let startup_res = scan_for_unadded(start_path, &songs, &pool).await;
-    match startup_res {
-        Ok(()) => {
-            println!("Startup succeeded");
-        }
-        Err(e) => {
-            println!("Error during startup scan {}", e);
+    if let Err(e) = sqlx::migrate!().run(&pool).await {
+        log::error!("Could not migrate database at {}: {}", db_name, e);
+        std::process::exit(1);
+    }
+
+    // Ahead of the startup scan, which would leave the database no longer empty
+    if let Ok(path) = var("IMPORT_FILE") {
+        match import_file(Path::new(&path), &pool).await {
+            Ok(Some(imported)) => log::info!("Imported {} library rows from '{}'", imported, path),
+            Ok(None) => log::info!("Not importing '{}', the library already has songs", path),
+            Err(e) => {
+                log::error!("Could not import '{}': {}", path, e);
+                std::process::exit(1);
+            }
         }
     }
 
-    let missing_res = scan_and_flag_missing(start_path, &pool).await;
-    match missing_res {
-        Ok(()) => {
-            println!("Task: Scan missing succeeded");
+    let state = std::sync::Arc::new(AppStateStruct::new(
+        lib_path.clone(),
+        settings,
+        lastfm::Lastfm::from_env(),
+        var("AUTH_TOKEN").ok().filter(|token| !token.is_empty()),
+        match var("MAX_STREAMS_PER_IP") {
+            Ok(max) => match max.trim().parse() {
+                Ok(max) if max > 0 => Some(max),
+                _ => panic!("MAX_STREAMS_PER_IP '{}' is not a positive number", max),
+            },
+            Err(_) => None,
         },
+        cover_cache(),
+    ));
+
+    let _watcher = match watcher::spawn_watcher(state.clone(), pool.clone()) {
+        Ok(watcher) => Some(watcher),
         Err(e) => {
-            println!("Task: Scan missing failed with error: {}", e);
+            log::warn!("Could not watch library for changes: {}", e);
+            None
         }
-    }
+    };
 
-    HttpServer::new(move || {
-        let cors = actix_cors::Cors::permissive();
-        let generated = generate();
-        let song_clone = songs.clone();
-        let state = std::sync::Arc::new(AppStateStruct::new(
-            lib_path.clone(),
-        ));
+    // Scan in the background so probes and the UI are reachable on large libraries,
+    // `/readyz` reports 503 until this finishes
+    let scan_state = state.clone();
+    let scan_pool = pool.clone();
+    tokio::spawn(async move {
+        let _scanning = scan_state.scan_lock.lock().await;
+        let base_path = Path::new(&scan_state.library_path);
+        let startup_res =
+            scan_for_unadded(&scan_state.settings, base_path, &songs, &scan_pool, false).await;
+        match startup_res {
+            Ok(summary) => {
+                log::info!(
+                    "Startup scan: {} files scanned, {} added, {} updated, {} unchanged, {} metadata rows saved",
+                    songs.len(),
+                    summary.added,
+                    summary.updated,
+                    summary.unchanged,
+                    summary.metadata_saved
+                );
+            }
+            Err(e) => {
+                log::error!("Startup scan failed: {}", e);
+            }
+        }
+
+        let missing_res = scan_and_flag_missing(&songs, &scan_pool).await;
+        match missing_res {
+            Ok(removed) => {
+                log::info!("Missing scan: {} flagged missing", removed);
+            },
+            Err(e) => {
+                log::error!("Missing scan failed: {}", e);
+            }
+        }
+        scan_state.scan_complete.store(true, Ordering::Release);
+    });
+
+    // Serve the UI from disk instead of the embedded bundle, e.g. while iterating on it
+    let ui_dir = std::env::var("UI_DIR").ok().filter(|dir| {
+        let index = std::path::Path::new(dir).join("index.html");
+        if !index.is_file() {
+            log::warn!(
+                "UI_DIR is set but '{}' does not exist, using the embedded UI",
+                index.display()
+            );
+        }
+        index.is_file()
+    });
+
+    // Same-origin only unless listed, e.g. `http://localhost:5173` for a separate frontend
+    let allowed_origins: Vec<String> = var("ALLOWED_ORIGINS")
+        .map(|origins| {
+            origins
+                .split(',')
+                .map(|o| o.trim().trim_end_matches('/').to_string())
+                .filter(|o| !o.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut server = HttpServer::new(move || {
+        let cors = api_cors(allowed_origins.clone());
+        let state = state.clone();
+        let ui_dir = ui_dir.clone();
 
         App::new()
+            .wrap(middleware::from_fn(health::require_scanned))
+            // Inside CORS, so preflight requests are answered without credentials
+            .wrap(middleware::from_fn(auth::require_token))
             .wrap(cors)
-            .wrap(Logger::default())
-            .service(web::resource("/api/songs").to(api::get_songs))
-            .service(get_song)
-            .service(ResourceFiles::new("/", generated))
+            // Song streams have their own access log with ranges and byte counts
+            .wrap(
+                Logger::new(r#"%a "%{request}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("request", auth::logged_request_line)
+                    .exclude_regex("^/song/")
+                    .exclude("/healthz")
+                    .exclude("/readyz"),
+            )
+            .configure(|cfg| configure_routes(cfg, ui_dir))
+            .default_service(web::to(routes::not_found))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| GenError::from(e).into()))
+            .app_data(web::PathConfig::default().error_handler(|e, _| GenError::from(e).into()))
             .app_data(web::Data::new(state))
-            .app_data(web::Data::new(song_clone))
             .app_data(web::Data::new(pool.clone()))
     })
-    .bind((web_addr, web_port))
-    .expect("Could not bind address")
-    .run()
-    .await
-    .expect("Could not start server")
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout);
+    for addr in listen_addrs {
+        let bound = match (addr, &tls) {
+            (SocketAddr::V6(v6), None) if v6.ip().is_unspecified() => {
+                dual_stack_listener(addr).and_then(|listener| server.listen(listener))
+            }
+            (SocketAddr::V6(v6), Some(tls)) if v6.ip().is_unspecified() => dual_stack_listener(addr)
+                .and_then(|listener| server.listen_rustls_0_23(listener, tls.clone())),
+            (_, None) => server.bind(addr),
+            (_, Some(tls)) => server.bind_rustls_0_23(addr, tls.clone()),
+        };
+        server = bound.unwrap_or_else(|e| {
+            log::error!("Could not bind {}: {}", addr, e);
+            std::process::exit(1);
+        });
+        log::info!(
+            "Listening on {}://{}",
+            if tls.is_some() { "https" } else { "http" },
+            addr
+        );
+    }
+    let server = server.run();
+
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!(
+            "Shutdown requested, no longer accepting connections; waiting up to {}s for in-flight requests",
+            shutdown_timeout
+        );
+        handle.stop(true).await;
+    });
+
+    server.await.expect("Could not start server");
+    log::info!("Shutdown complete");
+}
+
+/// Every route the server answers, the UI from `ui_dir` when given or else the embedded bundle
+fn configure_routes(cfg: &mut web::ServiceConfig, ui_dir: Option<String>) {
+    cfg.service(web::resource("/healthz").route(web::get().to(health::healthz)))
+        .service(web::resource("/readyz").route(web::get().to(health::readyz)))
+        // JSON compresses well, audio doesn't, so only the API is compressed
+        .service(
+            web::scope("/api")
+                .wrap(middleware::Compress::default())
+                .service(web::resource("/info").to(api::get_info))
+                .service(web::resource("/songs").to(api::get_songs))
+                .service(web::resource("/stats").to(api::get_stats))
+                .service(web::resource("/albums").to(api::get_album_list))
+                .service(
+                    web::resource("/album/{artist}/{album}/tracks").to(api::get_album),
+                )
+                .service(web::resource("/artists").to(api::get_artist_list))
+                .service(web::resource("/artist/{name}").to(api::get_artist))
+                .service(web::resource("/genres").to(api::get_genre_list))
+                .service(web::resource("/genre/{name}").to(api::get_genre))
+                .service(web::resource("/duplicates").to(api::get_duplicate_list))
+                .service(web::resource("/tag-conflicts").to(api::get_tag_conflict_list))
+                .service(web::resource("/scan-errors").to(api::get_scan_error_list))
+                .service(web::resource("/recently-played").to(api::get_recently_played))
+                .service(web::resource("/recently-added").to(api::get_recently_added_list))
+                .service(web::resource("/top-tracks").to(api::get_top_track_list))
+                .service(web::resource("/search").to(api::search))
+                .service(web::resource("/next").to(api::get_next))
+                .service(web::resource("/random").to(api::get_random))
+                .service(web::resource("/playlist.m3u").to(api::get_playlist))
+                .service(web::resource("/rescan").route(web::post().to(api::rescan)))
+                .service(web::resource("/reload").route(web::post().to(api::reload)))
+                .service(web::resource("/export").to(api::export))
+                .service(
+                    web::resource("/import")
+                        .app_data(
+                            web::JsonConfig::default()
+                                .limit(IMPORT_LIMIT)
+                                .error_handler(|e, _| GenError::from(e).into()),
+                        )
+                        .route(web::post().to(api::import)),
+                )
+                .service(web::resource("/song/{song_id}").to(api::get_song_details))
+                .service(web::resource("/song/{song_id}/cover").to(api::get_cover))
+                .service(web::resource("/song/{song_id}/lyrics").to(api::get_lyrics))
+                .service(web::resource("/song/{song_id}/peaks").to(api::get_peaks))
+                .service(
+                    web::resource("/song/{song_id}/favorite")
+                        .route(web::put().to(api::set_favorite))
+                        .route(web::delete().to(api::set_favorite)),
+                )
+                .service(web::resource("/favorites").to(api::get_favorite_list))
+                .service(
+                    web::resource("/scrobble/{song_id}").route(web::post().to(api::scrobble)),
+                )
+                .default_service(web::to(routes::not_found)),
+        )
+        .service(web::resource("/play/{song_id}").to(routes::player::play_page))
+        .service(get_song)
+        .service(song_head)
+        .configure(routes::subsonic::configure)
+        // The same page of songs as `/api/songs` for clients asking `/` for JSON
+        .service(
+            web::resource("/")
+                .guard(guard::Get())
+                .guard(guard::fn_guard(routes::accepts_json))
+                .to(api::get_songs),
+        )
+        .configure(|cfg| match ui_dir {
+            Some(dir) => {
+                cfg.service(
+                    actix_files::Files::new("/", dir)
+                        .index_file("index.html")
+                        .default_handler(web::to(routes::not_found)),
+                );
+            }
+            None => {
+                cfg.service(ResourceFiles::new("/", generate()).skip_handler_when_not_found());
+            }
+        });
+}
+
+/// `RUST_LOG` filters, defaulting to `info`. `LOG_FORMAT=json` writes one JSON object per
+/// line, for log shippers, instead of the human readable format
+fn init_logger() {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"));
+    match var("LOG_FORMAT").as_deref().map(str::trim) {
+        Ok("json") => {
+            builder.format(|buf, record| {
+                writeln!(buf, "{}", json_log_line(buf.timestamp_millis(), record))
+            });
+        }
+        Ok("text") | Err(_) => {}
+        Ok(other) => {
+            builder.init();
+            log::warn!("LOG_FORMAT '{}' is not json or text, using text", other);
+            return;
+        }
+    }
+    builder.init();
+}
+
+/// `record` as a single JSON object, which serde keeps on one line whatever the message holds
+fn json_log_line(ts: impl std::fmt::Display, record: &log::Record) -> serde_json::Value {
+    serde_json::json!({
+        "ts": ts.to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+}
+
+/// CORS for other origins' frontends, on `/api/` routes only. `*` allows any origin
+fn api_cors(allowed_origins: Vec<String>) -> Cors {
+    Cors::default()
+        .allowed_origin_fn(move |origin, head| {
+            head.uri.path().starts_with("/api/")
+                && allowed_origins
+                    .iter()
+                    .any(|allowed| allowed == "*" || origin.as_bytes() == allowed.as_bytes())
+        })
+        .allow_any_method()
+        .allow_any_header()
+        .expose_headers([header::CONTENT_DISPOSITION])
+        .max_age(3600)
+}
+
+/// An env var set to `1`, `true` or `yes`
+fn env_flag(name: &str) -> bool {
+    var(name).is_ok_and(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+/// The SQLite file at `DB_PATH`, else `DATABASE_URL`, else `musrs.db` in the XDG data
+/// directory, or `./music.db` without one. Also returns where that is, for logging.
+/// Journaled in WAL mode, so requests keep reading the last committed library while a scan
+/// writes to it
+fn db_options() -> Result<(SqliteConnectOptions, String), String> {
+    let path = match (var("DB_PATH"), var("DATABASE_URL")) {
+        (Ok(path), _) if !path.trim().is_empty() => PathBuf::from(path.trim()),
+        (_, Ok(url)) => {
+            let options = SqliteConnectOptions::from_str(&url)
+                .map_err(|e| format!("DATABASE_URL '{}' is not a valid sqlite url: {}", url, e))?
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal);
+            return Ok((options, format!("'{}'", url)));
+        }
+        _ => var("XDG_DATA_HOME")
+            .ok()
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").ok().map(|home| Path::new(&home).join(".local/share")))
+            .map_or_else(|| PathBuf::from("music.db"), |data| data.join("musrs/musrs.db")),
+    };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(|e| {
+            format!("Could not create database directory '{}': {}", dir.display(), e)
+        })?;
+    }
+    // SQLite only reports a read-only file or directory on the first write, well after startup
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| {
+            format!(
+                "Database '{}' is not writable: {}. Check the permissions of it and its directory",
+                path.display(),
+                e
+            )
+        })?;
+    let options = SqliteConnectOptions::new()
+        .filename(&path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal);
+    Ok((options, format!("'{}'", path.display())))
+}
+
+/// Loads an `/api/export` file into the database, `None` when it already has songs
+async fn import_file(path: &Path, pool: &Pool<Sqlite>) -> Result<Option<u64>, String> {
+    let json = std::fs::read(path).map_err(|e| e.to_string())?;
+    let records = serde_json::from_slice::<Vec<ExportRecord>>(&json).map_err(|e| e.to_string())?;
+    db::validate_import(&records)?;
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    db::import_library(&mut conn, &records).await.map_err(|e| e.to_string())
+}
+
+/// `CACHE_DIR`, or else `$XDG_CACHE_HOME/musrs` or `~/.cache/musrs`, with covers under
+/// `covers/`. Empty turns the disk cache off, as does a directory that can't be written to
+fn cover_cache() -> Option<CoverCache> {
+    let dir = match var("CACHE_DIR") {
+        Ok(dir) if dir.trim().is_empty() => return None,
+        Ok(dir) => PathBuf::from(dir.trim()),
+        Err(_) => var("XDG_CACHE_HOME")
+            .ok()
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").ok().map(|home| Path::new(&home).join(".cache")))?
+            .join("musrs"),
+    };
+    match CoverCache::open(dir.join("covers")) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            log::warn!("Not caching covers on disk, {}", e);
+            None
+        }
+    }
+}
+
+/// `LISTEN_ADDR`, e.g. `0.0.0.0:8080` or `[::]:8080`, or else `WEB_ADDR` and `WEB_PORT`, as
+/// `var` reads them from the environment
+fn listen_addrs(
+    var: impl Fn(&'static str) -> Result<String, std::env::VarError>,
+) -> Result<Vec<SocketAddr>, String> {
+    if let Ok(listen) = var("LISTEN_ADDR") {
+        return listen
+            .trim()
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect())
+            .map_err(|e| format!("LISTEN_ADDR '{}' is not a valid host:port: {}", listen, e));
+    }
+    let port = var("WEB_PORT").map_err(|_| "WEB_PORT is required unless LISTEN_ADDR is set")?;
+    let port = port
+        .trim()
+        .parse::<u16>()
+        .map_err(|e| format!("WEB_PORT '{}' is not a valid port: {}", port, e))?;
+    let addr = var("WEB_ADDR").map_err(|_| "WEB_ADDR is required unless LISTEN_ADDR is set")?;
+    // `[::]` is accepted as well as `::`
+    let host = addr.trim().trim_start_matches('[').trim_end_matches(']');
+    (host, port)
+        .to_socket_addrs()
+        .map(|addrs| addrs.collect())
+        .map_err(|e| format!("WEB_ADDR '{}' is not a valid address: {}", addr, e))
+}
+
+/// TLS from `TLS_CERT` and `TLS_KEY`, which must be set together, or `None` for plain HTTP.
+/// actix offers HTTP/2 as well as HTTP/1.1 over it
+fn tls_config() -> Result<Option<rustls::ServerConfig>, String> {
+    let (cert_path, key_path) = match (var("TLS_CERT"), var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => (cert, key),
+        (Err(_), Err(_)) => return Ok(None),
+        (Ok(_), Err(_)) => return Err("TLS_CERT is set but TLS_KEY is not; set both or neither".into()),
+        (Err(_), Ok(_)) => return Err("TLS_KEY is set but TLS_CERT is not; set both or neither".into()),
+    };
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            format!(
+                "Could not load TLS_CERT '{}': {}. Expected a PEM file of one or more \
+                 '-----BEGIN CERTIFICATE-----' blocks, server certificate first, then intermediates",
+                cert_path, e
+            )
+        })?;
+    if certs.is_empty() {
+        return Err(format!(
+            "TLS_CERT '{}' has no certificates. Expected a PEM file of one or more \
+             '-----BEGIN CERTIFICATE-----' blocks, server certificate first, then intermediates",
+            cert_path
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_file(&key_path).map_err(|e| {
+        format!(
+            "Could not load TLS_KEY '{}': {}. Expected an unencrypted PEM private key, \
+             either PKCS#8 ('BEGIN PRIVATE KEY'), PKCS#1 ('BEGIN RSA PRIVATE KEY') or SEC1 ('BEGIN EC PRIVATE KEY')",
+            key_path, e
+        )
+    })?;
+    let config =
+        rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Could not set up TLS: {}", e))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| {
+                format!(
+                    "TLS_CERT '{}' and TLS_KEY '{}' can not be used together: {}. \
+                     The key must be the one the first certificate was issued for",
+                    cert_path, key_path, e
+                )
+            })?;
+    Ok(Some(config))
+}
+
+/// Listens on the IPv6 wildcard address and, where the OS allows, IPv4 as well
+fn dual_stack_listener(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    if let Err(e) = socket.set_only_v6(false) {
+        log::warn!("Could not accept IPv4 connections on {}: {}", addr, e);
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Resolves on ctrl-c or, on unix, SIGTERM as sent by `docker stop`
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env::VarError;
+
+    fn listen_addrs_from(vars: &[(&str, &str)]) -> Result<Vec<SocketAddr>, String> {
+        let vars = vars.iter().copied().collect::<HashMap<_, _>>();
+        listen_addrs(|name| vars.get(name).map(|v| v.to_string()).ok_or(VarError::NotPresent))
+    }
+
+    #[test]
+    fn listen_addrs_prefers_listen_addr() {
+        let addrs = listen_addrs_from(&[
+            ("LISTEN_ADDR", " [::]:8080 "),
+            ("WEB_ADDR", "127.0.0.1"),
+            ("WEB_PORT", "9000"),
+        ]);
+        assert_eq!(addrs.unwrap(), ["[::]:8080".parse::<SocketAddr>().unwrap()]);
+        let addrs = listen_addrs_from(&[("LISTEN_ADDR", "0.0.0.0:80")]);
+        assert_eq!(addrs.unwrap(), ["0.0.0.0:80".parse::<SocketAddr>().unwrap()]);
+        assert!(listen_addrs_from(&[("LISTEN_ADDR", "0.0.0.0")]).is_err());
+    }
+
+    #[test]
+    fn listen_addrs_falls_back_to_web_addr_and_port() {
+        let addrs = listen_addrs_from(&[("WEB_ADDR", "127.0.0.1"), ("WEB_PORT", " 9000")]);
+        assert_eq!(addrs.unwrap(), ["127.0.0.1:9000".parse::<SocketAddr>().unwrap()]);
+        let addrs = listen_addrs_from(&[("WEB_ADDR", "[::]"), ("WEB_PORT", "9000")]);
+        assert_eq!(addrs.unwrap(), ["[::]:9000".parse::<SocketAddr>().unwrap()]);
+        let addrs = listen_addrs_from(&[("WEB_ADDR", "::1"), ("WEB_PORT", "9000")]);
+        assert_eq!(addrs.unwrap(), ["[::1]:9000".parse::<SocketAddr>().unwrap()]);
+
+        for vars in [
+            &[("WEB_ADDR", "127.0.0.1")][..],
+            &[("WEB_PORT", "9000")],
+            &[("WEB_ADDR", "127.0.0.1"), ("WEB_PORT", "99999")],
+            &[("WEB_ADDR", "127.0.0.1"), ("WEB_PORT", "web")],
+        ] {
+            assert!(listen_addrs_from(vars).is_err(), "{:?}", vars);
+        }
+    }
+
+    #[test]
+    fn json_log_line_writes_one_object_per_record() {
+        let message = "Song \"A\"\nfailed";
+        // format_args! lives only as long as the statement it's in
+        let line = json_log_line(
+            "2026-10-14T12:00:00.000Z",
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("musrs::watcher")
+                .args(format_args!("{}", message))
+                .build(),
+        )
+        .to_string();
+        assert!(!line.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&line).unwrap(),
+            serde_json::json!({
+                "ts": "2026-10-14T12:00:00.000Z",
+                "level": "WARN",
+                "target": "musrs::watcher",
+                "message": "Song \"A\"\nfailed",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn import_file_loads_an_export_only_into_an_empty_database() {
+        let data = tempfile::tempdir().unwrap();
+        let db = file_utils::test_db(data.path()).await;
+        let export = data.path().join("export.json");
+        let record = serde_json::json!([{
+            "relative_path": "Artist/Album/1.mp3",
+            "file_name": "1",
+            "file_extension": "mp3",
+            "is_present": true,
+            "first_path_segment": "Artist",
+            "second_path_segment": "Album",
+            "path_inferred": false,
+            "created_at": 1,
+            "updated_at": null,
+            "file_mtime": 1,
+            "file_size": 2000,
+            "content_hash": null,
+            "cue_track": null,
+            "cue_start_ms": null,
+            "cue_end_ms": null,
+            "metadata": null
+        }]);
+        std::fs::write(&export, record.to_string()).unwrap();
+
+        assert_eq!(import_file(&export, &db).await, Ok(Some(1)));
+        assert_eq!(import_file(&export, &db).await, Ok(None));
+        std::fs::write(&export, "[{}]").unwrap();
+        assert!(import_file(&export, &db).await.is_err());
+        assert!(import_file(&data.path().join("missing.json"), &db).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn only_api_responses_are_compressed() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = file_utils::test_db(data.path()).await;
+        let settings = file_utils::test_settings();
+        std::fs::create_dir_all(lib.path().join("Artist/Album")).unwrap();
+        std::fs::write(lib.path().join("Artist/Album/01 Song.mp3"), [0u8; 1000]).unwrap();
+        file_utils::rescan_library(&settings, lib.path(), &db, false)
+            .await
+            .unwrap();
+        let library_path = lib.path().to_string_lossy().into_owned();
+        let state = AppStateStruct::new(library_path, settings, None, None, None, None);
+        let app = test::init_service(
+            App::new()
+                .configure(|cfg| configure_routes(cfg, None))
+                .app_data(web::Data::new(Arc::new(state)))
+                .app_data(web::Data::new(db)),
+        )
+        .await;
+        let fetch = |uri: &str| {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::ACCEPT_ENCODING, "gzip"));
+            test::call_service(&app, request.to_request())
+        };
+
+        let resp = fetch("/api/songs").await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        let resp = fetch("/song/1").await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(test::read_body(resp).await.len(), 1000);
+    }
+
+    #[actix_web::test]
+    async fn api_cors_allows_listed_origins_on_the_api_only() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = file_utils::test_db(data.path()).await;
+        let library_path = lib.path().to_string_lossy().into_owned();
+        let settings = file_utils::test_settings();
+        let state = AppStateStruct::new(library_path, settings, None, None, None, None);
+        let app = test::init_service(
+            App::new()
+                .wrap(api_cors(vec!["http://localhost:5173".into()]))
+                .configure(|cfg| configure_routes(cfg, None))
+                .app_data(web::Data::new(Arc::new(state)))
+                .app_data(web::Data::new(db)),
+        )
+        .await;
+        let call = |request: test::TestRequest, origin: &str| {
+            let request = request.insert_header((header::ORIGIN, origin));
+            test::call_service(&app, request.to_request())
+        };
+        let allowed_origin = |headers: &header::HeaderMap| {
+            headers
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .map(|origin| origin.to_str().unwrap().to_string())
+        };
+
+        let resp = call(test::TestRequest::get().uri("/api/info"), "http://localhost:5173").await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(allowed_origin(resp.headers()).as_deref(), Some("http://localhost:5173"));
+
+        let preflight = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/songs")
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"));
+        let resp = call(preflight, "http://localhost:5173").await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(allowed_origin(resp.headers()).as_deref(), Some("http://localhost:5173"));
+
+        // Served, but without the header the browser needs to hand the response over
+        let resp = call(test::TestRequest::get().uri("/api/info"), "http://evil.example").await;
+        assert_eq!(allowed_origin(resp.headers()), None);
+        let resp = call(test::TestRequest::get().uri("/healthz"), "http://localhost:5173").await;
+        assert_eq!(allowed_origin(resp.headers()), None);
+    }
 }
@@ -0,0 +1,269 @@
+//! Just enough of the Ogg container to read the Vorbis comments and stream details of Opus
+//! and Vorbis files, which the tag readers behind `audiotags` don't understand
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Comment packets carry embedded cover art, so they can be large, but not unboundedly so
+const MAX_HEADER_PACKET: usize = 16 * 1024 * 1024;
+/// Longest an Ogg page can be, so the last page starts within this many bytes of the end
+const MAX_PAGE_SIZE: u64 = 27 + 255 + 255 * 255;
+/// Opus always decodes at 48kHz, whatever rate the source was
+const OPUS_RATE: u32 = 48_000;
+
+#[derive(Debug)]
+pub enum OggError {
+    Io(io::Error),
+    /// Not an Ogg stream, or one whose headers don't parse
+    Invalid(String),
+    /// An Ogg stream of a codec other than Opus and Vorbis, e.g. FLAC
+    UnsupportedCodec,
+}
+
+impl std::fmt::Display for OggError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OggError::Io(e) => write!(f, "{e}"),
+            OggError::Invalid(e) => write!(f, "invalid ogg stream: {e}"),
+            OggError::UnsupportedCodec => write!(f, "unsupported ogg codec"),
+        }
+    }
+}
+
+impl From<io::Error> for OggError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+pub struct OggInfo {
+    /// Vorbis comments in file order, keys uppercased
+    pub comments: Vec<(String, String)>,
+    pub duration_secs: Option<f64>,
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// Nominal bitrate in bits per second, as Vorbis headers may give
+    pub nominal_bitrate: Option<u32>,
+}
+
+impl OggInfo {
+    /// Every value of `key`, in file order
+    pub fn values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.comments
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn first(&self, key: &str) -> Option<&str> {
+        self.comments
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct PageHeader {
+    serial: u32,
+    lacing: Vec<u8>,
+}
+
+fn read_page_header(reader: &mut impl Read) -> Result<Option<PageHeader>, OggError> {
+    let mut header = [0u8; 27];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    if &header[..4] != b"OggS" {
+        return Err(OggError::Invalid("missing page capture pattern".into()));
+    }
+    let mut lacing = vec![0u8; header[26] as usize];
+    reader.read_exact(&mut lacing)?;
+    Ok(Some(PageHeader {
+        serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+        lacing,
+    }))
+}
+
+/// The first two packets of the file's first logical stream: its identification and
+/// comment headers
+fn read_header_packets(reader: &mut impl Read) -> Result<(u32, Vec<Vec<u8>>), OggError> {
+    let mut serial = None;
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut current = Vec::new();
+    while packets.len() < 2 {
+        let Some(page) = read_page_header(reader)? else {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        };
+        let body_len = page.lacing.iter().map(|l| *l as usize).sum::<usize>();
+        let mut body = vec![0u8; body_len];
+        reader.read_exact(&mut body)?;
+        // Pages of other multiplexed streams are skipped
+        if *serial.get_or_insert(page.serial) != page.serial {
+            continue;
+        }
+        let mut offset = 0;
+        for lace in page.lacing {
+            current.extend_from_slice(&body[offset..offset + lace as usize]);
+            offset += lace as usize;
+            if current.len() > MAX_HEADER_PACKET {
+                return Err(OggError::Invalid("header packet too large".into()));
+            }
+            if lace < 255 {
+                packets.push(std::mem::take(&mut current));
+                if packets.len() == 2 {
+                    break;
+                }
+            }
+        }
+    }
+    Ok((serial.unwrap_or_default(), packets))
+}
+
+/// `KEY=value` pairs of a comment header, after its codec's magic
+fn parse_comments(mut data: &[u8]) -> Result<Vec<(String, String)>, OggError> {
+    let invalid = || OggError::Invalid("truncated comment header".into());
+    let mut take = |n: usize| -> Result<&[u8], OggError> {
+        if data.len() < n {
+            return Err(invalid());
+        }
+        let (head, rest) = data.split_at(n);
+        data = rest;
+        Ok(head)
+    };
+    let len = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap()) as usize;
+    let vendor_len = len(take(4)?);
+    take(vendor_len)?;
+    let count = len(take(4)?);
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let comment_len = len(take(4)?);
+        let comment = String::from_utf8_lossy(take(comment_len)?);
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_ascii_uppercase(), value.to_string()));
+        }
+    }
+    Ok(comments)
+}
+
+/// The granule position of the stream's last page, which counts the samples in the file
+fn last_granule(file: &mut File, serial: u32) -> Result<Option<i64>, OggError> {
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(MAX_PAGE_SIZE);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.take(MAX_PAGE_SIZE).read_to_end(&mut tail)?;
+    let last = (0..tail.len().saturating_sub(27)).rev().find_map(|i| {
+        let page = &tail[i..i + 27];
+        if &page[..4] != b"OggS" || page[4] != 0 {
+            return None;
+        }
+        let granule = i64::from_le_bytes(page[6..14].try_into().unwrap());
+        let page_serial = u32::from_le_bytes(page[14..18].try_into().unwrap());
+        // -1 marks a page where no packet ends
+        (page_serial == serial && granule >= 0).then_some(granule)
+    });
+    Ok(last)
+}
+
+pub fn read_ogg(path: &Path) -> Result<OggInfo, OggError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (serial, packets) = read_header_packets(&mut reader)?;
+    let (ident, comment) = (&packets[0], &packets[1]);
+    let short = || OggError::Invalid("identification header too short".into());
+
+    let (sample_rate, channels, pre_skip, nominal_bitrate, comments) =
+        if let Some(head) = ident.strip_prefix(b"OpusHead") {
+            if head.len() < 11 {
+                return Err(short());
+            }
+            let Some(tags) = comment.strip_prefix(b"OpusTags") else {
+                return Err(OggError::Invalid("missing OpusTags header".into()));
+            };
+            let pre_skip = u16::from_le_bytes([head[2], head[3]]) as i64;
+            (OPUS_RATE, head[1], pre_skip, None, parse_comments(tags)?)
+        } else if let Some(head) = ident.strip_prefix(b"\x01vorbis") {
+            if head.len() < 17 {
+                return Err(short());
+            }
+            let Some(tags) = comment.strip_prefix(b"\x03vorbis") else {
+                return Err(OggError::Invalid("missing vorbis comment header".into()));
+            };
+            let sample_rate = u32::from_le_bytes(head[5..9].try_into().unwrap());
+            let nominal = i32::from_le_bytes(head[13..17].try_into().unwrap());
+            let nominal = (nominal > 0).then_some(nominal as u32);
+            (sample_rate, head[4], 0, nominal, parse_comments(tags)?)
+        } else {
+            return Err(OggError::UnsupportedCodec);
+        };
+
+    let mut file = reader.into_inner();
+    let duration_secs = match last_granule(&mut file, serial)? {
+        Some(granule) if sample_rate > 0 => {
+            Some((granule - pre_skip).max(0) as f64 / sample_rate as f64)
+        }
+        _ => None,
+    };
+    Ok(OggInfo {
+        comments,
+        duration_secs,
+        sample_rate,
+        channels,
+        nominal_bitrate,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Ogg page of stream 1 holding `packet`, which must fit in one lacing value
+    fn page(sequence: u32, granule: i64, packet: &[u8]) -> Vec<u8> {
+        assert!(packet.len() < 255);
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        // The checksum isn't checked
+        page.extend_from_slice(&[0; 4]);
+        page.extend_from_slice(&[1, packet.len() as u8]);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    #[test]
+    fn read_ogg_reads_the_comments_and_duration_of_an_opus_file() {
+        let pre_skip: u16 = 312;
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&44_100u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        let mut tags = b"OpusTags".to_vec();
+        let comments: [&[u8]; 2] = ["title=Größe".as_bytes(), b"ARTIST=Someone"];
+        tags.extend_from_slice(&4u32.to_le_bytes());
+        tags.extend_from_slice(b"test");
+        tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment);
+        }
+        // 183.5 seconds after the pre-skip
+        let granule = pre_skip as i64 + 48_000 * 1835 / 10;
+        let mut file = page(0, 0, &head);
+        file.extend(page(1, 0, &tags));
+        file.extend(page(2, granule, &[0xfc; 100]));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.opus");
+        std::fs::write(&path, file).unwrap();
+
+        let info = read_ogg(&path).unwrap();
+        assert_eq!(info.first("TITLE"), Some("Größe"));
+        assert_eq!(info.values("artist").collect::<Vec<_>>(), ["Someone"]);
+        assert_eq!(info.duration_secs, Some(183.5));
+        // Opus decodes at 48kHz whatever the input rate was
+        assert_eq!((info.sample_rate, info.channels), (48_000, 2));
+        assert_eq!(info.nominal_bitrate, None);
+    }
+}
@@ -0,0 +1,99 @@
+/// Peaks computed and cached per song; requests for fewer buckets are downsampled from these
+pub const PEAK_RESOLUTION: usize = 4000;
+pub const MAX_BUCKETS: usize = PEAK_RESOLUTION;
+/// Plenty to find peaks at the resolution a waveform is drawn at
+pub const DECODE_SAMPLE_RATE: u32 = 8000;
+
+/// The loudest absolute sample in each of `PEAK_RESOLUTION` equal slices of `samples`
+pub fn compute_peaks(samples: &[i16]) -> Vec<u16> {
+    (0..PEAK_RESOLUTION)
+        .map(|i| {
+            let start = i * samples.len() / PEAK_RESOLUTION;
+            let end = (i + 1) * samples.len() / PEAK_RESOLUTION;
+            samples[start..end]
+                .iter()
+                .map(|s| s.unsigned_abs())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+/// Folds cached peaks into `buckets` values between 0 and 1 of full scale
+pub fn downsample(peaks: &[u16], buckets: usize) -> Vec<f64> {
+    (0..buckets)
+        .map(|i| {
+            let start = i * peaks.len() / buckets;
+            let end = ((i + 1) * peaks.len() / buckets)
+                .max(start + 1)
+                .min(peaks.len());
+            let peak = peaks[start..end].iter().max().copied().unwrap_or(0);
+            (peak as f64 / 32768.0 * 10000.0).round() / 10000.0
+        })
+        .collect()
+}
+
+pub fn to_blob(peaks: &[u16]) -> Vec<u8> {
+    peaks.iter().flat_map(|p| p.to_le_bytes()).collect()
+}
+
+pub fn from_blob(blob: &[u8]) -> Vec<u16> {
+    // A trailing odd byte can't be a peak, so it's dropped
+    let (peaks, _) = blob.as_chunks::<2>();
+    peaks.iter().map(|p| u16::from_le_bytes(*p)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_peaks_of_nothing_is_silence() {
+        let peaks = compute_peaks(&[]);
+        assert_eq!(peaks.len(), PEAK_RESOLUTION);
+        assert!(peaks.iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn compute_peaks_of_fewer_samples_than_buckets_keeps_each_sample_once() {
+        let peaks = compute_peaks(&[1, -3, 2]);
+        assert_eq!(peaks.len(), PEAK_RESOLUTION);
+        let heard = peaks.iter().copied().filter(|p| *p != 0).collect::<Vec<_>>();
+        assert_eq!(heard, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn compute_peaks_takes_the_loudest_absolute_sample() {
+        let mut samples = vec![0i16; PEAK_RESOLUTION * 2 + 1];
+        samples[0] = 5;
+        samples[1] = i16::MIN;
+        let peaks = compute_peaks(&samples);
+        assert_eq!(peaks[0], 32768);
+        assert_eq!(peaks[1], 0);
+    }
+
+    #[test]
+    fn downsample_of_nothing_is_silence() {
+        assert_eq!(downsample(&[], 3), vec![0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn downsample_to_more_buckets_than_peaks_repeats_them() {
+        assert_eq!(
+            downsample(&[32768, 0, 16384], 6),
+            vec![1.0, 1.0, 0.0, 0.0, 0.5, 0.5]
+        );
+    }
+
+    #[test]
+    fn downsample_folds_an_odd_number_of_peaks() {
+        assert_eq!(downsample(&[0, 8192, 32768, 0, 16384], 2), vec![0.25, 1.0]);
+    }
+
+    #[test]
+    fn blobs_round_trip_and_drop_a_trailing_byte() {
+        let peaks = vec![0, 1, 513, u16::MAX];
+        assert_eq!(from_blob(&to_blob(&peaks)), peaks);
+        assert_eq!(from_blob(&[1, 0, 2]), vec![1]);
+    }
+}
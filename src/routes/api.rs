@@ -1,13 +1,1359 @@
-use crate::db::get_library;
+use crate::db::{
+    add_favorite, count_filtered_library, count_library, export_library, find_cover_path,
+    find_library_row, find_library_rows, find_peaks, find_song, find_track_metadata,
+    fuzzy_search_library, get_album_tracks, get_albums, get_artists, get_duplicates, get_favorites,
+    get_filtered_library, get_genre_songs, get_genres, get_library_stats, get_recent_plays,
+    get_recently_added, get_scan_errors, get_tag_conflicts, get_top_tracks, import_library,
+    next_song_id, random_song_ids, remove_favorite, save_cover_path, save_peaks, search_library,
+    validate_import,
+};
+use crate::cover_cache::CoverCache;
+use crate::errors::GenError;
+use crate::file_utils::{
+    read_cover, read_folder_cover, read_lyrics, read_sidecar_lyrics, reload_library,
+    rescan_library, resolve_in_library, unix_timestamp,
+};
+use crate::lastfm::Scrobble;
+use crate::lyrics;
+use crate::peaks::{self, DECODE_SAMPLE_RATE, MAX_BUCKETS};
+use crate::state::{AppState, CachedMeta};
+use crate::transcode::{decode_mono, Segment};
+use crate::types::{ExportRecord, QueueScope, ScanStatus, SongFilter, SongSort, SortOrder};
 use actix_web::{
     web::{self},
-    HttpResponse,
+    HttpRequest, HttpResponse,
 };
+use serde::Deserialize;
 use serde_json::json;
 use sqlx::{Pool, Sqlite};
+use std::path::Path;
+
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+const MAX_PAGE_LIMIT: u32 = 1000;
+const MAX_SEARCH_RESULTS: usize = 50;
+const DEFAULT_HISTORY_LIMIT: u32 = 50;
+const DEFAULT_PEAK_BUCKETS: usize = 1000;
+const MAX_RANDOM_SONGS: u32 = 100;
+
+#[derive(Deserialize)]
+pub struct PageParams {
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    /// 1-based page of `limit` songs, takes precedence over `offset`
+    pub page: Option<String>,
+    #[serde(default)]
+    pub sort: SongSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// The unknown genre's name lists songs without a genre
+    pub genre: Option<String>,
+    pub year: Option<i64>,
+}
+
+/// What this server is and serves, for clients to feature-detect and to check a deployment
+pub async fn get_info(state: web::Data<AppState>, db: web::Data<Pool<Sqlite>>) -> super::GenResponse {
+    let library_path = if state.settings.hide_paths {
+        Path::new(&state.library_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    } else {
+        state.library_path.clone()
+    };
+    let mut conn = db.acquire().await?;
+    let song_count = count_library(&mut conn).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "library_path": library_path,
+        "song_count": song_count,
+        "extensions": state.settings.allowed_extensions,
+    })))
+}
+
+pub async fn get_songs(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    page: web::Query<PageParams>,
+) -> super::GenResponse {
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    let non_empty = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+    let filter = SongFilter {
+        artist: non_empty(&page.artist),
+        album: non_empty(&page.album),
+        genre: non_empty(&page.genre).map(|genre| {
+            if genre.eq_ignore_ascii_case(&state.settings.unknown_genre) {
+                String::new()
+            } else {
+                genre
+            }
+        }),
+        year: page.year,
+        song_ids: None,
+    };
+    let mut conn = db.acquire().await?;
+    let total = count_filtered_library(&mut conn, &filter).await?;
+    let total_pages = ((total as u64).div_ceil(limit.max(1) as u64)).max(1);
+    let offset = match &page.page {
+        // Out of range or unparseable pages clamp instead of erroring
+        Some(p) => {
+            let p = p.trim().parse::<i64>().unwrap_or(1).clamp(1, total_pages as i64);
+            (p as u64 - 1) * limit as u64
+        }
+        None => page.offset.unwrap_or(0) as u64,
+    };
+    let songs = get_filtered_library(
+        &mut conn,
+        limit as i64,
+        offset as i64,
+        page.sort,
+        page.order,
+        &filter,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "songs": songs,
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "page": offset / limit.max(1) as u64 + 1,
+        "total_pages": total_pages,
+        "sort": page.sort,
+        "order": page.order,
+    })))
+}
+
+pub async fn get_stats(db: web::Data<Pool<Sqlite>>) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    Ok(HttpResponse::Ok().json(get_library_stats(&mut conn).await?))
+}
+
+#[derive(Deserialize)]
+pub struct AlbumParams {
+    pub artist: Option<String>,
+}
+
+pub async fn get_album_list(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<AlbumParams>,
+) -> super::GenResponse {
+    let loose = state.settings.loose_file_policy;
+    let albums = get_albums(&db, params.artist.as_deref(), loose).await?;
+    Ok(HttpResponse::Ok().json(json!({ "albums": albums })))
+}
+
+/// An album's tracks in disc and track order
+pub async fn get_album(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<(String, String)>,
+) -> super::GenResponse {
+    let (artist, album) = path.into_inner();
+    let loose = state.settings.loose_file_policy;
+    let Some((row, tracks)) = get_album_tracks(&db, &artist, &album, loose).await? else {
+        return Err(GenError::NotFound(format!("album '{}' by '{}' not found", album, artist)));
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "album": row,
+        "duration": tracks.iter().filter_map(|t| t.duration).sum::<u32>(),
+        "tracks": tracks,
+    })))
+}
+
+pub async fn get_artist_list(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+) -> super::GenResponse {
+    let artists = get_artists(&db, state.settings.loose_file_policy).await?;
+    Ok(HttpResponse::Ok().json(json!({ "artists": artists })))
+}
+
+pub async fn get_artist(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<String>,
+) -> super::GenResponse {
+    // Path segments arrive percent-decoded, so `/api/artist/Sigur%20R%C3%B3s` matches
+    let artist = path.into_inner();
+    let albums = get_albums(&db, Some(&artist), state.settings.loose_file_policy).await?;
+    if albums.is_empty() {
+        return Err(GenError::NotFound(format!("artist '{}' not found", artist)));
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "artist": albums[0].artist,
+        "albums": albums,
+    })))
+}
+
+pub async fn get_genre_list(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    let genres = get_genres(&mut conn, &state.settings.unknown_genre).await?;
+    Ok(HttpResponse::Ok().json(json!({ "genres": genres })))
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    /// Every remaining song when unset
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+pub async fn get_genre(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<String>,
+    params: web::Query<ListParams>,
+) -> super::GenResponse {
+    let genre = path.into_inner();
+    let mut conn = db.acquire().await?;
+    let songs = get_genre_songs(&mut conn, &genre, &state.settings.unknown_genre).await?;
+    if songs.is_empty() {
+        return Err(GenError::NotFound(format!("genre '{}' not found", genre)));
+    }
+    let offset = params.offset.unwrap_or(0) as usize;
+    let limit = params.limit.map_or(usize::MAX, |l| l as usize);
+    Ok(HttpResponse::Ok().json(json!({
+        "genre": genre,
+        "songs": super::paginate(&songs, offset, limit),
+        "total": songs.len(),
+        "offset": offset,
+    })))
+}
+
+pub async fn get_duplicate_list(db: web::Data<Pool<Sqlite>>) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    let duplicates = get_duplicates(&mut conn).await?;
+    Ok(HttpResponse::Ok().json(json!({ "duplicates": duplicates })))
+}
+
+pub async fn get_tag_conflict_list(db: web::Data<Pool<Sqlite>>) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    let conflicts = get_tag_conflicts(&mut conn).await?;
+    Ok(HttpResponse::Ok().json(json!({ "conflicts": conflicts })))
+}
+
+#[derive(Deserialize)]
+pub struct ScanErrorParams {
+    /// `read_error` unless given, `truncated` lists files that end early and `no_tags` files
+    /// that only lack tags
+    pub status: Option<ScanStatus>,
+}
+
+pub async fn get_scan_error_list(
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<ScanErrorParams>,
+) -> super::GenResponse {
+    let status = params.status.unwrap_or(ScanStatus::ReadError);
+    let mut conn = db.acquire().await?;
+    let files = get_scan_errors(&mut conn, status).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "status": status,
+        "files": files,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    pub limit: Option<u32>,
+}
+
+impl HistoryParams {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).min(MAX_PAGE_LIMIT) as i64
+    }
+}
+
+pub async fn get_recently_played(
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<HistoryParams>,
+) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    let plays = get_recent_plays(&mut conn, params.limit()).await?;
+    Ok(HttpResponse::Ok().json(json!({ "plays": plays })))
+}
+
+pub async fn get_recently_added_list(
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<HistoryParams>,
+) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    let songs = get_recently_added(&mut conn, params.limit()).await?;
+    Ok(HttpResponse::Ok().json(json!({ "songs": songs })))
+}
+
+pub async fn get_top_track_list(
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<HistoryParams>,
+) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    let tracks = get_top_tracks(&mut conn, params.limit()).await?;
+    Ok(HttpResponse::Ok().json(json!({ "tracks": tracks })))
+}
+
+/// A song's file details and, once scanned, its tags, without the audio
+pub async fn get_song_details(
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<u64>,
+) -> super::GenResponse {
+    let song_id = path.into_inner() as i64;
+    let mut conn = db.acquire().await?;
+    let Some(song) = find_song(&mut conn, song_id).await? else {
+        return Err(GenError::NotFound(format!("song {} not found", song_id)));
+    };
+    let metadata = find_track_metadata(&mut conn, song_id).await?;
+    Ok(HttpResponse::Ok().json(json!({ "song": song, "metadata": metadata })))
+}
+
+pub async fn get_favorite_list(db: web::Data<Pool<Sqlite>>) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    let songs = get_favorites(&mut conn).await?;
+    Ok(HttpResponse::Ok().json(json!({ "songs": songs })))
+}
+
+/// `PUT` marks the song as a favorite and `DELETE` unmarks it, either being safe to repeat
+pub async fn set_favorite(
+    request: HttpRequest,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<i64>,
+) -> super::GenResponse {
+    let song_id = path.into_inner();
+    let mut conn = db.acquire().await?;
+    if find_song(&mut conn, song_id).await?.is_none() {
+        return Err(GenError::NotFound(format!("song {} not found", song_id)));
+    }
+    let favorite = request.method() == actix_web::http::Method::PUT;
+    if favorite {
+        add_favorite(&mut conn, song_id, unix_timestamp()).await?;
+    } else {
+        remove_favorite(&mut conn, song_id).await?;
+    }
+    Ok(HttpResponse::Ok().json(json!({ "id": song_id, "is_favorite": favorite })))
+}
+
+#[derive(Deserialize)]
+pub struct RandomParams {
+    /// 1 unless given, at most [`MAX_RANDOM_SONGS`]
+    pub count: Option<u32>,
+    pub artist: Option<String>,
+    pub genre: Option<String>,
+}
+
+/// Distinct songs picked at random from the present ones, optionally of one artist and genre
+pub async fn get_random(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<RandomParams>,
+) -> super::GenResponse {
+    let count = params.count.unwrap_or(1).clamp(1, MAX_RANDOM_SONGS);
+    let non_empty = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+    let filter = SongFilter {
+        artist: non_empty(&params.artist),
+        genre: non_empty(&params.genre).map(|genre| {
+            if genre.eq_ignore_ascii_case(&state.settings.unknown_genre) {
+                String::new()
+            } else {
+                genre
+            }
+        }),
+        ..Default::default()
+    };
+    let mut conn = db.acquire().await?;
+    let ids = random_song_ids(&mut conn, count.into(), &filter).await?;
+    if ids.is_empty() && (filter.artist.is_some() || filter.genre.is_some()) {
+        return Err(GenError::NotFound("no songs match the artist and genre".into()));
+    }
+    let mut rows = find_library_rows(&mut conn, &ids).await?;
+    let picked = ids.iter().filter_map(|id| rows.remove(id)).collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(json!({ "songs": picked })))
+}
+
+#[derive(Deserialize)]
+pub struct NextParams {
+    pub after: i64,
+    #[serde(default)]
+    pub scope: QueueScope,
+    /// `1` to start the scope over after its last song instead of stopping
+    pub repeat: Option<String>,
+}
+
+/// The song after `after` in library order within the same album, artist or the whole
+/// library, skipping missing files. 204 once the scope runs out, unless `repeat` is set
+pub async fn get_next(
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<NextParams>,
+) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    if find_song(&mut conn, params.after).await?.is_none() {
+        return Err(GenError::NotFound(format!("song {} not found", params.after)));
+    }
+    let repeat = params
+        .repeat
+        .as_deref()
+        .is_some_and(|r| r == "1" || r == "true");
+    let next = match next_song_id(&mut conn, params.after, params.scope, repeat).await? {
+        Some(id) => find_library_row(&mut conn, id).await?,
+        None => None,
+    };
+    Ok(match next {
+        Some(song) => HttpResponse::Ok().json(json!({ "song": song })),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    pub q: Option<String>,
+    /// `1` to also match misspellings, ranking results by similarity
+    pub fuzzy: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+pub async fn search(
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<SearchParams>,
+) -> super::GenResponse {
+    let query = params.q.as_deref().unwrap_or("").trim();
+    if query.is_empty() {
+        return Err(GenError::BadRequest("q must not be empty".into()));
+    }
+    let fuzzy = params
+        .fuzzy
+        .as_deref()
+        .is_some_and(|f| f == "1" || f == "true");
+    let mut conn = db.acquire().await?;
+    let ids = if fuzzy {
+        fuzzy_search_library(&mut conn, query).await?
+    } else {
+        search_library(&mut conn, query).await?
+    };
+    let offset = params.offset.unwrap_or(0) as usize;
+    let limit = params
+        .limit
+        .map_or(MAX_SEARCH_RESULTS, |l| l as usize)
+        .min(MAX_SEARCH_RESULTS);
+    // Only the page's songs are read in full
+    let page = super::paginate(&ids, offset, limit);
+    let mut rows = find_library_rows(&mut conn, page).await?;
+    let songs = page.iter().filter_map(|id| rows.remove(id)).collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(json!({
+        "songs": songs,
+        "total": ids.len(),
+        "offset": offset,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct PlaylistParams {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+pub async fn get_playlist(
+    request: HttpRequest,
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<PlaylistParams>,
+) -> super::GenResponse {
+    let filter = SongFilter {
+        artist: params.artist.clone(),
+        album: params.album.clone(),
+        ..Default::default()
+    };
+    let mut conn = db.acquire().await?;
+    let songs =
+        get_filtered_library(&mut conn, -1, 0, SongSort::default(), SortOrder::Asc, &filter)
+            .await?;
+    if songs.is_empty() {
+        return Err(GenError::NotFound("no songs match the playlist filter".into()));
+    }
+
+    let conn_info = request.connection_info();
+    let mut playlist = String::from("#EXTM3U\n");
+    for song in songs.iter() {
+        playlist.push_str(&format!(
+            "#EXTINF:{},{} - {}\n{}://{}/song/{}\n",
+            song.duration.map_or(-1, |d| d as i64),
+            song.artist,
+            song.track_name,
+            conn_info.scheme(),
+            conn_info.host(),
+            song.id
+        ));
+    }
+
+    let name = if params.album.is_some() {
+        songs[0].album.as_str()
+    } else if params.artist.is_some() {
+        songs[0].artist.as_str()
+    } else {
+        "library"
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("audio/x-mpegurl")
+        .insert_header(super::attachment(&format!("{}.m3u", name)))
+        .body(playlist))
+}
+
+/// Every library row with its tags, for `/api/import` into another database
+pub async fn export(db: web::Data<Pool<Sqlite>>) -> super::GenResponse {
+    let mut conn = db.acquire().await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(super::attachment("musrs-library.json"))
+        .json(export_library(&mut conn).await?))
+}
+
+/// Restores an `/api/export` into a database with no library rows, so a move to another
+/// host keeps its tags without reading every file again. Scans that follow find the files
+/// unchanged by their mtime. The startup scan fills the database before a request can get
+/// here, so a new instance either starts with an empty `MUS_DIR` or imports `IMPORT_FILE`
+/// ahead of its scan
+pub async fn import(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    records: web::Json<Vec<ExportRecord>>,
+) -> super::GenResponse {
+    validate_import(&records).map_err(GenError::BadRequest)?;
+    // Holding the scan lock keeps a rescan from adding rows partway through
+    let _scanning = state.scan_lock.lock().await;
+    let mut conn = db.acquire().await?;
+    let Some(imported) = import_library(&mut conn, &records).await? else {
+        return Err(GenError::BadRequest(
+            "the library already has songs, import needs an empty database".into(),
+        ));
+    };
+    log::info!("Imported {} library rows", imported);
+    Ok(HttpResponse::Ok().json(json!({ "imported": imported })))
+}
+
+/// Query of [`rescan`] and [`reload`]
+#[derive(Deserialize)]
+pub struct RescanParams {
+    /// `1` to re-read every file's tags, not only those of new and changed files
+    pub force: Option<String>,
+}
+
+/// Picks up added, changed and removed files without a restart. Requests read the database,
+/// which moves from the old library to the new one a batch at a time as the rescan commits.
+/// A rescan requested while another, or the startup scan, is running waits for it to finish
+pub async fn rescan(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<RescanParams>,
+) -> super::GenResponse {
+    let force = params
+        .force
+        .as_deref()
+        .is_some_and(|f| f == "1" || f == "true");
+    let _scanning = state.scan_lock.lock().await;
+    let base_path = Path::new(&state.library_path);
+    let summary = rescan_library(&state.settings, base_path, &db, force).await?;
+    state.meta_cache.clear();
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// As [`rescan`], but the whole sync commits at once, so requests see the old library until
+/// the new one is complete. See [`reload_library`]
+pub async fn reload(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<RescanParams>,
+) -> super::GenResponse {
+    let force = params
+        .force
+        .as_deref()
+        .is_some_and(|f| f == "1" || f == "true");
+    let _scanning = state.scan_lock.lock().await;
+    let base_path = Path::new(&state.library_path);
+    let summary = reload_library(&state.settings, base_path, &db, force).await?;
+    state.meta_cache.clear();
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+pub async fn get_cover(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<i64>,
+) -> super::GenResponse {
+    let song_id = path.into_inner();
+    let meta = state
+        .meta_cache
+        .get_or_load(song_id, || async {
+            let mut conn = db.acquire().await?;
+            let Some(song) = find_song(&mut conn, song_id).await? else {
+                return Err(GenError::NotFound(format!("song {} not found", song_id)));
+            };
+            let abs_path = resolve_in_library(
+                &state.settings,
+                Path::new(&state.library_path),
+                song.path(),
+            )?;
+            let stored = find_cover_path(&mut conn, song_id).await?;
+            let lookup_state = state.clone();
+            let (cover, found_path) = web::block(move || {
+                let disk = lookup_state.cover_cache.as_ref();
+                let version = disk.and_then(|_| CoverCache::version(&abs_path));
+                if let Some(cached) = disk.zip(version).and_then(|(d, v)| d.read(song_id, v)) {
+                    return (cached, None);
+                }
+                let found = match read_cover(&abs_path) {
+                    Some(cover) => (Some(cover), None),
+                    None => {
+                        let base_path = Path::new(&lookup_state.library_path);
+                        let settings = &lookup_state.settings;
+                        match read_folder_cover(settings, base_path, &abs_path, stored.as_deref()) {
+                            Some((cover, path)) => {
+                                (Some(cover), path.filter(|p| Some(p) != stored.as_ref()))
+                            }
+                            None => (None, None),
+                        }
+                    }
+                };
+                if let Some((disk, version)) = disk.zip(version) {
+                    disk.write(song_id, version, found.0.as_ref());
+                }
+                found
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+            // Once evicted from the cache, the song's cover is read again without a search
+            if let Some(path) = found_path {
+                save_cover_path(&mut conn, song_id, &path).await?;
+            }
+            Ok(CachedMeta { cover })
+        })
+        .await?;
+
+    match meta.cover {
+        Some(cover) => Ok(HttpResponse::Ok()
+            .content_type(cover.mime_type)
+            .body(cover.data)),
+        None => Err(GenError::NotFound(format!("song {} has no cover art", song_id))),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LyricsParams {
+    /// `json` for the lines and their timestamps instead of the raw text
+    pub format: Option<String>,
+}
+
+/// The song's `.lrc` file, or else the lyrics embedded in its tags
+pub async fn get_lyrics(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<i64>,
+    params: web::Query<LyricsParams>,
+) -> super::GenResponse {
+    let song_id = path.into_inner();
+    let as_json = match params.format.as_deref() {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(other) => {
+            return Err(GenError::BadRequest(format!("unknown lyrics format '{}'", other)))
+        }
+    };
+    let mut conn = db.acquire().await?;
+    let Some(song) = find_song(&mut conn, song_id).await? else {
+        return Err(GenError::NotFound(format!("song {} not found", song_id)));
+    };
+    drop(conn);
+    let abs_path = resolve_in_library(
+        &state.settings,
+        Path::new(&state.library_path),
+        song.path(),
+    )?;
+    let found = web::block(move || {
+        read_sidecar_lyrics(&abs_path)
+            .map(|text| (text, "lrc"))
+            .or_else(|| read_lyrics(&abs_path, &song.file_extension).map(|text| (text, "tag")))
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let Some((text, source)) = found else {
+        return Err(GenError::NotFound(format!("song {} has no lyrics", song_id)));
+    };
+    if !as_json {
+        return Ok(HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(text));
+    }
+    let lines = lyrics::parse_lrc(&text);
+    Ok(HttpResponse::Ok().json(json!({
+        "song_id": song_id,
+        "source": source,
+        "synced": lines.iter().any(|l| l.time_ms.is_some()),
+        "lines": lines,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct ScrobbleParams {
+    /// Unix time playback started, defaults to now
+    pub timestamp: Option<i64>,
+}
+
+pub async fn scrobble(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<i64>,
+    params: web::Query<ScrobbleParams>,
+) -> super::GenResponse {
+    let Some(lastfm) = &state.lastfm else {
+        return Err(GenError::NotImplemented(
+            "Last.fm scrobbling is not configured".into(),
+        ));
+    };
+    let song_id = path.into_inner();
+    let mut conn = db.acquire().await?;
+    let Some(song) = find_library_row(&mut conn, song_id).await? else {
+        return Err(GenError::NotFound(format!("song {} not found", song_id)));
+    };
+    if song.artist_inferred {
+        return Err(GenError::BadRequest(format!("song {} has no known artist", song_id)));
+    }
+    let timestamp = params.timestamp.unwrap_or_else(unix_timestamp);
+    lastfm
+        .scrobble(&Scrobble {
+            artist: &song.artist,
+            track: &song.track_name,
+            album: (!song.album_inferred).then_some(song.album.as_str()),
+            track_number: song.track_number,
+            duration: song.duration,
+            timestamp,
+        })
+        .await?;
+    log::info!("Scrobbled song {}: {} - {}", song_id, song.artist, song.track_name);
+    Ok(HttpResponse::Ok().json(json!({ "scrobbled": song_id })))
+}
+
+#[derive(Deserialize)]
+pub struct PeaksParams {
+    pub buckets: Option<usize>,
+}
+
+/// Waveform peaks between 0 and 1 of full scale, decoded once and cached with the metadata
+pub async fn get_peaks(
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<i64>,
+    params: web::Query<PeaksParams>,
+) -> super::GenResponse {
+    let song_id = path.into_inner();
+    let buckets = params.buckets.unwrap_or(DEFAULT_PEAK_BUCKETS).min(MAX_BUCKETS);
+    if buckets == 0 {
+        return Err(GenError::BadRequest("buckets must be at least 1".into()));
+    }
+    let mut conn = db.acquire().await?;
+    let Some(song) = find_song(&mut conn, song_id).await? else {
+        return Err(GenError::NotFound(format!("song {} not found", song_id)));
+    };
+    let cached = find_peaks(&mut conn, song_id).await?.map(|b| peaks::from_blob(&b));
+    let song_peaks = match cached {
+        Some(cached) => cached,
+        None => {
+            let abs_path = resolve_in_library(
+                &state.settings,
+                Path::new(&state.library_path),
+                song.path(),
+            )?;
+            let segment = song.cue.as_ref().map(|cue| Segment {
+                start_ms: cue.start_ms,
+                end_ms: cue.end_ms,
+            });
+            // Decoding takes a while, so the connection goes back to the pool meanwhile
+            drop(conn);
+            let samples = decode_mono(&abs_path, segment, DECODE_SAMPLE_RATE).await?;
+            let computed = web::block(move || peaks::compute_peaks(&samples))
+                .await
+                .map_err(|e| e.to_string())?;
+            let mut conn = db.acquire().await?;
+            save_peaks(&mut conn, song_id, &peaks::to_blob(&computed)).await?;
+            computed
+        }
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "song_id": song_id,
+        "buckets": buckets,
+        "peaks": peaks::downsample(&song_peaks, buckets),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, test};
+    use crate::state::AppStateStruct;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    /// A library of 1000-byte untagged files at `paths`, scanned one at a time so that the
+    /// songs' ids follow the order of `paths` from 1
+    async fn library(lib: &Path, data: &Path, paths: &[&str]) -> (AppState, Pool<Sqlite>) {
+        let db = crate::file_utils::test_db(data).await;
+        let settings = crate::file_utils::test_settings();
+        for path in paths {
+            let path = lib.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, [0u8; 1000]).unwrap();
+            rescan_library(&settings, lib, &db, false).await.unwrap();
+        }
+        let library_path = lib.to_string_lossy().into_owned();
+        let state = AppStateStruct::new(library_path, settings, None, None, None, None);
+        (Arc::new(state), db)
+    }
+
+    /// Status and JSON body, `null` when it isn't JSON, of `GET uri` on the server's routes
+    async fn get_json(state: &AppState, db: &Pool<Sqlite>, uri: &str) -> (u16, Value) {
+        let app = test::init_service(
+            actix_web::App::new()
+                .configure(|cfg| crate::configure_routes(cfg, None))
+                .app_data(web::Data::new(state.clone()))
+                .app_data(web::Data::new(db.clone())),
+        )
+        .await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        let status = resp.status().as_u16();
+        let body = test::read_body(resp).await;
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[actix_web::test]
+    async fn get_songs_pages_through_the_library() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let paths = ["A/B/1.mp3", "A/B/2.mp3", "A/B/3.mp3", "A/B/4.mp3", "A/B/5.mp3"];
+        let (state, db) = library(lib.path(), data.path(), &paths).await;
+
+        let (status, body) = get_json(&state, &db, "/api/songs").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["total"], 5);
+        assert_eq!(body["limit"], DEFAULT_PAGE_LIMIT);
+        assert_eq!(body["songs"].as_array().unwrap().len(), 5);
+        assert_eq!(body["songs"][0]["file_size"], 1000);
+
+        let (_, body) = get_json(&state, &db, "/api/songs?limit=2&offset=3").await;
+        assert_eq!(body["total"], 5);
+        assert_eq!(body["offset"], 3);
+        let names = body["songs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|song| song["track_name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["4", "5"]);
+
+        let (_, body) = get_json(&state, &db, "/api/songs?limit=5000").await;
+        assert_eq!(body["limit"], MAX_PAGE_LIMIT);
+
+        for uri in ["/api/songs?limit=ten", "/api/songs?offset=-1"] {
+            assert_eq!(get_json(&state, &db, uri).await.0, 400, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn get_songs_sorts_by_duration_and_filters_by_year() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let paths = ["A/B/1.mp3", "A/B/2.mp3", "A/B/3.mp3", "A/B/4.mp3", "A/B/5.mp3"];
+        let (state, db) = library(lib.path(), data.path(), &paths).await;
+        for (id, duration, year) in [(1, 200, 1999), (2, 95, 2001), (3, 310, 2001), (4, 40, 2001)] {
+            sqlx::query(
+                "update track_metadata set duration = ?, release_year = ?
+                where filesystem_artifact_id = ?",
+            )
+            .bind(duration)
+            .bind(year)
+            .bind(id)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let names = |body: &Value| {
+            body["songs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|song| song["track_name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let (status, body) = get_json(&state, &db, "/api/songs?sort=duration&order=desc").await;
+        assert_eq!(status, 200);
+        assert_eq!((&body["sort"], &body["order"]), (&"duration".into(), &"desc".into()));
+        // Songs without a duration sort as the shortest
+        assert_eq!(names(&body), ["3", "1", "2", "4", "5"]);
+
+        let (_, body) = get_json(&state, &db, "/api/songs?year=2001&sort=duration").await;
+        assert_eq!(body["total"], 3);
+        assert_eq!(names(&body), ["4", "2", "3"]);
+
+        // Along with paging, which counts the filtered songs only
+        let uri = "/api/songs?year=2001&sort=duration&order=desc&limit=2&page=2";
+        let (_, body) = get_json(&state, &db, uri).await;
+        assert_eq!((&body["total"], &body["total_pages"]), (&3.into(), &2.into()));
+        assert_eq!(names(&body), ["4"]);
+
+        let (_, body) = get_json(&state, &db, "/api/songs?year=1850").await;
+        assert_eq!(body["total"], 0);
+        assert_eq!(get_json(&state, &db, "/api/songs?sort=bitrate").await.0, 400);
+    }
+
+    #[actix_web::test]
+    async fn get_cover_serves_embedded_art_with_its_type() {
+        use id3::TagLike;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let paths = ["A/B/1.mp3", "A/B/2.mp3", "C/D/3.mp3"];
+        let (state, db) = library(lib.path(), data.path(), &paths).await;
+        let covers = [
+            (1, "image/jpeg", b"\xff\xd8\xff\xe0 jpeg".as_slice()),
+            (2, "image/png", b"\x89PNG\r\n\x1a\n png".as_slice()),
+        ];
+        for (path, (_, mime_type, picture)) in paths.iter().zip(covers) {
+            let mut tag = id3::Tag::new();
+            tag.add_frame(id3::frame::Picture {
+                mime_type: mime_type.into(),
+                picture_type: id3::frame::PictureType::CoverFront,
+                description: String::new(),
+                data: picture.to_vec(),
+            });
+            tag.write_to_path(lib.path().join(path), id3::Version::Id3v24).unwrap();
+        }
+        let app = test::init_service(
+            actix_web::App::new()
+                .configure(|cfg| crate::configure_routes(cfg, None))
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db)),
+        )
+        .await;
+        let cover = |song_id: i64| {
+            let uri = format!("/api/song/{}/cover", song_id);
+            test::call_service(&app, test::TestRequest::get().uri(&uri).to_request())
+        };
+
+        for (song_id, mime_type, picture) in covers {
+            let resp = cover(song_id).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), mime_type);
+            assert_eq!(test::read_body(resp).await, picture);
+        }
+        assert_eq!(cover(3).await.status(), 404);
+
+        // Served from the cache once read, without the file
+        std::fs::remove_file(lib.path().join("A/B/1.mp3")).unwrap();
+        assert_eq!(cover(1).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn get_cover_reads_a_cached_cover_from_disk_until_the_song_changes() {
+        use id3::TagLike;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        /// The cover body as served by a fresh state, whose in-memory cache is empty
+        async fn cover(lib: &Path, db: &Pool<Sqlite>, disk: &CoverCache) -> Vec<u8> {
+            let library_path = lib.to_string_lossy().into_owned();
+            let settings = crate::file_utils::test_settings();
+            let disk = Some(disk.clone());
+            let state = AppStateStruct::new(library_path, settings, None, None, None, disk);
+            let app = test::init_service(
+                actix_web::App::new()
+                    .configure(|cfg| crate::configure_routes(cfg, None))
+                    .app_data(web::Data::new(Arc::new(state)))
+                    .app_data(web::Data::new(db.clone())),
+            )
+            .await;
+            let request = test::TestRequest::get().uri("/api/song/1/cover").to_request();
+            let resp = test::call_service(&app, request).await;
+            assert_eq!(resp.status(), 200);
+            test::read_body(resp).await.to_vec()
+        }
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (_, db) = library(lib.path(), data.path(), &["A/B/1.mp3"]).await;
+        let disk = CoverCache::open(cache_dir.path().to_path_buf()).unwrap();
+        let path = lib.path().join("A/B/1.mp3");
+        let scanned_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let set_cover = |picture: &[u8], mtime: std::time::SystemTime| {
+            let mut tag = id3::Tag::new();
+            tag.add_frame(id3::frame::Picture {
+                mime_type: "image/jpeg".into(),
+                picture_type: id3::frame::PictureType::CoverFront,
+                description: String::new(),
+                data: picture.to_vec(),
+            });
+            tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        };
+
+        set_cover(b"\xff\xd8\xff\xe0 first", scanned_at);
+        assert_eq!(cover(lib.path(), &db, &disk).await, b"\xff\xd8\xff\xe0 first");
+
+        // Unchanged as far as the mtime goes, so the tags aren't read again
+        set_cover(b"\xff\xd8\xff\xe0 second", scanned_at);
+        assert_eq!(cover(lib.path(), &db, &disk).await, b"\xff\xd8\xff\xe0 first");
+
+        set_cover(b"\xff\xd8\xff\xe0 second", scanned_at + Duration::from_secs(60));
+        assert_eq!(cover(lib.path(), &db, &disk).await, b"\xff\xd8\xff\xe0 second");
+        // Only the new version is left
+        assert_eq!(std::fs::read_dir(cache_dir.path().join("1")).unwrap().count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn get_album_list_groups_songs_by_artist_and_album() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let paths = ["Beta/Zeta/1.mp3", "Beta/Zeta/2.mp3", "Alpha/Yotta/1.mp3", "Beta/Aleph/1.mp3"];
+        let (state, db) = library(lib.path(), data.path(), &paths).await;
+        let names = |body: &Value| {
+            body["albums"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|album| {
+                    let name = |key: &str| album[key].as_str().unwrap().to_string();
+                    format!("{}/{}", name("artist"), name("album"))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (status, body) = get_json(&state, &db, "/api/albums").await;
+        assert_eq!(status, 200);
+        assert_eq!(names(&body), ["Alpha/Yotta", "Beta/Aleph", "Beta/Zeta"]);
+        let zeta = &body["albums"][2];
+        assert_eq!(zeta["track_count"], 2);
+        assert_eq!(zeta["song_ids"], serde_json::json!([1, 2]));
+        assert_eq!(zeta["cover_song_id"], 1);
+
+        let (_, body) = get_json(&state, &db, "/api/albums?artist=Beta").await;
+        assert_eq!(names(&body), ["Beta/Aleph", "Beta/Zeta"]);
+    }
+
+    #[actix_web::test]
+    async fn get_album_lists_an_albums_tracks_in_play_order() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let paths = [
+            "Sigur Rós/Ágætis byrjun/a.mp3",
+            "Sigur Rós/Ágætis byrjun/b.mp3",
+            "Sigur Rós/Ágætis byrjun/c.mp3",
+            "Misc/Stuff/x.mp3",
+        ];
+        let (state, db) = library(lib.path(), data.path(), &paths).await;
+        let tags = [
+            (1, None, None, 3, 100),
+            (2, None, None, 1, 200),
+            (3, None, None, 2, 300),
+            (4, Some("Tagged"), Some("Record"), 1, 50),
+        ];
+        for (id, artist, album, track, duration) in tags {
+            sqlx::query(
+                "update track_metadata set artist = ?, album = ?, track_number = ?, duration = ?
+                where filesystem_artifact_id = ?",
+            )
+            .bind(artist)
+            .bind(album)
+            .bind(track)
+            .bind(duration)
+            .bind(id)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let track_ids = |body: &Value| {
+            body["tracks"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|track| track["id"].as_i64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Path segments are percent-decoded before matching
+        let uri = "/api/album/Sigur%20R%C3%B3s/%C3%81g%C3%A6tis%20byrjun/tracks";
+        let (status, body) = get_json(&state, &db, uri).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["album"]["track_count"], 3);
+        assert_eq!(body["duration"], 600);
+        assert_eq!(track_ids(&body), [2, 3, 1]);
+        let numbers = body["tracks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|track| (track["track_number"].clone(), track["duration"].clone()))
+            .collect::<Vec<_>>();
+        let expected: [(Value, Value); 3] = [
+            (1.into(), 200.into()),
+            (2.into(), 300.into()),
+            (3.into(), 100.into()),
+        ];
+        assert_eq!(numbers, expected);
+
+        // By its tags, or by the directories it is in
+        for uri in ["/api/album/tagged/record/tracks", "/api/album/Misc/Stuff/tracks"] {
+            let (status, body) = get_json(&state, &db, uri).await;
+            assert_eq!(status, 200, "{}", uri);
+            assert_eq!(track_ids(&body), [4], "{}", uri);
+        }
+
+        let (status, body) = get_json(&state, &db, "/api/album/Sigur%20R%C3%B3s/Takk/tracks").await;
+        assert_eq!(status, 404);
+        assert_eq!(body["error"], "album 'Takk' by 'Sigur Rós' not found");
+    }
+
+    #[actix_web::test]
+    async fn get_songs_flags_the_guessed_artist_and_album_of_a_top_level_file() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let paths = ["Loose.mp3", "Unknown Artist/Unknown Album/Real.mp3"];
+        let (state, db) = library(lib.path(), data.path(), &paths).await;
+
+        let (_, body) = get_json(&state, &db, "/api/songs?sort=title").await;
+        let songs = body["songs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|song| {
+                let fields = ["track_name", "artist", "album", "artist_inferred", "album_inferred"];
+                Value::from(fields.map(|field| song[field].clone()).to_vec())
+            })
+            .collect::<Vec<_>>();
+        // A folder that happens to have the placeholder's name is no guess
+        assert_eq!(
+            songs,
+            [
+                json!(["Loose", "Unknown Artist", "Unknown Album", true, true]),
+                json!(["Real", "Unknown Artist", "Unknown Album", false, false]),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn get_cover_falls_back_to_the_folder_image_and_remembers_it() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let (state, db) = library(lib.path(), data.path(), &["A/B/1.mp3"]).await;
+        let folder_png = b"\x89PNG\r\n\x1a\n folder".as_slice();
+        std::fs::write(lib.path().join("A/B/Folder.PNG"), folder_png).unwrap();
+        let cover = |state: AppState| {
+            let db = db.clone();
+            async move {
+                let app = test::init_service(
+                    actix_web::App::new()
+                        .configure(|cfg| crate::configure_routes(cfg, None))
+                        .app_data(web::Data::new(state))
+                        .app_data(web::Data::new(db)),
+                )
+                .await;
+                let request = test::TestRequest::get().uri("/api/song/1/cover").to_request();
+                let resp = test::call_service(&app, request).await;
+                let content_type = resp.headers().get(header::CONTENT_TYPE).unwrap().clone();
+                (resp.status().as_u16(), content_type, test::read_body(resp).await)
+            }
+        };
+
+        let (status, content_type, body) = cover(state.clone()).await;
+        assert_eq!((status, content_type.to_str().unwrap()), (200, "image/png"));
+        assert_eq!(body, folder_png);
+        let mut conn = db.acquire().await.unwrap();
+        let stored = find_cover_path(&mut conn, 1).await.unwrap();
+        assert_eq!(stored.as_deref(), Some("A/B/Folder.PNG"));
+
+        // A fresh cache goes to the stored image, though a better named one turned up since
+        std::fs::write(lib.path().join("A/B/cover.jpg"), b"jpeg").unwrap();
+        let settings = crate::file_utils::test_settings();
+        let library_path = state.library_path.clone();
+        let fresh = AppStateStruct::new(library_path, settings, None, None, None, None);
+        let (status, _, body) = cover(Arc::new(fresh)).await;
+        assert_eq!((status, body.as_ref()), (200, folder_png));
+    }
+
+    #[actix_web::test]
+    async fn get_scan_error_list_tells_corrupt_files_from_untagged_ones() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        // One MPEG audio frame header, 128 kbps at 44.1 kHz, and no tags
+        let mut untagged = vec![0xff, 0xfb, 0x90, 0x00];
+        untagged.resize(1000, 0);
+        std::fs::create_dir_all(lib.path().join("A/B")).unwrap();
+        std::fs::write(lib.path().join("A/B/untagged.mp3"), untagged).unwrap();
+        let (state, db) = library(lib.path(), data.path(), &["A/B/corrupt.mp3"]).await;
+        let paths = |body: &Value| {
+            let files = body["files"].as_array().unwrap();
+            files.iter().map(|f| f["path"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+        };
+
+        let (status, body) = get_json(&state, &db, "/api/scan-errors").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["status"], "read_error");
+        assert_eq!(paths(&body), ["A/B/corrupt.mp3"]);
+        assert!(body["files"][0]["error"].as_str().unwrap().contains("no MPEG audio frames"));
+
+        let (_, body) = get_json(&state, &db, "/api/scan-errors?status=no_tags").await;
+        assert_eq!(paths(&body), ["A/B/untagged.mp3"]);
+    }
+
+    #[actix_web::test]
+    async fn get_song_details_has_the_tagged_title() {
+        use id3::TagLike;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let (state, db) = library(lib.path(), data.path(), &["A/B/04.mp3"]).await;
+        let mut tag = id3::Tag::new();
+        tag.set_title("Hoppípolla");
+        tag.set_artist("Sigur Rós");
+        tag.set_album("Takk...");
+        tag.set_track(4);
+        tag.write_to_path(lib.path().join("A/B/04.mp3"), id3::Version::Id3v24).unwrap();
+        rescan_library(&state.settings, lib.path(), &db, true).await.unwrap();
+
+        let (status, body) = get_json(&state, &db, "/api/song/1").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["song"]["relative_path"], "A/B/04.mp3");
+        let metadata = &body["metadata"];
+        assert_eq!(metadata["title"], "Hoppípolla");
+        assert_eq!(metadata["artist"], "Sigur Rós");
+        assert_eq!(metadata["album"], "Takk...");
+        assert_eq!(metadata["track_number"], 4);
+
+        let (status, body) = get_json(&state, &db, "/api/song/2").await;
+        assert_eq!(status, 404);
+        assert_eq!(body["error"], "song 2 not found");
+    }
+
+    #[actix_web::test]
+    async fn the_index_is_a_page_of_songs_for_json_clients_and_the_ui_otherwise() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let (state, db) = library(lib.path(), data.path(), &["A/B/1.mp3", "A/B/2.mp3"]).await;
+        let app = test::init_service(
+            actix_web::App::new()
+                .configure(|cfg| crate::configure_routes(cfg, None))
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db)),
+        )
+        .await;
+        let get = |accept: &str| {
+            let request = test::TestRequest::get()
+                .uri("/?limit=1&offset=1")
+                .insert_header((header::ACCEPT, accept));
+            test::call_service(&app, request.to_request())
+        };
+
+        let resp = get("application/json").await;
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(json!([body["total"], body["limit"], body["offset"]]), json!([2, 1, 1]));
+        assert_eq!(body["songs"][0]["track_name"], "2");
+
+        let resp = get("text/html,application/xhtml+xml,application/json;q=0.9").await;
+        let content_type = resp.headers().get(header::CONTENT_TYPE).unwrap();
+        assert!(content_type.to_str().unwrap().starts_with("text/html"));
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("<html"), "{}", body);
+    }
+
+    #[actix_web::test]
+    async fn get_info_reports_the_build_and_hides_the_library_root_when_asked() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let (state, db) = library(lib.path(), data.path(), &["A/B/1.mp3", "A/B/2.mp3"]).await;
+
+        let (status, body) = get_json(&state, &db, "/api/info").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["name"], env!("CARGO_PKG_NAME"));
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["song_count"], 2);
+        assert_eq!(body["library_path"], state.library_path.as_str());
+        assert_eq!(body["extensions"], serde_json::json!(state.settings.allowed_extensions));
+
+        let mut settings = crate::file_utils::test_settings();
+        settings.hide_paths = true;
+        let library_path = state.library_path.clone();
+        let hidden = AppStateStruct::new(library_path, settings, None, None, None, None);
+        let (_, body) = get_json(&Arc::new(hidden), &db, "/api/info").await;
+        let basename = lib.path().file_name().unwrap().to_str().unwrap();
+        assert_eq!(body["library_path"], basename);
+    }
+
+    #[actix_web::test]
+    async fn favorites_are_set_listed_and_cleared() {
+        use std::collections::BTreeSet;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let paths = ["A/B/1.mp3", "A/B/2.mp3", "A/B/3.mp3"];
+        let (state, db) = library(lib.path(), data.path(), &paths).await;
+        let app = test::init_service(
+            actix_web::App::new()
+                .configure(|cfg| crate::configure_routes(cfg, None))
+                .app_data(web::Data::new(state.clone()))
+                .app_data(web::Data::new(db.clone())),
+        )
+        .await;
+        let set = |request: test::TestRequest, id: i64| {
+            let request = request.uri(&format!("/api/song/{}/favorite", id)).to_request();
+            test::call_service(&app, request)
+        };
+        let favorite_ids = || async {
+            let (_, body) = get_json(&state, &db, "/api/favorites").await;
+            body["songs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|song| song["id"].as_i64().unwrap())
+                .collect::<BTreeSet<_>>()
+        };
+
+        // Setting one twice is the same as setting it once
+        for _ in 0..2 {
+            let resp = set(test::TestRequest::put(), 2).await;
+            assert_eq!(resp.status(), 200);
+            let body: Value = test::read_body_json(resp).await;
+            assert_eq!(body, serde_json::json!({ "id": 2, "is_favorite": true }));
+        }
+        assert_eq!(set(test::TestRequest::put(), 3).await.status(), 200);
+        assert_eq!(favorite_ids().await, BTreeSet::from([2, 3]));
+
+        let resp = set(test::TestRequest::delete(), 3).await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "id": 3, "is_favorite": false }));
+        assert_eq!(favorite_ids().await, BTreeSet::from([2]));
+
+        let (_, body) = get_json(&state, &db, "/api/songs").await;
+        let flags = body["songs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|song| song["is_favorite"].as_bool().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(flags, [false, true, false]);
 
-pub async fn get_songs(db: web::Data<Pool<Sqlite>>) -> super::GenResponse {
-    let conn = db.acquire().await?;
-    let songs = get_library(conn).await?;
-    Ok(HttpResponse::Ok().json(json!({ "songs": songs})))
+        for request in [test::TestRequest::put(), test::TestRequest::delete()] {
+            assert_eq!(set(request, 99).await.status(), 404);
+        }
+        assert_eq!(favorite_ids().await, BTreeSet::from([2]));
+    }
 }
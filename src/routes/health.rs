@@ -0,0 +1,134 @@
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, HttpResponse, Responder,
+};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+use std::sync::atomic::Ordering;
+
+use crate::state::AppState;
+
+/// Routes answering from the library, which is incomplete until the startup scan is done
+const LIBRARY_PREFIXES: &[&str] = &["/api/", "/rest/", "/song/", "/play/"];
+/// Seconds a client is told to wait for the startup scan before trying again
+const SCAN_RETRY_AFTER: u32 = 5;
+
+/// Liveness, answers as long as the server is accepting requests
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Readiness, only once the startup scan has finished and the database answers
+pub async fn readyz(state: web::Data<AppState>, db: web::Data<Pool<Sqlite>>) -> impl Responder {
+    if !state.scan_complete.load(Ordering::Acquire) {
+        log::debug!("Not ready: startup scan still running");
+        return HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, SCAN_RETRY_AFTER))
+            .json(json!({ "status": "scanning" }));
+    }
+    if let Err(e) = sqlx::query("SELECT 1").execute(db.get_ref()).await {
+        log::debug!("Not ready: database check failed: {}", e);
+        return HttpResponse::ServiceUnavailable()
+            .json(json!({ "status": "database unavailable" }));
+    }
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Answers `503` to library requests until the startup scan has finished, so clients
+/// retry instead of caching a partial library. Probes and the UI are always served
+pub async fn require_scanned(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let scanning = req
+        .app_data::<web::Data<AppState>>()
+        .is_some_and(|state| !state.scan_complete.load(Ordering::Acquire));
+    if !scanning || !LIBRARY_PREFIXES.iter().any(|p| req.path().starts_with(p)) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    log::debug!("Refused {} during the startup scan", req.path());
+    let response = HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, SCAN_RETRY_AFTER))
+        .json(json!({
+            "error": "the library is still being scanned",
+            "code": 503,
+        }));
+    Ok(req.into_response(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    fn scanning_state() -> AppState {
+        std::sync::Arc::new(crate::state::AppStateStruct::new(
+            "/music".into(),
+            crate::file_utils::test_settings(),
+            None,
+            None,
+            None,
+            None,
+        ))
+    }
+
+    #[actix_web::test]
+    async fn readyz_waits_for_the_startup_scan() {
+        let data = tempfile::tempdir().unwrap();
+        let db = crate::file_utils::test_db(data.path()).await;
+        let state = scanning_state();
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state.clone()))
+                .app_data(web::Data::new(db))
+                .route("/readyz", web::get().to(readyz)),
+        )
+        .await;
+        let ready = || {
+            let request = test::TestRequest::get().uri("/readyz");
+            test::call_service(&app, request.to_request())
+        };
+
+        let resp = ready().await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "5");
+        state.scan_complete.store(true, Ordering::Release);
+        let resp = ready().await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    #[actix_web::test]
+    async fn require_scanned_holds_library_routes_until_the_scan_is_done() {
+        let state = scanning_state();
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state.clone()))
+                .wrap(actix_web::middleware::from_fn(require_scanned))
+                .route("/api/songs", web::get().to(HttpResponse::Ok))
+                .route("/song/1", web::get().to(HttpResponse::Ok))
+                .route("/healthz", web::get().to(healthz))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |uri: &'static str| {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request())
+        };
+
+        for uri in ["/api/songs", "/song/1"] {
+            let resp = get(uri).await;
+            assert_eq!(resp.status(), 503, "{}", uri);
+            assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "5");
+        }
+        assert_eq!(get("/healthz").await.status(), 200);
+        assert_eq!(get("/").await.status(), 200);
+
+        state.scan_complete.store(true, Ordering::Release);
+        for uri in ["/api/songs", "/song/1"] {
+            assert_eq!(get(uri).await.status(), 200, "{}", uri);
+        }
+    }
+}
@@ -1,6 +1,145 @@
-use actix_web::Responder;
+use actix_web::{
+    guard::GuardContext, http::header, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+
+use crate::errors::GenError;
+use crate::file_utils::sanitize_filename;
 
 pub mod api;
+pub mod health;
+pub mod player;
 pub mod song;
+pub mod subsonic;
+
+pub type GenResponse = Result<HttpResponse, crate::errors::GenError>;
+
+/// Escapes text for use in XML and HTML content or attribute values
+pub(crate) fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The `limit` items of `items` starting at `offset`, cut short at the end of the slice.
+/// Offsets past the end give an empty slice rather than a panic
+pub(crate) fn paginate<T>(items: &[T], offset: usize, limit: usize) -> &[T] {
+    let start = offset.min(items.len());
+    let end = start.saturating_add(limit).min(items.len());
+    &items[start..end]
+}
+
+/// An `attachment` disposition for `filename`: a plain ASCII name for every client, plus the
+/// UTF-8 one as `filename*` for those that understand it
+pub(crate) fn attachment(filename: &str) -> header::ContentDisposition {
+    let filename = sanitize_filename(filename);
+    let ascii = filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect::<String>();
+    let mut parameters = vec![header::DispositionParam::Filename(ascii)];
+    if !filename.is_ascii() {
+        parameters.push(header::DispositionParam::FilenameExt(header::ExtendedValue {
+            charset: header::Charset::Ext("UTF-8".into()),
+            language_tag: None,
+            value: filename.into_bytes(),
+        }));
+    }
+    header::ContentDisposition {
+        disposition: header::DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Whether the client ranks `application/json` above `text/html`. Browsers, and clients that
+/// send no `Accept` at all, get HTML
+fn prefers_json(accept: Option<header::Accept>) -> bool {
+    accept
+        .and_then(|accept| {
+            accept
+                .ranked()
+                .into_iter()
+                .find_map(|mime| match mime.essence_str() {
+                    "application/json" => Some(true),
+                    "text/html" => Some(false),
+                    _ => None,
+                })
+        })
+        .unwrap_or(false)
+}
+
+/// Route guard for JSON responses to a page's URL, which serves the UI otherwise
+pub fn accepts_json(ctx: &GuardContext) -> bool {
+    prefers_json(ctx.header::<header::Accept>())
+}
+
+/// Fallback for unmatched routes and files missing from the UI bundle. JSON when the
+/// client ranks `application/json` above `text/html`, an HTML page otherwise
+pub async fn not_found(request: HttpRequest) -> HttpResponse {
+    let path = request.path();
+    if prefers_json(request.get_header::<header::Accept>()) {
+        return GenError::NotFound(format!("no route for {}", path)).error_response();
+    }
+    let path = xml_escape(path);
+    HttpResponse::NotFound()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>\n<html>\n<head><title>Not found</title></head>\n<body>\n\
+             <h1>Not found</h1>\n<p>Nothing is served at <code>{}</code>.</p>\n\
+             <p><a href=\"/\">Back to the library</a></p>\n</body>\n</html>\n",
+            path
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paginate_clamps_to_the_items() {
+        let items = [1, 2, 3, 4, 5];
+        assert_eq!(paginate(&items, 0, 2), [1, 2]);
+        assert_eq!(paginate(&items, 3, 10), [4, 5]);
+        assert_eq!(paginate(&items, 5, 1), [0; 0]);
+        assert_eq!(paginate(&items, 100, 1), [0; 0]);
+        assert_eq!(paginate(&items, 1, 0), [0; 0]);
+        assert_eq!(paginate(&items, 2, usize::MAX), [3, 4, 5]);
+        assert_eq!(paginate(&[0; 0], 0, 10), [0; 0]);
+    }
+
+    #[actix_web::test]
+    async fn not_found_answers_in_the_format_the_client_asks_for() {
+        use actix_web::{test, web, App};
+
+        let app = test::init_service(
+            App::new()
+                .configure(|cfg| crate::configure_routes(cfg, None))
+                .default_service(web::to(not_found)),
+        )
+        .await;
+        let get = |uri: &str, accept: Option<&str>| {
+            let mut request = test::TestRequest::get().uri(uri);
+            if let Some(accept) = accept {
+                request = request.insert_header((header::ACCEPT, accept));
+            }
+            test::call_service(&app, request.to_request())
+        };
 
-pub type GenResponse = Result<impl Responder, crate::errors::GenError>;
+        for uri in ["/no/such/page", "/api/no-such-route"] {
+            let resp = get(uri, Some("application/json")).await;
+            assert_eq!(resp.status(), 404);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            let message = format!("no route for {}", uri);
+            assert_eq!(body, serde_json::json!({ "error": message, "code": 404 }));
+        }
+        for accept in [Some("text/html,application/json;q=0.9"), None] {
+            let resp = get("/no/such/page", accept).await;
+            assert_eq!(resp.status(), 404);
+            let content_type = resp.headers().get(header::CONTENT_TYPE).unwrap();
+            assert_eq!(content_type, "text/html; charset=utf-8");
+            let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+            assert!(body.contains("<code>/no/such/page</code>"), "{}", body);
+        }
+    }
+}
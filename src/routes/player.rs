@@ -0,0 +1,111 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::{Pool, Sqlite};
+
+use super::xml_escape;
+use crate::db::get_library;
+use crate::errors::GenError;
+use crate::types::{LibraryRow, SongSort};
+
+/// A page that plays one song, linking to the songs before and after it in library order.
+/// The query string, e.g. a `?token=`, is passed on to the audio, cover and links so a
+/// shared URL keeps working
+pub async fn play_page(
+    request: HttpRequest,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<u64>,
+) -> Result<HttpResponse, GenError> {
+    let song_id = path.into_inner() as i64;
+    let mut conn = db.acquire().await?;
+    let songs = get_library(&mut conn, -1, 0, SongSort::default()).await?;
+    let Some(position) = songs.iter().position(|s| s.id == song_id) else {
+        return Ok(super::not_found(request).await);
+    };
+    let query = match request.query_string() {
+        "" => String::new(),
+        query => format!("?{}", xml_escape(query)),
+    };
+    let song = &songs[position];
+    let link = |label: &str, neighbour: Option<&LibraryRow>| match neighbour {
+        Some(s) => format!(
+            "<a href=\"/play/{}{}\" rel=\"{}\">{} {}</a>",
+            s.id,
+            query,
+            label.to_lowercase(),
+            label,
+            xml_escape(&s.track_name)
+        ),
+        None => format!("<span>{}: none</span>", label),
+    };
+    let prev = link("Prev", position.checked_sub(1).and_then(|p| songs.get(p)));
+    let next = link("Next", songs.get(position + 1));
+    let title = xml_escape(&song.track_name);
+    let artist = xml_escape(&song.artist);
+    let album = xml_escape(&song.album);
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>{title} - {artist}</title>\n</head>\n<body>\n\
+             <img src=\"/api/song/{id}/cover{query}\" alt=\"\" width=\"300\" \
+             onerror=\"this.remove()\">\n\
+             <h1>{title}</h1>\n<p>{artist} &middot; {album}</p>\n\
+             <audio controls autoplay preload=\"metadata\" src=\"/song/{id}{query}\"></audio>\n\
+             <nav>{prev} | {next}</nav>\n\
+             <p><a href=\"/\">Back to the library</a></p>\n</body>\n</html>\n",
+            id = song.id,
+        )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, test, App};
+
+    #[actix_web::test]
+    async fn play_page_shows_the_song_and_plays_it_from_its_stream() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = crate::file_utils::test_db(data.path()).await;
+        std::fs::create_dir_all(lib.path().join("Artist/Album")).unwrap();
+        for name in ["1 First", "2 Tom & Jerry", "3 Last"] {
+            let path = lib.path().join(format!("Artist/Album/{}.mp3", name));
+            std::fs::write(path, [0u8; 1000]).unwrap();
+        }
+        let settings = crate::file_utils::test_settings();
+        crate::file_utils::rescan_library(&settings, lib.path(), &db, false)
+            .await
+            .unwrap();
+        let id_of = |name: &'static str| {
+            sqlx::query_scalar::<_, i64>("select id from filesystem_artifacts where file_name = ?")
+                .bind(name)
+                .fetch_one(&db)
+        };
+        let (first, middle, last) = (
+            id_of("1 First").await.unwrap(),
+            id_of("2 Tom & Jerry").await.unwrap(),
+            id_of("3 Last").await.unwrap(),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(db.clone()))
+                .route("/play/{song_id}", web::get().to(play_page)),
+        )
+        .await;
+
+        let uri = format!("/play/{}?token=abc", middle);
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let content_type = resp.headers().get(header::CONTENT_TYPE).unwrap();
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        let html = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(html.contains("<h1>Tom &amp; Jerry</h1>"), "{}", html);
+        let audio = format!("src=\"/song/{}?token=abc\"></audio>", middle);
+        assert!(html.contains(&audio), "{}", html);
+        assert!(html.contains(&format!("href=\"/play/{}?token=abc\" rel=\"prev\"", first)));
+        assert!(html.contains(&format!("href=\"/play/{}?token=abc\" rel=\"next\"", last)));
+
+        let request = test::TestRequest::get().uri("/play/99999").to_request();
+        let resp = test::call_service(&app, request).await;
+        assert_eq!(resp.status(), 404);
+    }
+}
@@ -1,60 +1,942 @@
-use actix_files::NamedFile;
-use actix_web::{get, head, web, HttpRequest, HttpResponse, Responder};
+use actix_files::HttpRange;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::web::Bytes;
+use actix_web::{
+    get, head,
+    http::{header, Method},
+    middleware::{from_fn, Logger, Next},
+    web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use futures_util::task::AtomicWaker;
+use futures_util::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
 use sqlx::{Pool, Sqlite};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 
-use crate::types::Song;
+use crate::cue_bytes::{track_bytes, TrackBytes};
+use crate::db::{find_library_row, find_song, record_play};
+use crate::file_utils::{audio_mime_type, resolve_in_library, unix_timestamp};
+use crate::state::{AppState, StreamPermit};
+use crate::transcode::{find_format, transcode, Segment};
 
-#[head("/song/{song_id}")]
-async fn song_head() -> super::GenResponse {
-    let resp = HttpResponse::Ok()
-        .append_header(("Accept-Ranges", "bytes"))
-        .finish();
-    Ok(resp)
+/// Files only change when replaced on disk, which changes their ETag
+const CACHE_CONTROL: &str = "public, max-age=86400";
+/// Seconds a client over its stream limit is told to wait before trying again
+const STREAM_RETRY_AFTER: u32 = 5;
+
+#[derive(Deserialize)]
+pub struct SongParams {
+    /// Transcode to this format instead of serving the file as-is, or `raw` for the file
+    /// as-is even when the transcode profile has a rule for its type
+    pub format: Option<String>,
+    /// `1` to download as `Artist - Title.ext` rather than play inline
+    pub download: Option<String>,
+}
+
+impl SongParams {
+    fn download(&self) -> bool {
+        self.download.as_deref().is_some_and(|d| d == "1" || d == "true")
+    }
+}
+
+/// Cue tracks cut out of a file also go by their bytes of it, which change with the cue sheet
+fn file_etag(meta: &std::fs::Metadata, bytes: &TrackBytes) -> String {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    if *bytes == TrackBytes::whole(meta.len()) {
+        return format!("\"{:x}-{:x}\"", meta.len(), mtime);
+    }
+    format!("\"{:x}-{:x}-{:x}-{:x}\"", meta.len(), mtime, bytes.start, bytes.end)
+}
+
+/// The song a stream would be a play of, and who it is streamed to
+struct Play {
+    state: AppState,
+    db: Pool<Sqlite>,
+    song_id: i64,
+    client: Option<IpAddr>,
+}
+
+/// Passes a song's body through and records a play once enough of the song has been sent
+/// to its client, see [`crate::state::PlayTracker`]
+struct PlayCounter<S> {
+    inner: S,
+    /// Bytes that make a play, `None` when only reaching the end of the stream counts
+    needed: Option<u64>,
+    play: Option<Play>,
+}
+
+impl<S> PlayCounter<S> {
+    fn new(inner: S, play: Option<Play>, needed: Option<u64>) -> Self {
+        Self {
+            inner,
+            needed,
+            play,
+        }
+    }
+
+    fn add_sent(&mut self, sent: u64, finished: bool) {
+        let Some(play) = &self.play else {
+            return;
+        };
+        if !play.state.plays.add(play.client, play.song_id, sent, self.needed, finished) {
+            return;
+        }
+        if let Some(Play { db, song_id, .. }) = self.play.take() {
+            let played_at = unix_timestamp();
+            tokio::spawn(async move {
+                let res = match db.acquire().await {
+                    Ok(mut conn) => record_play(&mut conn, song_id, played_at).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    log::error!("Could not record play of song {}: {}", song_id, e);
+                }
+            });
+        }
+    }
+}
+
+impl<S, E> Stream for PlayCounter<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => self.add_sent(chunk.len() as u64, false),
+            Poll::Ready(None) => self.add_sent(0, true),
+            _ => {}
+        }
+        polled
+    }
+}
+
+/// What a song's body holds on to until it is sent or aborted
+struct Watched<S> {
+    body: S,
+    /// Counts the stream against its client, see [`limit_streams`]
+    _permit: Option<StreamPermit>,
+}
+
+/// When a body last yielded a chunk, in milliseconds after `started`, and the body itself
+/// until [`abort_when_idle`] takes it
+struct Progress<S> {
+    started: Instant,
+    last_ms: AtomicU64,
+    watched: Mutex<Option<Watched<S>>>,
+    waker: AtomicWaker,
+}
+
+/// Passes a song's body through, noting each chunk so [`abort_when_idle`] can tell a
+/// client that stopped reading. An aborted body fails with `TimedOut` when next polled,
+/// which ends its response, or just its stream on an HTTP/2 connection
+struct IdleWatch<S> {
+    progress: Arc<Progress<S>>,
+    failed: bool,
+}
+
+impl<S> IdleWatch<S>
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Unpin + Send + 'static,
+{
+    fn new(
+        body: S,
+        permit: Option<StreamPermit>,
+        timeout: Option<Duration>,
+        song_id: i64,
+        peer: String,
+    ) -> Self {
+        let progress = Arc::new(Progress {
+            started: Instant::now(),
+            last_ms: AtomicU64::new(0),
+            watched: Mutex::new(Some(Watched {
+                body,
+                _permit: permit,
+            })),
+            waker: AtomicWaker::new(),
+        });
+        if let Some(timeout) = timeout {
+            tokio::spawn(abort_when_idle(
+                Arc::downgrade(&progress),
+                timeout,
+                song_id,
+                peer,
+            ));
+        }
+        Self {
+            progress,
+            failed: false,
+        }
+    }
+}
+
+impl<S> Stream for IdleWatch<S>
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Unpin,
+{
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.failed {
+            return Poll::Ready(None);
+        }
+        self.progress.waker.register(cx.waker());
+        let polled = match self.progress.watched.lock().unwrap().as_mut() {
+            Some(watched) => Pin::new(&mut watched.body).poll_next(cx),
+            None => Poll::Ready(Some(Err(std::io::ErrorKind::TimedOut.into()))),
+        };
+        match &polled {
+            Poll::Ready(Some(Ok(_))) => {
+                let elapsed = self.progress.started.elapsed().as_millis() as u64;
+                self.progress.last_ms.store(elapsed, Ordering::Relaxed);
+            }
+            Poll::Ready(Some(Err(_))) => self.failed = true,
+            _ => {}
+        }
+        polled
+    }
+}
+
+/// Aborts the body behind `progress` when it yields nothing for `timeout`. A client that
+/// stops reading fills the socket's buffers, after which actix no longer polls the body,
+/// so the body's file or ffmpeg process and its stream permit are dropped from here. The
+/// connection is left alone, only the stalled response fails once actix polls it again.
+/// Returns once the body is aborted or dropped
+async fn abort_when_idle<S>(
+    progress: Weak<Progress<S>>,
+    timeout: Duration,
+    song_id: i64,
+    peer: String,
+) {
+    loop {
+        // Not held while sleeping, so a body that is sent or dropped frees it right away
+        let Some(progress) = progress.upgrade() else {
+            return;
+        };
+        let last = Duration::from_millis(progress.last_ms.load(Ordering::Relaxed));
+        let deadline = progress.started + last + timeout;
+        if Instant::now() < deadline {
+            drop(progress);
+            tokio::time::sleep_until(deadline.into()).await;
+            continue;
+        }
+        log::debug!(
+            "Aborting the stream of song {} to {}, nothing was sent for {:?}",
+            song_id,
+            peer,
+            timeout
+        );
+        let watched = progress.watched.lock().unwrap().take();
+        drop(watched);
+        progress.waker.wake();
+        return;
+    }
+}
+
+/// Wraps `body` to be aborted when the client stops reading it for the settings'
+/// `stream_idle_timeout`, taking over the request's stream permit so it is freed along with
+/// the body
+fn watch_idle<S>(
+    request: &HttpRequest,
+    state: &crate::state::AppState,
+    song_id: i64,
+    body: S,
+) -> IdleWatch<S>
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Unpin + Send + 'static,
+{
+    let permit = request.extensions_mut().remove::<StreamPermit>();
+    let peer = request
+        .peer_addr()
+        .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+    IdleWatch::new(body, permit, state.settings.stream_idle_timeout, song_id, peer)
+}
+
+/// Access log for song streams. `%b` counts the bytes actually written, so aborted and
+/// partial transfers show what really left the server
+pub fn stream_logger() -> Logger {
+    Logger::new(r#"%a "%{request}xi" %s song=%{song_id}xi range="%{Range}i" bytes=%b %Dms"#)
+        .custom_request_replace("request", crate::auth::logged_request_line)
+        .custom_request_replace("song_id", |req| {
+            req.match_info().get("song_id").unwrap_or("-").to_string()
+        })
+}
+
+/// Answers `429` to a client that already has `MAX_STREAMS_PER_IP` song streams open.
+/// `HEAD` requests send no body and are never limited
+pub async fn limit_streams(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let streams = req.app_data::<web::Data<AppState>>().map(|s| &s.streams);
+    let (Some(ip), Some(streams)) = (ip, streams) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    if req.method() == Method::HEAD {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    let Some(permit) = streams.try_acquire(ip) else {
+        log::debug!("Refused a stream of {} to {}, at its limit", req.path(), ip);
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, STREAM_RETRY_AFTER))
+            .json(json!({
+                "error": "too many streams open from this address",
+                "code": 429,
+            }));
+        return Ok(req.into_response(response));
+    };
+    // Taken over by the song's body, or dropped along with the request when there is none
+    req.extensions_mut().insert(permit);
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+/// Same headers as `GET`; actix drops the body for `HEAD` but keeps the sized Content-Length.
+/// Transcoded streams have no length to give
+#[head("/song/{song_id}", wrap = "stream_logger()")]
+async fn song_head(
+    request: HttpRequest,
+    state: web::Data<crate::state::AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    path: web::Path<u64>,
+    params: web::Query<SongParams>,
+) -> Result<impl Responder, crate::errors::GenError> {
+    let song_id = path.into_inner() as i64;
+    let format = params.format.as_deref();
+    stream_song(&request, &state, &db, song_id, format, params.download()).await
 }
 
-#[get("/song/{song_id}")]
+#[get("/song/{song_id}", wrap = "stream_logger()", wrap = "from_fn(limit_streams)")]
 async fn get_song(
     request: HttpRequest,
     state: web::Data<crate::state::AppState>,
     db: web::Data<Pool<Sqlite>>,
     path: web::Path<u64>,
+    params: web::Query<SongParams>,
 ) -> Result<impl Responder, crate::errors::GenError> {
     let song_id = path.into_inner() as i64;
+    let format = params.format.as_deref();
+    stream_song(&request, &state, &db, song_id, format, params.download()).await
+}
+
+/// Streams a song's file, honoring `Range` requests, or transcodes it when `format` is given
+/// or, without one, when the transcode profile has a rule for the file's type
+pub async fn stream_song(
+    request: &HttpRequest,
+    state: &crate::state::AppState,
+    db: &Pool<Sqlite>,
+    song_id: i64,
+    format: Option<&str>,
+    download: bool,
+) -> Result<HttpResponse, crate::errors::GenError> {
     let mut conn = db.acquire().await?;
-    let song = sqlx::query!(
-        "
-        select
-            id,
-            relative_path,
-            file_name,
-            file_extension,
-            first_path_segment,
-            second_path_segment
-        from filesystem_artifacts
-        where id = ?",
-        song_id
-    )
-    .fetch_optional(conn.as_mut())
-    .await?
-    .map(|r| Song {
-        id: r.id as u64,
-        file_name: r.file_name,
-        file_path: r.relative_path.clone(),
-        file_extension: r.file_extension,
-        artist: r.first_path_segment.unwrap_or(String::from("Unknown")),
-        album: r.second_path_segment.unwrap_or(String::from("Unknown")),
-        full_path: r.relative_path,
+    let song = find_song(&mut conn, song_id).await?;
+
+    let Some(song) = song else {
+        return Err(crate::errors::GenError::NotFound(format!(
+            "song {} not found",
+            song_id
+        )));
+    };
+    let absolute_path = resolve_in_library(
+        &state.settings,
+        std::path::Path::new(&state.library_path),
+        song.path(),
+    )?;
+    let download_name = if download {
+        find_library_row(&mut conn, song_id)
+            .await?
+            .map(|row| format!("{} - {}", row.artist, row.track_name))
+    } else {
+        None
+    };
+    let disposition = |extension: &str| {
+        download_name
+            .as_ref()
+            .map(|name| super::attachment(&format!("{}.{}", name, extension)))
+    };
+    // `HEAD` bodies are still drained internally and must not count as plays
+    let play = (request.method() == Method::GET).then(|| Play {
+        state: state.clone(),
+        db: db.clone(),
+        song_id,
+        client: request.peer_addr().map(|addr| addr.ip()),
     });
 
-    if song.is_none() {
-        return Err(crate::errors::GenError::Other(
-            "song not found in db".into(),
-        ));
+    // Formats come with the bitrate to encode at, `None` for the format's default
+    let requested = match format {
+        Some(format_name) if format_name.eq_ignore_ascii_case("raw") => None,
+        Some(format_name) => Some((
+            find_format(format_name).ok_or_else(|| {
+                crate::errors::GenError::BadRequest(format!("unsupported format '{}'", format_name))
+            })?,
+            None,
+        )),
+        None => state
+            .settings
+            .transcode_profile
+            .rule_for(&song.file_extension)
+            .map(|rule| (rule.format, rule.bitrate)),
+    };
+    let segment = song.cue.as_ref().map(|cue| Segment {
+        start_ms: cue.start_ms,
+        end_ms: cue.end_ms,
+    });
+    let keeps_format = requested
+        .is_none_or(|(format, _)| format.extension.eq_ignore_ascii_case(&song.file_extension));
+    // A cue sheet track is served as bytes of its file where the format allows, see
+    // [`track_bytes`]
+    let cut = match segment {
+        Some(segment) if keeps_format => {
+            let (path, extension) = (absolute_path.clone(), song.file_extension.clone());
+            web::block(move || track_bytes(&path, &extension, segment))
+                .await
+                .map_err(|e| e.to_string())?
+                .unwrap_or_else(|e| {
+                    log::warn!("Could not cut song {} out of its file: {}", song_id, e);
+                    None
+                })
+        }
+        _ => None,
+    };
+    // Other cue tracks are transcoded out of their file, keeping its format where possible
+    let transcode_to = match (segment, &cut) {
+        (Some(_), Some(_)) => None,
+        (Some(_), None) => Some(
+            requested
+                .or_else(|| find_format(&song.file_extension).map(|f| (f, None)))
+                .or_else(|| find_format("flac").map(|f| (f, None)))
+                .ok_or_else(|| {
+                    crate::errors::GenError::Other("no format to cut cue tracks".into())
+                })?,
+        ),
+        (None, _) => requested.filter(|_| !keeps_format),
+    };
+    if let Some((format, bitrate)) = transcode_to {
+        let mut resp = HttpResponse::Ok();
+        resp.insert_header((header::CONTENT_TYPE, format.content_type));
+        if let Some(disposition) = disposition(format.extension) {
+            resp.insert_header(disposition);
+        }
+        // Only ffmpeg could tell the length, so a `HEAD` gets the headers without running it
+        if request.method() == Method::HEAD {
+            return Ok(resp.body(actix_web::body::None::new()));
+        }
+        let stream = Box::pin(transcode(&absolute_path, format, bitrate, segment)?);
+        let stream = watch_idle(request, state, song_id, stream);
+        return Ok(resp.streaming(PlayCounter::new(stream, play, None)));
     }
-    let song = song.unwrap();
-    let base_path = state.into_inner().library_path.clone();
-    let absolute_path = std::path::Path::new(&base_path).join(song.full_path);
-    let f = NamedFile::open(absolute_path)?;
 
-    Ok(f.into_response(&request))
+    let mut file = tokio::fs::File::open(absolute_path).await?;
+    let file_meta = file.metadata().await?;
+    let bytes = cut.unwrap_or_else(|| TrackBytes::whole(file_meta.len()));
+    let file_size = bytes.size();
+    let content_type = audio_mime_type(&song.file_extension);
+
+    let etag = file_etag(&file_meta, &bytes);
+    let not_modified = request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| {
+            h.split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == etag)
+        });
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+            .finish());
+    }
+
+    let range = match request.headers().get(header::RANGE) {
+        Some(range_header) => {
+            let parsed = range_header
+                .to_str()
+                .ok()
+                .and_then(|r| HttpRange::parse(r, file_size).ok());
+            match parsed {
+                // Multiple ranges would need a multipart body, so only the first is served
+                Some(ranges) => ranges.first().copied(),
+                None => {
+                    return Ok(HttpResponse::RangeNotSatisfiable()
+                        .insert_header((header::CONTENT_RANGE, format!("bytes */{}", file_size)))
+                        .finish());
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut resp = match range {
+        Some(r) => {
+            let mut resp = HttpResponse::PartialContent();
+            resp.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", r.start, r.start + r.length - 1, file_size),
+            ));
+            resp
+        }
+        None => HttpResponse::Ok(),
+    };
+    let (start, length) = range.map_or((0, file_size), |r| (r.start, r.length));
+    // The range may take in some of a cut track's header before its bytes of the file
+    let header_len = bytes.header.len() as u64;
+    let header = Bytes::from(bytes.header)
+        .slice(start.min(header_len) as usize..(start + length).min(header_len) as usize);
+    file.seek(std::io::SeekFrom::Start(bytes.start + start.saturating_sub(header_len)))
+        .await?;
+    let file_length = length - header.len() as u64;
+
+    resp.insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header((header::CONTENT_TYPE, content_type))
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+        .no_chunking(length);
+    if let Some(disposition) = disposition(&song.file_extension) {
+        resp.insert_header(disposition);
+    }
+    // More than half of the file sent to the client is a play, however many ranges that took
+    let chunk_size = state.settings.stream_chunk_size;
+    let body = stream::iter((!header.is_empty()).then(|| Ok(header)))
+        .chain(ReaderStream::with_capacity(file.take(file_length), chunk_size));
+    let body = watch_idle(request, state, song_id, body);
+    Ok(resp.streaming(PlayCounter::new(body, play, Some(file_size / 2 + 1))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    /// Never yields, and notes when it is dropped
+    struct Stalled(Arc<AtomicBool>);
+
+    impl Stream for Stalled {
+        type Item = std::io::Result<Bytes>;
+
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Stalled {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    async fn play_count(db: &Pool<Sqlite>) -> i64 {
+        sqlx::query_scalar("select count(*) from play_events")
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    /// A library in `lib` of one 1000 byte song, id 1, and the app state serving it
+    async fn one_song_library(
+        lib: &std::path::Path,
+        data: &std::path::Path,
+        settings: crate::file_utils::Settings,
+    ) -> (AppState, Pool<Sqlite>) {
+        let db = crate::file_utils::test_db(data).await;
+        std::fs::create_dir_all(lib.join("Artist/Album")).unwrap();
+        std::fs::write(lib.join("Artist/Album/01 Song.mp3"), [0u8; 1000]).unwrap();
+        crate::file_utils::rescan_library(&settings, lib, &db, false)
+            .await
+            .unwrap();
+        let library_path = lib.to_string_lossy().into_owned();
+        let state = crate::state::AppStateStruct::new(
+            library_path,
+            settings,
+            None,
+            None,
+            None,
+            None,
+        );
+        (Arc::new(state), db)
+    }
+
+    #[actix_web::test]
+    async fn an_unknown_song_is_a_404_naming_its_id() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db = crate::file_utils::test_db(data.path()).await;
+        let library_path = lib.path().to_string_lossy().into_owned();
+        let settings = crate::file_utils::test_settings();
+        let state =
+            crate::state::AppStateStruct::new(library_path, settings, None, None, None, None);
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(Arc::new(state)))
+                .app_data(web::Data::new(db))
+                .service(get_song),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/song/99999").to_request();
+        let resp = test::call_service(&app, request).await;
+        assert_eq!(resp.status(), 404);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "error": "song 99999 not found", "code": 404 }));
+    }
+
+    #[actix_web::test]
+    async fn range_requests_for_a_song_add_up_to_one_play() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let settings = crate::file_utils::test_settings();
+        let (state, db) = one_song_library(lib.path(), data.path(), settings).await;
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db.clone()))
+                .service(get_song),
+        )
+        .await;
+        let fetch = |client: &str, range: &str| {
+            test::TestRequest::get()
+                .uri("/song/1")
+                .peer_addr(client.parse().unwrap())
+                .insert_header((header::RANGE, range))
+                .to_request()
+        };
+
+        for range in ["bytes=0-299", "bytes=300-", "bytes=0-"] {
+            let resp = test::call_service(&app, fetch("10.0.0.1:5000", range)).await;
+            assert_eq!(resp.status(), 206);
+            test::read_body(resp).await;
+        }
+        let resp = test::call_service(&app, fetch("10.0.0.2:5000", "bytes=0-")).await;
+        test::read_body(resp).await;
+
+        // Plays are recorded in the background
+        for _ in 0..50 {
+            if play_count(&db).await >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(play_count(&db).await, 2);
+    }
+
+    #[actix_web::test]
+    async fn stream_song_serves_the_requested_range() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let settings = crate::file_utils::test_settings();
+        let (state, db) = one_song_library(lib.path(), data.path(), settings).await;
+        // Distinct bytes, so a slice from the wrong offset can't pass
+        let path = lib.path().join("Artist/Album/01 Song.mp3");
+        std::fs::write(&path, (0..1000).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>()).unwrap();
+        let file = std::fs::read(&path).unwrap();
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db))
+                .service(get_song),
+        )
+        .await;
+        let fetch = |range: &str| {
+            let request = test::TestRequest::get()
+                .uri("/song/1")
+                .insert_header((header::RANGE, range));
+            test::call_service(&app, request.to_request())
+        };
+
+        for (range, start, end) in [("bytes=100-199", 100, 199), ("bytes=-10", 990, 999)] {
+            let resp = fetch(range).await;
+            assert_eq!(resp.status(), 206, "{}", range);
+            assert_eq!(
+                resp.headers().get(header::CONTENT_RANGE).unwrap(),
+                format!("bytes {}-{}/1000", start, end).as_str()
+            );
+            assert_eq!(test::read_body(resp).await, file[start..=end], "{}", range);
+        }
+
+        let resp = fetch("bytes=5000-").await;
+        assert_eq!(resp.status(), 416);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */1000");
+    }
+
+    #[actix_web::test]
+    async fn stream_song_sends_a_large_file_whole_in_chunks_of_the_set_size() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let mut settings = crate::file_utils::test_settings();
+        settings.stream_chunk_size = 16 * 1024;
+        let (state, db) = one_song_library(lib.path(), data.path(), settings).await;
+        // Not a whole number of chunks
+        let file = (0..5 * 1024 * 1024 + 123).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(lib.path().join("Artist/Album/01 Song.mp3"), &file).unwrap();
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db))
+                .service(get_song),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/song/1").to_request();
+        let resp = test::call_service(&app, request).await;
+        assert_eq!(resp.status(), 200);
+        let length = file.len().to_string();
+        assert_eq!(resp.headers().get(header::CONTENT_LENGTH).unwrap(), length.as_str());
+        let mut body = resp.into_body();
+        let mut received = Vec::with_capacity(file.len());
+        while let Some(chunk) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= 16 * 1024, "a chunk of {} bytes", chunk.len());
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received.len(), file.len());
+        assert!(received == file);
+    }
+
+    #[actix_web::test]
+    async fn song_etag_answers_a_repeated_request_with_304() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let settings = crate::file_utils::test_settings();
+        let (state, db) = one_song_library(lib.path(), data.path(), settings).await;
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db))
+                .service(get_song),
+        )
+        .await;
+        let fetch = |etag: Option<&str>| {
+            let mut request = test::TestRequest::get().uri("/song/1");
+            if let Some(etag) = etag {
+                request = request.insert_header((header::IF_NONE_MATCH, etag));
+            }
+            test::call_service(&app, request.to_request())
+        };
+
+        let resp = fetch(None).await;
+        assert_eq!(resp.status(), 200);
+        let etag = resp.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string();
+        assert!(resp.headers().get(header::CACHE_CONTROL).is_some());
+        assert_eq!(test::read_body(resp).await.len(), 1000);
+
+        let resp = fetch(Some(&etag)).await;
+        assert_eq!(resp.status(), 304);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), etag.as_str());
+        assert!(test::read_body(resp).await.is_empty());
+
+        // A replaced file no longer matches
+        std::fs::write(lib.path().join("Artist/Album/01 Song.mp3"), [1u8; 1200]).unwrap();
+        let resp = fetch(Some(&etag)).await;
+        assert_eq!(resp.status(), 200);
+        assert_ne!(resp.headers().get(header::ETAG).unwrap(), etag.as_str());
+    }
+
+    #[actix_web::test]
+    async fn download_names_the_file_after_its_tags() {
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let settings = crate::file_utils::test_settings();
+        let (state, db) = one_song_library(lib.path(), data.path(), settings).await;
+        sqlx::query(
+            "insert or replace into track_metadata (filesystem_artifact_id, artist, track_name)
+            values (1, 'Sigur Rós', 'Ágætis byrjun / Svefn-g-englar')",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db))
+                .service(get_song),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/song/1?download=1").to_request();
+        let resp = test::call_service(&app, request).await;
+        assert_eq!(resp.status(), 200);
+        let value = resp.headers().get(header::CONTENT_DISPOSITION).unwrap();
+        let disposition = header::ContentDisposition::from_raw(value).unwrap();
+        assert!(disposition.is_attachment());
+        // The `/` can't end up as a path separator on the client's disk
+        assert_eq!(
+            disposition.get_filename(),
+            Some("Sigur R_s - _g_tis byrjun _ Svefn-g-englar.mp3")
+        );
+        let utf8 = disposition.get_filename_ext().unwrap();
+        assert_eq!(
+            String::from_utf8(utf8.value.clone()).unwrap(),
+            "Sigur Rós - Ágætis byrjun _ Svefn-g-englar.mp3"
+        );
+
+        let request = test::TestRequest::get().uri("/song/1").to_request();
+        let resp = test::call_service(&app, request).await;
+        assert!(resp.headers().get(header::CONTENT_DISPOSITION).is_none());
+    }
+
+    #[actix_web::test]
+    async fn head_has_the_headers_of_get_without_a_body_or_a_play() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let settings = crate::file_utils::test_settings();
+        let (state, db) = one_song_library(lib.path(), data.path(), settings).await;
+        // A real server, since only its dispatcher leaves out `HEAD` bodies
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_db = db.clone();
+        let server = actix_web::HttpServer::new(move || {
+            actix_web::App::new()
+                .app_data(web::Data::new(state.clone()))
+                .app_data(web::Data::new(server_db.clone()))
+                .service(get_song)
+                .service(song_head)
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        let compared = ["content-length", "content-type", "accept-ranges"];
+        let fetch = |method: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!("{} /song/1 HTTP/1.1\r\nConnection: close\r\n\r\n", method);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            let response = String::from_utf8_lossy(&response).into_owned();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let mut headers = head
+                .lines()
+                .skip(1)
+                .filter_map(|line| line.split_once(": "))
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+                .filter(|(name, _)| compared.contains(&name.as_str()))
+                .collect::<Vec<_>>();
+            headers.sort();
+            (head.lines().next().unwrap().to_string(), headers, body.len())
+        };
+
+        let (status, head_headers, body_len) = fetch("HEAD").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body_len, 0);
+        assert_eq!(play_count(&db).await, 0);
+        let (_, get_headers, body_len) = fetch("GET").await;
+        assert_eq!(body_len, 1000);
+        assert_eq!(head_headers, get_headers);
+        assert!(head_headers.contains(&("content-length".into(), "1000".into())));
+        assert!(head_headers.contains(&("accept-ranges".into(), "bytes".into())));
+        handle.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn head_answers_transcoded_streams_without_running_ffmpeg() {
+        use actix_web::body::{BodySize, MessageBody};
+        use actix_web::test;
+
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let mut settings = crate::file_utils::test_settings();
+        settings.transcode_profile = "mp3:flac".parse().unwrap();
+        let (state, db) = one_song_library(lib.path(), data.path(), settings).await;
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(db))
+                .service(song_head),
+        )
+        .await;
+        let head = |uri: &str| {
+            let request = test::TestRequest::default().method(Method::HEAD).uri(uri);
+            test::call_service(&app, request.to_request())
+        };
+
+        // Where ffmpeg isn't installed, as in CI, running it would fail the request
+        for uri in ["/song/1", "/song/1?format=flac"] {
+            let resp = head(uri).await;
+            assert_eq!(resp.status(), 200, "{}", uri);
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "audio/flac");
+            // Neither a Content-Length nor a chunked body
+            assert!(resp.headers().get(header::CONTENT_LENGTH).is_none());
+            assert_eq!(resp.response().body().size(), BodySize::None);
+        }
+        for uri in ["/song/1?format=raw", "/song/1?format=mp3"] {
+            let resp = head(uri).await;
+            assert_eq!(resp.status(), 200, "{}", uri);
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "audio/mpeg");
+            assert_eq!(resp.headers().get(header::CONTENT_LENGTH).unwrap(), "1000");
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_watch_aborts_a_body_that_makes_no_progress() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let timeout = Duration::from_millis(50);
+        let mut body = IdleWatch::new(Stalled(dropped.clone()), None, Some(timeout), 1, "-".into());
+
+        let next = tokio::time::timeout(Duration::from_secs(5), body.next()).await;
+        let err = next.expect("the body was never aborted").unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(dropped.load(Ordering::Relaxed));
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idle_watch_leaves_a_body_alone_without_a_timeout() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut body = IdleWatch::new(Stalled(dropped.clone()), None, None, 1, "-".into());
+
+        let next = tokio::time::timeout(Duration::from_millis(200), body.next()).await;
+        assert!(next.is_err());
+        assert!(!dropped.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn idle_watch_passes_a_progressing_body_through() {
+        let chunks = (0..5).map(|i| Ok(Bytes::from(vec![i; 4])));
+        let slow = futures_util::stream::iter(chunks)
+            .then(|chunk| async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                chunk
+            })
+            .boxed();
+        let timeout = Some(Duration::from_millis(60));
+        let body = IdleWatch::new(slow, None, timeout, 1, "-".into());
+
+        let sent = body.collect::<Vec<_>>().await;
+        assert_eq!(sent.len(), 5);
+        assert!(sent.iter().all(|chunk| chunk.is_ok()));
+    }
 }
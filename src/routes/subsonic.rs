@@ -0,0 +1,193 @@
+//! A read-only subset of the Subsonic REST API, enough for clients to browse and stream.
+//! Credentials are only checked when `AUTH_TOKEN` is set, see [`crate::auth::require_token`].
+use actix_web::{middleware::from_fn, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sqlx::{Pool, Sqlite};
+
+use super::xml_escape;
+use crate::db::find_library_row;
+use crate::file_utils::audio_mime_type;
+use crate::state::AppState;
+use crate::types::LibraryRow;
+
+const API_VERSION: &str = "1.16.1";
+const ERROR_NOT_FOUND: u32 = 70;
+const ERROR_MISSING_PARAM: u32 = 10;
+
+#[derive(Deserialize)]
+pub struct SubsonicParams {
+    /// Response format, `json` or `xml` (the default)
+    pub f: Option<String>,
+    pub id: Option<String>,
+    pub format: Option<String>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // Clients differ on whether they append `.view`
+    cfg.service(web::resource(["/rest/ping", "/rest/ping.view"]).to(ping))
+        .service(
+            web::resource(["/rest/getMusicFolders", "/rest/getMusicFolders.view"])
+                .to(get_music_folders),
+        )
+        .service(web::resource(["/rest/getSong", "/rest/getSong.view"]).to(get_song))
+        .service(
+            web::resource(["/rest/stream", "/rest/stream.view"])
+                .wrap(from_fn(super::song::limit_streams))
+                .to(stream),
+        );
+}
+
+fn respond(params: &SubsonicParams, status: &str, body: Map<String, Value>) -> HttpResponse {
+    let mut inner = Map::new();
+    inner.insert("status".into(), status.into());
+    inner.insert("version".into(), API_VERSION.into());
+    inner.extend(body);
+
+    if params.f.as_deref() == Some("json") {
+        HttpResponse::Ok().json(json!({ "subsonic-response": inner }))
+    } else {
+        inner.insert("xmlns".into(), "http://subsonic.org/restapi".into());
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>{}",
+            to_xml("subsonic-response", &Value::Object(inner))
+        );
+        HttpResponse::Ok().content_type("text/xml; charset=utf-8").body(xml)
+    }
+}
+
+fn respond_error(params: &SubsonicParams, code: u32, message: &str) -> HttpResponse {
+    let mut body = Map::new();
+    body.insert("error".into(), json!({ "code": code, "message": message }));
+    respond(params, "failed", body)
+}
+
+/// Scalars become attributes, objects become child elements and arrays repeat the element
+fn to_xml(name: &str, value: &Value) -> String {
+    let Value::Object(fields) = value else {
+        return format!("<{}>{}</{}>", name, xml_escape(&scalar(value)), name);
+    };
+    let mut attrs = String::new();
+    let mut children = String::new();
+    for (key, field) in fields {
+        match field {
+            Value::Object(_) => children.push_str(&to_xml(key, field)),
+            Value::Array(items) => {
+                for item in items {
+                    children.push_str(&to_xml(key, item));
+                }
+            }
+            Value::Null => {}
+            _ => attrs.push_str(&format!(" {}=\"{}\"", key, xml_escape(&scalar(field)))),
+        }
+    }
+    if children.is_empty() {
+        format!("<{}{}/>", name, attrs)
+    } else {
+        format!("<{}{}>{}</{}>", name, attrs, children, name)
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn song_child(row: &LibraryRow) -> Value {
+    json!({
+        "id": row.id.to_string(),
+        "isDir": false,
+        "title": row.track_name,
+        "album": row.album,
+        "artist": row.artist,
+        "track": row.track_number,
+        "discNumber": row.disc_number,
+        "year": row.release_year,
+        "genre": row.genre,
+        "duration": row.duration,
+        "size": row.file_size,
+        "suffix": row.file_extension,
+        "contentType": audio_mime_type(&row.file_extension),
+        "type": "music",
+    })
+}
+
+async fn ping(params: web::Query<SubsonicParams>) -> HttpResponse {
+    respond(&params, "ok", Map::new())
+}
+
+async fn get_music_folders(params: web::Query<SubsonicParams>) -> HttpResponse {
+    let mut body = Map::new();
+    body.insert(
+        "musicFolders".into(),
+        json!({ "musicFolder": [{ "id": 1, "name": "Music" }] }),
+    );
+    respond(&params, "ok", body)
+}
+
+async fn get_song(
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<SubsonicParams>,
+) -> super::GenResponse {
+    let Some(song_id) = params.id.as_deref().and_then(|id| id.parse::<i64>().ok()) else {
+        return Ok(respond_error(&params, ERROR_MISSING_PARAM, "id is required"));
+    };
+    let mut conn = db.acquire().await?;
+    let Some(row) = find_library_row(&mut conn, song_id).await? else {
+        return Ok(respond_error(&params, ERROR_NOT_FOUND, "song not found"));
+    };
+    let mut body = Map::new();
+    body.insert("song".into(), song_child(&row));
+    Ok(respond(&params, "ok", body))
+}
+
+async fn stream(
+    request: HttpRequest,
+    state: web::Data<AppState>,
+    db: web::Data<Pool<Sqlite>>,
+    params: web::Query<SubsonicParams>,
+) -> Result<HttpResponse, crate::errors::GenError> {
+    let Some(song_id) = params.id.as_deref().and_then(|id| id.parse::<i64>().ok()) else {
+        return Ok(respond_error(&params, ERROR_MISSING_PARAM, "id is required"));
+    };
+    // Subsonic's `raw`, meaning no transcoding, is understood by stream_song as well
+    let format = params.format.as_deref();
+    super::song::stream_song(&request, &state, &db, song_id, format, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, test};
+
+    #[actix_web::test]
+    async fn ping_answers_in_the_subsonic_envelope() {
+        let app = test::init_service(actix_web::App::new().configure(configure)).await;
+        let get = |uri: &str| {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request())
+        };
+
+        for uri in ["/rest/ping.view?f=json", "/rest/ping?f=json"] {
+            let body: Value = test::read_body_json(get(uri).await).await;
+            assert_eq!(
+                body,
+                json!({ "subsonic-response": { "status": "ok", "version": API_VERSION } }),
+                "{}",
+                uri
+            );
+        }
+
+        let resp = get("/rest/ping.view").await;
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "text/xml; charset=utf-8");
+        assert_eq!(
+            test::read_body(resp).await,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?><subsonic-response status=\"ok\" \
+                version=\"{}\" xmlns=\"http://subsonic.org/restapi\"/>",
+                API_VERSION
+            )
+        );
+    }
+}
@@ -1,13 +1,377 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::cover_cache::CoverCache;
+use crate::file_utils::Settings;
+use crate::lastfm::Lastfm;
+use crate::types::CoverArt;
+
 pub type AppState = std::sync::Arc<AppStateStruct>;
 
+/// Songs whose file-derived metadata is kept in memory at once
+const META_CACHE_CAPACITY: usize = 512;
+/// A client's streams of a song within this long of its last play don't count as another
+const PLAY_WINDOW: Duration = Duration::from_secs(5 * 60);
+
 pub struct AppStateStruct {
     pub library_path: String,
+    pub settings: Settings,
+    pub meta_cache: MetaCache,
+    /// `None` when covers are only cached in memory, see [`CoverCache`]
+    pub cover_cache: Option<CoverCache>,
+    /// Set once the startup scan has synced the library into the database
+    pub scan_complete: AtomicBool,
+    /// Held for the whole of the startup scan or a rescan, so only one syncs the library at a time
+    pub scan_lock: tokio::sync::Mutex<()>,
+    /// `None` when Last.fm credentials are not configured
+    pub lastfm: Option<Lastfm>,
+    /// Required of every request when set, see [`crate::auth::require_token`]
+    pub auth_token: Option<String>,
+    pub streams: StreamLimiter,
+    pub plays: PlayTracker,
 }
 
 impl AppStateStruct {
-    pub fn new(library_path: String) -> Self {
+    pub fn new(
+        library_path: String,
+        settings: Settings,
+        lastfm: Option<Lastfm>,
+        auth_token: Option<String>,
+        max_streams_per_ip: Option<usize>,
+        cover_cache: Option<CoverCache>,
+    ) -> Self {
         Self {
             library_path,
+            settings,
+            meta_cache: MetaCache::new(META_CACHE_CAPACITY),
+            cover_cache,
+            scan_complete: AtomicBool::new(false),
+            scan_lock: tokio::sync::Mutex::new(()),
+            lastfm,
+            auth_token,
+            streams: StreamLimiter::new(max_streams_per_ip),
+            plays: PlayTracker::new(PLAY_WINDOW),
+        }
+    }
+}
+
+/// Counts the song streams open to each client address, refusing more than the limit
+pub struct StreamLimiter {
+    /// `None` for no limit
+    max_per_ip: Option<usize>,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl StreamLimiter {
+    pub fn new(max_per_ip: Option<usize>) -> Self {
+        Self {
+            max_per_ip,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// A permit for one more stream to `ip`, `None` when it already has the maximum open.
+    /// The stream counts as open until the permit is dropped
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<StreamPermit> {
+        let mut active = self.active.lock().unwrap();
+        let open = active.entry(ip).or_insert(0);
+        if self.max_per_ip.is_some_and(|max| *open >= max) {
+            return None;
+        }
+        *open += 1;
+        Some(StreamPermit {
+            ip,
+            active: self.active.clone(),
+        })
+    }
+}
+
+pub struct StreamPermit {
+    ip: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(open) = active.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// How much of a song a client has been sent since its last play of it
+struct Listen {
+    sent: u64,
+    last_sent: Instant,
+    played_at: Option<Instant>,
+}
+
+/// Adds up the song streams to each client, so a song fetched over several range requests
+/// is one play, and fetching it again soon after doesn't make another
+pub struct PlayTracker {
+    window: Duration,
+    listens: Mutex<HashMap<(Option<IpAddr>, i64), Listen>>,
+}
+
+impl PlayTracker {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            listens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Notes `sent` more bytes of `song_id` streamed to `client`, returning whether that
+    /// makes a play. It does once the client's streams of the song add up to `needed` bytes,
+    /// or with `needed` unknown, when one runs to its end. A client gets at most one play
+    /// of a song per window, and a listen it hasn't added to for a window is forgotten
+    pub fn add(
+        &self,
+        client: Option<IpAddr>,
+        song_id: i64,
+        sent: u64,
+        needed: Option<u64>,
+        finished: bool,
+    ) -> bool {
+        let now = Instant::now();
+        let mut listens = self.listens.lock().unwrap();
+        if !listens.contains_key(&(client, song_id)) {
+            listens.retain(|_, l| now.duration_since(l.last_sent) < self.window);
+        }
+        let listen = listens.entry((client, song_id)).or_insert(Listen {
+            sent: 0,
+            last_sent: now,
+            played_at: None,
+        });
+        let stale = match listen.played_at {
+            Some(played_at) => now.duration_since(played_at) >= self.window,
+            None => now.duration_since(listen.last_sent) >= self.window,
+        };
+        if stale {
+            listen.sent = 0;
+            listen.played_at = None;
+        }
+        listen.sent += sent;
+        listen.last_sent = now;
+        if listen.played_at.is_some() {
+            return false;
+        }
+        let played = match needed {
+            Some(needed) => listen.sent >= needed,
+            None => finished,
+        };
+        if played {
+            listen.played_at = Some(now);
+        }
+        played
+    }
+}
+
+/// What is read from a song's file rather than the database
+#[derive(Clone)]
+pub struct CachedMeta {
+    /// Embedded cover art, `None` when the file has none
+    pub cover: Option<CoverArt>,
+}
+
+struct CacheEntry {
+    meta: CachedMeta,
+    last_used: AtomicU64,
+}
+
+/// Bounded map of song id to [`CachedMeta`], evicting the least recently used entry when full
+pub struct MetaCache {
+    capacity: usize,
+    entries: RwLock<HashMap<i64, CacheEntry>>,
+    clock: AtomicU64,
+}
+
+impl MetaCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: RwLock::new(HashMap::new()),
+            clock: AtomicU64::new(0),
         }
     }
+
+    pub fn get(&self, song_id: i64) -> Option<CachedMeta> {
+        let entries = self.entries.read().unwrap();
+        let entry = entries.get(&song_id)?;
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        entry.last_used.store(now, Ordering::Relaxed);
+        Some(entry.meta.clone())
+    }
+
+    pub fn insert(&self, song_id: i64, meta: CachedMeta) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&song_id) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used.load(Ordering::Relaxed))
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            song_id,
+            CacheEntry {
+                meta,
+                last_used: AtomicU64::new(now),
+            },
+        );
+    }
+
+    /// Returns the cached entry or runs `load` once to fill it. The lock is only taken
+    /// before and after `load`, never while it is awaited
+    pub async fn get_or_load<F, Fut, E>(&self, song_id: i64, load: F) -> Result<CachedMeta, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedMeta, E>>,
+    {
+        if let Some(meta) = self.get(song_id) {
+            return Ok(meta);
+        }
+        let meta = load().await?;
+        self.insert(song_id, meta.clone());
+        Ok(meta)
+    }
+
+    /// Drops everything, e.g. after a rescan may have replaced files
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+
+    #[test]
+    fn play_tracker_adds_up_a_clients_streams_of_a_song() {
+        let plays = PlayTracker::new(Duration::from_secs(60));
+        assert!(!plays.add(CLIENT, 1, 400, Some(501), false));
+        assert!(plays.add(CLIENT, 1, 400, Some(501), false));
+        // Within the window of that play
+        assert!(!plays.add(CLIENT, 1, 1000, Some(501), true));
+        // Another client, and another song
+        assert!(plays.add(None, 1, 1000, Some(501), false));
+        assert!(!plays.add(CLIENT, 2, 400, Some(501), false));
+    }
+
+    #[test]
+    fn play_tracker_counts_unsized_streams_at_their_end() {
+        let plays = PlayTracker::new(Duration::from_secs(60));
+        assert!(!plays.add(CLIENT, 1, 1 << 20, None, false));
+        assert!(plays.add(CLIENT, 1, 0, None, true));
+        assert!(!plays.add(CLIENT, 1, 0, None, true));
+    }
+
+    #[test]
+    fn play_tracker_counts_again_after_the_window() {
+        let plays = PlayTracker::new(Duration::from_millis(50));
+        assert!(plays.add(CLIENT, 1, 1000, Some(501), false));
+        assert!(!plays.add(CLIENT, 1, 1000, Some(501), false));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(plays.add(CLIENT, 1, 1000, Some(501), false));
+
+        // A partial listen is forgotten once the client leaves it for a window
+        assert!(!plays.add(CLIENT, 2, 400, Some(501), false));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!plays.add(CLIENT, 2, 400, Some(501), false));
+    }
+
+    fn cover(data: &'static [u8]) -> CachedMeta {
+        CachedMeta {
+            cover: Some(CoverArt {
+                mime_type: "image/png",
+                data: data.into(),
+            }),
+        }
+    }
+
+    fn cached(cache: &MetaCache, song_id: i64) -> Option<Vec<u8>> {
+        Some(cache.get(song_id)?.cover?.data.to_vec())
+    }
+
+    #[test]
+    fn meta_cache_evicts_the_least_recently_used_song() {
+        let cache = MetaCache::new(2);
+        cache.insert(1, cover(b"one"));
+        cache.insert(2, cover(b"two"));
+        // Reading song 1 leaves song 2 the oldest
+        assert_eq!(cached(&cache, 1).as_deref(), Some(&b"one"[..]));
+        cache.insert(3, cover(b"three"));
+        assert_eq!(cached(&cache, 2), None);
+        assert_eq!(cached(&cache, 1).as_deref(), Some(&b"one"[..]));
+        assert_eq!(cached(&cache, 3).as_deref(), Some(&b"three"[..]));
+
+        // Replacing a cached song evicts nothing
+        cache.insert(3, cover(b"new"));
+        assert_eq!(cached(&cache, 1).as_deref(), Some(&b"one"[..]));
+        assert_eq!(cached(&cache, 3).as_deref(), Some(&b"new"[..]));
+
+        cache.clear();
+        assert_eq!((cached(&cache, 1), cached(&cache, 3)), (None, None));
+    }
+
+    #[tokio::test]
+    async fn meta_cache_loads_each_song_once() {
+        let cache = MetaCache::new(4);
+        let loads = AtomicU64::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::Relaxed);
+            Ok::<_, ()>(cover(b"loaded"))
+        };
+        cache.get_or_load(1, load).await.unwrap();
+        cache.get_or_load(1, load).await.unwrap();
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+        // Failed loads aren't cached
+        assert!(cache.get_or_load(2, || async { Err(()) }).await.is_err());
+        assert_eq!(cached(&cache, 2), None);
+        cache.get_or_load(2, load).await.unwrap();
+        assert_eq!(loads.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn stream_limiter_refuses_streams_over_the_limit() {
+        let streams = StreamLimiter::new(Some(2));
+        let client = CLIENT.unwrap();
+        let first = streams.try_acquire(client).unwrap();
+        let second = streams.try_acquire(client).unwrap();
+        assert!(streams.try_acquire(client).is_none());
+        // Other clients have their own count
+        let other = IpAddr::V6(std::net::Ipv6Addr::LOCALHOST);
+        assert!(streams.try_acquire(other).is_some());
+
+        drop(first);
+        let third = streams.try_acquire(client).unwrap();
+        assert!(streams.try_acquire(client).is_none());
+        drop((second, third));
+        assert!(streams.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stream_limiter_without_a_limit_allows_any_number() {
+        let streams = StreamLimiter::new(None);
+        let permits = (0..100)
+            .map(|_| streams.try_acquire(CLIENT.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(streams.active.lock().unwrap()[&CLIENT.unwrap()], 100);
+        drop(permits);
+        assert!(streams.active.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,232 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+use tokio::process::Command;
+use tokio_util::io::ReaderStream;
+
+use crate::errors::GenError;
+
+pub struct TranscodeFormat {
+    pub extension: &'static str,
+    pub content_type: &'static str,
+    ffmpeg_args: &'static [&'static str],
+    /// Bitrate in kbps a lossy format is encoded at, `None` for lossless formats
+    default_bitrate: Option<u32>,
+}
+
+const FORMATS: &[TranscodeFormat] = &[
+    TranscodeFormat {
+        extension: "mp3",
+        content_type: "audio/mpeg",
+        ffmpeg_args: &["-codec:a", "libmp3lame", "-f", "mp3"],
+        default_bitrate: Some(256),
+    },
+    TranscodeFormat {
+        extension: "flac",
+        content_type: "audio/flac",
+        ffmpeg_args: &["-codec:a", "flac", "-f", "flac"],
+        default_bitrate: None,
+    },
+];
+
+/// A slice of the input in milliseconds, e.g. one track of a cue sheet
+#[derive(Clone, Copy)]
+pub struct Segment {
+    pub start_ms: i64,
+    pub end_ms: Option<i64>,
+}
+
+pub fn find_format(name: &str) -> Option<&'static TranscodeFormat> {
+    FORMATS.iter().find(|f| f.extension.eq_ignore_ascii_case(name))
+}
+
+/// What songs of one file extension are transcoded to when no format is requested
+#[derive(Clone)]
+pub struct TranscodeRule {
+    pub source: String,
+    pub format: &'static TranscodeFormat,
+    /// Overrides the format's default bitrate, in kbps
+    pub bitrate: Option<u32>,
+}
+
+/// Rules applied to every stream that doesn't ask for a format. Extensions without a rule
+/// are streamed as they are
+#[derive(Clone, Default)]
+pub struct TranscodeProfile {
+    rules: Vec<TranscodeRule>,
+}
+
+impl TranscodeProfile {
+    pub fn rule_for(&self, extension: &str) -> Option<&TranscodeRule> {
+        self.rules
+            .iter()
+            .find(|r| r.source.eq_ignore_ascii_case(extension))
+    }
+}
+
+impl FromStr for TranscodeProfile {
+    type Err = String;
+
+    /// Comma separated `source:format` rules with an optional `@bitrate` in kbps, e.g.
+    /// `flac:mp3@256k,wav:flac`
+    fn from_str(profile: &str) -> Result<Self, Self::Err> {
+        let mut rules: Vec<TranscodeRule> = Vec::new();
+        for rule in profile.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let Some((source, target)) = rule.split_once(':') else {
+                return Err(format!("'{}' is not a source:format rule", rule));
+            };
+            let (target, bitrate) = match target.split_once('@') {
+                Some((target, bitrate)) => {
+                    let kbps = bitrate.trim().trim_end_matches(['k', 'K']);
+                    match kbps.parse() {
+                        Ok(kbps) if kbps > 0 => (target, Some(kbps)),
+                        _ => return Err(format!("'{}' is not a bitrate in kbps", bitrate)),
+                    }
+                }
+                None => (target, None),
+            };
+            let source = source.trim().trim_start_matches('.').to_lowercase();
+            let Some(format) = find_format(target.trim()) else {
+                return Err(format!("'{}' is not a format songs can be transcoded to", target));
+            };
+            if bitrate.is_some() && format.default_bitrate.is_none() {
+                return Err(format!("{} is lossless and takes no bitrate", format.extension));
+            }
+            if rules.iter().any(|r| r.source == source) {
+                return Err(format!("'{}' has more than one rule", source));
+            }
+            rules.push(TranscodeRule {
+                source,
+                format,
+                bitrate,
+            });
+        }
+        Ok(Self { rules })
+    }
+}
+
+/// An ffmpeg command reading the audio of `abs_path`, or just `segment` of it
+fn ffmpeg_input(abs_path: &Path, segment: Option<Segment>) -> Command {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-v", "error"]);
+    if let Some(segment) = segment {
+        command.args(["-ss", &seconds(segment.start_ms)]);
+        if let Some(end_ms) = segment.end_ms {
+            command.args(["-t", &seconds(end_ms - segment.start_ms)]);
+        }
+    }
+    command
+        .arg("-i")
+        .arg(abs_path)
+        .args(["-map", "0:a", "-vn"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    command
+}
+
+fn spawn_error(e: std::io::Error) -> GenError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => GenError::NotImplemented(
+            "transcoding and waveforms require ffmpeg on the server's PATH".into(),
+        ),
+        _ => GenError::Other(format!("could not start ffmpeg: {}", e)),
+    }
+}
+
+/// Spawns ffmpeg to transcode `abs_path`, or just `segment` of it, returning its stdout as
+/// a stream. The child is killed when the stream is dropped, e.g. when the client disconnects.
+/// `bitrate` in kbps overrides the format's default
+pub fn transcode(
+    abs_path: &Path,
+    format: &TranscodeFormat,
+    bitrate: Option<u32>,
+    segment: Option<Segment>,
+) -> Result<impl Stream<Item = std::io::Result<Bytes>>, GenError> {
+    let mut command = ffmpeg_input(abs_path, segment);
+    if let Some(kbps) = bitrate.or(format.default_bitrate) {
+        command.arg("-b:a").arg(format!("{}k", kbps));
+    }
+    let mut child = command
+        .args(format.ffmpeg_args)
+        .arg("pipe:1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(spawn_error)?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| GenError::Other("ffmpeg stdout was not captured".into()))?;
+
+    Ok(ReaderStream::new(stdout).map(move |chunk| {
+        // Holding the child here ties its lifetime to the response body
+        let _ = &child;
+        chunk
+    }))
+}
+
+/// Decodes `abs_path`, or just `segment` of it, to mono 16-bit samples at `sample_rate`
+pub async fn decode_mono(
+    abs_path: &Path,
+    segment: Option<Segment>,
+    sample_rate: u32,
+) -> Result<Vec<i16>, GenError> {
+    let output = ffmpeg_input(abs_path, segment)
+        .args(["-ac", "1", "-ar"])
+        .arg(sample_rate.to_string())
+        .args(["-f", "s16le", "pipe:1"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(spawn_error)?;
+    if !output.status.success() {
+        return Err(GenError::Other(format!(
+            "ffmpeg could not decode '{}': {}",
+            abs_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let (samples, _) = output.stdout.as_chunks::<2>();
+    Ok(samples.iter().map(|s| i16::from_le_bytes(*s)).collect())
+}
+
+fn seconds(ms: i64) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transcode_profile_parses_rules() {
+        let profile = " flac:mp3@320k, .WAV:FLAC ,,".parse::<TranscodeProfile>().unwrap();
+        let flac = profile.rule_for("FLAC").unwrap();
+        assert_eq!((flac.format.extension, flac.bitrate), ("mp3", Some(320)));
+        let wav = profile.rule_for("wav").unwrap();
+        assert_eq!((wav.format.extension, wav.bitrate), ("flac", None));
+        assert!(profile.rule_for("mp3").is_none());
+
+        let profile = "".parse::<TranscodeProfile>().unwrap();
+        assert!(profile.rule_for("flac").is_none());
+    }
+
+    #[test]
+    fn transcode_profile_rejects_bad_rules() {
+        for bad in [
+            "flac",
+            "flac:ogg",
+            "flac:mp3@",
+            "flac:mp3@0k",
+            "flac:mp3@fast",
+            "wav:flac@900k",
+            "flac:mp3,FLAC:mp3@128",
+        ] {
+            assert!(bad.parse::<TranscodeProfile>().is_err(), "{}", bad);
+        }
+    }
+}
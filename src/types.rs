@@ -1,25 +1,49 @@
-use serde::Serialize;
+use actix_web::web::Bytes;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
+/// A song as found by a crawl, before it has a database id. Fields are named after their
+/// `filesystem_artifacts` columns, like [`Song`]'s
 #[derive(Serialize, Clone)]
 pub struct PartialSong {
-    pub filename: String,
-    pub filepath: String,
-    pub extension: String,
+    pub file_name: String,
+    pub file_extension: String,
     pub artist: String,
     pub album: String,
-    pub full_path: String,
+    /// Relative to the library root
+    pub relative_path: String,
+    /// The path as the filesystem names it, only kept when `relative_path` had to replace
+    /// names that aren't UTF-8
+    #[serde(skip)]
+    pub raw_path: Option<PathBuf>,
+    /// Artist and album are placeholders because the path had too few directories
+    pub path_inferred: bool,
+    /// Set when this is one track of a file split by a CUE sheet
+    pub cue: Option<CueTrack>,
 }
 
 impl PartialSong {
-    pub fn with_id(&self, id: u64) -> Song {
+    pub fn with_id(self, id: u64) -> Song {
         Song {
             id,
-            file_name: self.filename.clone(),
-            file_path: self.filepath.clone(),
-            full_path: self.full_path.clone(),
-            file_extension: self.extension.clone(),
-            artist: self.artist.clone(),
-            album: self.album.clone(),
+            ..Song::from(self)
+        }
+    }
+}
+
+/// The one conversion from a crawled song, leaving `id` 0 until the song is stored
+impl From<PartialSong> for Song {
+    fn from(song: PartialSong) -> Self {
+        Song {
+            id: 0,
+            file_name: song.file_name,
+            file_extension: song.file_extension,
+            artist: song.artist,
+            album: song.album,
+            relative_path: song.relative_path,
+            raw_path: song.raw_path,
+            path_inferred: song.path_inferred,
+            cue: song.cue,
         }
     }
 }
@@ -28,36 +52,430 @@ impl PartialSong {
 pub struct Song {
     pub id: u64,
     pub file_name: String,
-    pub file_path: String,
     pub file_extension: String,
     pub artist: String,
     pub album: String,
-    pub full_path: String,
+    /// Relative to the library root, see [`Song::absolute`]
+    pub relative_path: String,
+    /// As on [`PartialSong`], see [`Song::path`]
+    #[serde(skip)]
+    pub raw_path: Option<PathBuf>,
+    pub path_inferred: bool,
+    pub cue: Option<CueTrack>,
 }
 
-#[derive(Default)]
+impl Song {
+    /// Relative to the library root as the filesystem names it, for opening the file
+    pub fn path(&self) -> &Path {
+        self.raw_path
+            .as_deref()
+            .unwrap_or_else(|| Path::new(&self.relative_path))
+    }
+
+    /// Where the file is on disk under the library root `base`. Requests should go through
+    /// [`crate::file_utils::resolve_in_library`] instead, which also checks it stays under `base`
+    pub fn absolute(&self, base: &Path) -> PathBuf {
+        base.join(self.path())
+    }
+}
+
+/// One track of a CUE sheet describing a single-file album
+#[derive(Clone, Serialize, Default, PartialEq)]
+pub struct CueTrack {
+    pub number: u16,
+    pub start_ms: i64,
+    /// `None` for the last track in the file, which runs to the end
+    pub end_ms: Option<i64>,
+    /// Text from the sheet, only known when the song came from a crawl
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub album: Option<String>,
+}
+
+#[derive(Default, Serialize)]
 pub struct TrackMetadata {
     pub file_artifact_id: i64,
     pub title: Option<String>,
     pub album: Option<String>,
     pub artist: Option<String>,
+    /// The album's artist as tagged, e.g. `Various Artists` on a compilation
+    pub album_artist: Option<String>,
     pub year: Option<u16>,
     pub duration: Option<u32>,
     pub genre: Option<String>,
     pub composer: Option<String>,
     pub track_number: Option<u16>,
+    pub disc_number: Option<u16>,
+    /// ReplayGain adjustments in dB
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    /// Average bitrate in kbps
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    /// Artists after the first when the artist tag lists several
+    pub additional_artists: Vec<String>,
+    pub additional_genres: Vec<String>,
+    pub scan_status: ScanStatus,
+    /// Why the tags could not be read, unless `scan_status` is ok
+    pub scan_error: Option<String>,
+}
+
+/// How reading a file's tags went
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    #[default]
+    Ok,
+    /// The file is readable but has no tags, or none in a format that is understood
+    NoTags,
+    /// The file could not be read, or is not valid audio of its type
+    ReadError,
+    /// The file ends partway through its tags or headers
+    Truncated,
+}
+
+impl ScanStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanStatus::Ok => "ok",
+            ScanStatus::NoTags => "no_tags",
+            ScanStatus::ReadError => "read_error",
+            ScanStatus::Truncated => "truncated",
+        }
+    }
+
+    /// The status stored as [`ScanStatus::as_str`], treating anything unknown as ok
+    pub fn from_db(status: &str) -> Self {
+        match status {
+            "no_tags" => ScanStatus::NoTags,
+            "read_error" => ScanStatus::ReadError,
+            "truncated" => ScanStatus::Truncated,
+            _ => ScanStatus::Ok,
+        }
+    }
 }
 
 #[derive(Serialize)]
+pub struct ScanErrorRow {
+    pub song_id: i64,
+    pub path: String,
+    pub cue_track: Option<u16>,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
 pub struct LibraryRow {
     pub id: i64,
     pub track_name: String,
-    pub duration: Option<String>,
+    pub file_extension: String,
+    pub duration: Option<u32>,
+    pub duration_pretty: String,
     pub artist: String,
+    pub additional_artists: Vec<String>,
     pub album: String,
+    /// As tagged, `None` for songs without an album artist tag
+    pub album_artist: Option<String>,
+    /// Directory names the layout takes the artist and album from, which tell apart albums
+    /// of the same name. `None` for loose files, see [`crate::file_utils::LooseFilePolicy`]
+    #[serde(skip)]
+    pub album_dir: (Option<String>, Option<String>),
     pub track_number: Option<u16>,
+    pub disc_number: Option<u16>,
     pub genre: Option<String>,
+    pub additional_genres: Vec<String>,
     pub composer: Option<String>,
     pub release_year: Option<u16>,
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    /// Bytes on disk, of the whole file for a CUE sheet track
+    pub file_size: Option<u64>,
+    /// The artist or album is the configured placeholder rather than a tag or directory name
+    pub artist_inferred: bool,
+    pub album_inferred: bool,
     pub is_present: bool,
+    pub is_favorite: bool,
+}
+
+/// Primary sort key for library listings; ties fall back to artist, album, then track order
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SongSort {
+    #[default]
+    Artist,
+    Album,
+    Title,
+    Duration,
+    /// When the song was first scanned
+    Added,
+}
+
+/// Direction of the primary sort key, its ties are still broken in ascending order
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Narrows library listings to the songs matching every field that is set. Names and genres
+/// compare case-insensitively
+#[derive(Default)]
+pub struct SongFilter {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Primary or additional genre, `Some("")` for songs without any genre
+    pub genre: Option<String>,
+    pub year: Option<i64>,
+    /// Only these songs
+    pub song_ids: Option<Vec<i64>>,
+}
+
+/// How far `/api/next` looks for the song after the current one
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueScope {
+    Album,
+    Artist,
+    #[default]
+    All,
+}
+
+impl SongSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SongSort::Artist => "artist",
+            SongSort::Album => "album",
+            SongSort::Title => "title",
+            SongSort::Duration => "duration",
+            SongSort::Added => "added",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AlbumRow {
+    pub artist: String,
+    pub album: String,
+    pub track_count: u32,
+    /// Distinct disc numbers among the tracks, 1 for single-disc and untagged albums
+    pub disc_count: u32,
+    /// In disc, then track, then file name order
+    pub song_ids: Vec<i64>,
+    /// The album's first track, for use with the cover route
+    pub cover_song_id: i64,
+    /// Tracks are by several artists, and `artist` is the album artist, tagged or Various
+    /// Artists
+    pub compilation: bool,
+}
+
+#[derive(Serialize)]
+pub struct ArtistRow {
+    pub artist: String,
+    pub song_count: u32,
+    pub album_count: u32,
+}
+
+#[derive(Serialize)]
+pub struct GenreRow {
+    pub genre: String,
+    pub track_count: u32,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateFile {
+    pub id: i64,
+    pub path: String,
+}
+
+/// Files whose size and leading bytes hash identically
+#[derive(Serialize)]
+pub struct DuplicateGroup {
+    pub content_hash: String,
+    pub files: Vec<DuplicateFile>,
+}
+
+/// An artist/album directory whose songs disagree on their artist or album tags, which
+/// splits it into several albums when browsing. Compilations legitimately list several artists
+#[derive(Serialize)]
+pub struct TagConflict {
+    pub artist_dir: String,
+    pub album_dir: String,
+    pub song_count: i64,
+    /// Every distinct tagged value, exactly as spelled
+    pub artists: Vec<String>,
+    pub albums: Vec<String>,
+}
+
+/// One play of a song, as listed in the recently played feed
+#[derive(Serialize)]
+pub struct PlayEvent {
+    pub played_at: i64,
+    pub song: LibraryRow,
+}
+
+/// A song as listed in the recently added feed, stamped with when a scan first found it
+#[derive(Serialize)]
+pub struct AddedSong {
+    pub created_at: i64,
+    /// `created_at` as an ISO-8601 UTC timestamp
+    pub created_at_iso: String,
+    pub song: LibraryRow,
+}
+
+#[derive(Serialize)]
+pub struct TopTrack {
+    pub play_count: i64,
+    pub last_played_at: i64,
+    pub song: LibraryRow,
+}
+
+/// One `filesystem_artifacts` row and its tags, as moved between databases by
+/// `/api/export` and `/api/import`. Rows are matched by path and cue track, not id
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportRecord {
+    pub relative_path: String,
+    pub file_name: String,
+    pub file_extension: String,
+    pub is_present: bool,
+    pub first_path_segment: Option<String>,
+    pub second_path_segment: Option<String>,
+    pub path_inferred: bool,
+    pub created_at: i64,
+    pub updated_at: Option<i64>,
+    pub file_mtime: Option<i64>,
+    pub file_size: Option<i64>,
+    pub content_hash: Option<String>,
+    pub cue_track: Option<i64>,
+    pub cue_start_ms: Option<i64>,
+    pub cue_end_ms: Option<i64>,
+    /// The path as the filesystem's bytes, for names that aren't UTF-8. Missing from exports
+    /// made before it was added
+    #[serde(default)]
+    pub raw_path: Option<Vec<u8>>,
+    /// `None` for files whose tags have not been read yet
+    pub metadata: Option<ExportMetadata>,
+}
+
+/// A `track_metadata` row, less the waveform peaks, which are recomputed on request
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportMetadata {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_name: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub release_year: Option<i64>,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub duration: Option<i64>,
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub bitrate: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub channels: Option<i64>,
+    pub cover_path: Option<String>,
+    pub scan_status: ScanStatus,
+    pub scan_error: Option<String>,
+    pub additional_artists: Vec<String>,
+    pub additional_genres: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LibraryStats {
+    pub songs: i64,
+    pub artists: i64,
+    pub albums: i64,
+    /// Seconds, summed over songs whose duration is known
+    pub duration: i64,
+    pub duration_pretty: String,
+    /// Size of the audio files on disk, as of their last scan
+    pub bytes: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct ScanSummary {
+    pub added: u64,
+    pub updated: u64,
+    pub removed: u64,
+    pub unchanged: u64,
+    /// Rows written to `track_metadata` by tag reads
+    pub metadata_saved: u64,
+}
+
+#[derive(Clone)]
+pub struct CoverArt {
+    pub mime_type: &'static str,
+    pub data: Bytes,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::find_song;
+    use crate::file_utils::{crawl_dir, load_library, rescan_library, test_db, test_settings};
+
+    #[tokio::test]
+    async fn absolute_resolves_a_nested_song_under_the_library_root() {
+        let lib = tempfile::tempdir().unwrap();
+        let dir = lib.path().join("Artist").join("Album").join("Disc 1");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("01 Song.flac"), b"not really audio").unwrap();
+
+        let songs = load_library(&test_settings(), lib.path()).await.unwrap();
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].relative_path, "Artist/Album/Disc 1/01 Song.flac");
+        let absolute = songs[0].absolute(lib.path());
+        assert_eq!(absolute, dir.join("01 Song.flac"));
+        assert!(absolute.is_file());
+    }
+
+    #[tokio::test]
+    async fn a_crawled_song_converts_to_the_song_stored_for_it() {
+        let lib = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let dir = lib.path().join("Artist").join("Album");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("01 Song.flac"), b"not really audio").unwrap();
+        let settings = test_settings();
+
+        let mut crawled = crawl_dir(&settings, lib.path(), lib.path()).await.unwrap();
+        assert_eq!(crawled.len(), 1);
+        let song = Song::from(crawled.remove(0));
+        assert_eq!(song.id, 0);
+
+        let db = test_db(data.path()).await;
+        rescan_library(&settings, lib.path(), &db, false).await.unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        let stored = find_song(&mut conn, 1).await.unwrap().unwrap();
+        assert_eq!(stored.id, 1);
+        assert_eq!(stored.file_name, song.file_name);
+        assert_eq!(stored.file_name, "01 Song");
+        assert_eq!(stored.file_extension, song.file_extension);
+        assert_eq!(stored.file_extension, "flac");
+        assert_eq!(stored.artist, song.artist);
+        assert_eq!(stored.album, song.album);
+        assert_eq!((stored.artist.as_str(), stored.album.as_str()), ("Artist", "Album"));
+        assert_eq!(stored.relative_path, song.relative_path);
+        assert_eq!(stored.path(), song.path());
+        assert!(!stored.path_inferred && !song.path_inferred);
+        assert!(stored.cue.is_none() && song.cue.is_none());
+    }
 }
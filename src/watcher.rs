@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use sqlx::{Pool, Sqlite};
+use tokio::sync::mpsc;
+
+use crate::file_utils::sync_paths;
+use crate::state::AppState;
+
+/// How long the library has to be quiet before queued changes are applied
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Watches the library and syncs changed files into the database in the background, one
+/// sync at a time with the startup scan and rescans. The returned watcher must be kept alive
+/// for events to keep arriving.
+pub fn spawn_watcher(
+    state: AppState,
+    db: Pool<Sqlite>,
+) -> notify::Result<notify::RecommendedWatcher> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<PathBuf>();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        match res {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Library watcher error: {}", e),
+        }
+    })?;
+    watcher.watch(Path::new(&state.library_path), RecursiveMode::Recursive)?;
+
+    tokio::spawn(async move {
+        while let Some(first) = rx.recv().await {
+            let mut pending = HashSet::from([first]);
+            while let Ok(Some(path)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+                pending.insert(path);
+            }
+            let paths = pending.into_iter().collect::<Vec<_>>();
+            let _scanning = state.scan_lock.lock().await;
+            let base_path = Path::new(&state.library_path);
+            match sync_paths(&state.settings, base_path, &paths, &db).await {
+                Ok(summary) => log::info!(
+                    "Library change applied: {} added, {} updated, {} removed",
+                    summary.added,
+                    summary.updated,
+                    summary.removed
+                ),
+                Err(e) => log::error!("Could not apply library change: {}", e),
+            }
+        }
+    });
+
+    Ok(watcher)
+}
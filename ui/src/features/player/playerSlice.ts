@@ -78,8 +78,17 @@ export const playerSlice = createAppSlice({
       }
     }),
     loadLibrary: create.asyncThunk(async () => {
-      const resp = await (await fetch('/api/songs')).json() as IGetLibraryResponse;
-      return resp;
+      const songs: Array<ISong> = [];
+      let total = 0;
+      do {
+        const resp = await (await fetch(`/api/songs?limit=1000&offset=${songs.length}`)).json() as IGetLibraryResponse;
+        if (resp.songs.length == 0) {
+          break;
+        }
+        songs.push(...resp.songs);
+        total = resp.total;
+      } while (songs.length < total);
+      return { songs };
     }, {
       pending: state => {
         state.library.loadingState = "loading";
@@ -2,4 +2,11 @@ import { ISong } from "./songs";
 
 export interface IGetLibraryResponse {
   songs: Array<ISong>;
-}
\ No newline at end of file
+  total: number;
+  limit: number;
+  offset: number;
+  page: number;
+  total_pages: number;
+  sort: "artist" | "album" | "title" | "duration" | "added";
+  order: "asc" | "desc";
+}
@@ -1,11 +1,25 @@
 export interface ISong {
   id: number;
-  duration?: string;
+  duration?: number;
+  duration_pretty: string;
   track_name: string;
   track_number: string;
+  disc_number?: number;
   artist: string;
+  artist_inferred: boolean;
+  additional_artists: Array<string>;
   album: string;
+  album_artist: string | null;
+  album_inferred: boolean;
   genre?: string;
+  additional_genres: Array<string>;
   release_year?: string;
+  track_gain?: number;
+  album_gain?: number;
+  bitrate?: number;
+  sample_rate?: number;
+  channels?: number;
+  file_size?: number;
   is_present: boolean;
+  is_favorite: boolean;
 }